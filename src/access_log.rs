@@ -0,0 +1,307 @@
+use crate::auth::Identity;
+use crate::listen::ClientAddr;
+use crate::logging::{self, LogFileConfig};
+use crate::request_id;
+use axum::body::HttpBody;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::HeaderMap;
+use axum::http::header::{CONTENT_LENGTH, REFERER, USER_AGENT};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AccessLogFormat {
+    /// Apache combined log format, followed by duration, dedup and request id
+    #[default]
+    #[serde(rename = "combined")]
+    Combined,
+    /// One JSON object per line
+    #[serde(rename = "json")]
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// Written to stdout if not set
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Some(file) = &self.file {
+            file.validate("logging.access_log.file", problems);
+        }
+    }
+}
+
+/**
+ * What an upload did, added to the response for the access log.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Upload {
+    pub bytes: u64,
+    /// Whether the blob was stored already, unknown if the upload was
+    /// older than the stored file and therefore ignored
+    pub dedup_hit: Option<bool>,
+}
+
+struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+}
+
+/**
+ * Start writing the access log. The returned guard must be kept until the
+ * process exits.
+ */
+pub fn init(config: &AccessLogConfig) -> io::Result<WorkerGuard> {
+    let (writer, guard) = match &config.file {
+        Some(file) => file.open()?,
+        None => logging::non_blocking(io::stdout()),
+    };
+    let access_log = AccessLog {
+        format: config.format.clone(),
+        writer,
+    };
+    if ACCESS_LOG.set(access_log).is_err() {
+        return Err(io::Error::other("Access log started twice"));
+    }
+    Ok(guard)
+}
+
+/**
+ * Request as written to the access log.
+ */
+#[derive(Serialize)]
+struct Entry {
+    time: String,
+    client: Option<String>,
+    identity: Option<String>,
+    method: String,
+    path: String,
+    query: Option<String>,
+    version: String,
+    status: u16,
+    bytes_sent: Option<u64>,
+    bytes_received: Option<u64>,
+    duration_ms: f64,
+    dedup_hit: Option<bool>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    /**
+     * Line of the entry in format, for a request started at start.
+     */
+    fn line(&self, format: &AccessLogFormat, start: &DateTime<Local>) -> String {
+        match format {
+            AccessLogFormat::Combined => self.combined(start),
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap() + "\n",
+        }
+    }
+
+    fn combined(&self, start: &DateTime<Local>) -> String {
+        let dedup = match self.dedup_hit {
+            Some(true) => "hit",
+            Some(false) => "miss",
+            None => "-",
+        };
+        let target = match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {:.3} {} {}\n",
+            self.client.as_deref().unwrap_or("-"),
+            self.identity.as_deref().unwrap_or("-"),
+            start.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&target),
+            self.version,
+            self.status,
+            self.bytes_sent
+                .map_or("-".to_string(), |bytes| bytes.to_string()),
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            self.duration_ms / 1000.0,
+            dedup,
+            self.request_id.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+/**
+ * Escape quotes, backslashes and control characters, so that every request
+ * stays on one line with its fields intact.
+ */
+fn escape(value: &str) -> String {
+    value.escape_debug().to_string()
+}
+
+fn header(headers: &HeaderMap, name: impl axum::http::header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/**
+ * Middleware writing every request to the access log, if it is enabled.
+ * Must run inside `request_id::assign` and outside the bucket routers, so
+ * that paths are logged as the client sent them.
+ */
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return next.run(request).await;
+    };
+    let start = Local::now();
+    let started = Instant::now();
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .and_then(|ConnectInfo(ClientAddr(ip))| ip.map(|ip| ip.to_string()));
+    let headers = request.headers();
+    let mut entry = Entry {
+        time: start.to_rfc3339(),
+        client,
+        identity: None,
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        query: request.uri().query().map(str::to_string),
+        version: format!("{:?}", request.version()),
+        status: 0,
+        bytes_sent: None,
+        bytes_received: header(headers, CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+        duration_ms: 0.0,
+        dedup_hit: None,
+        referer: header(headers, REFERER),
+        user_agent: header(headers, USER_AGENT),
+        request_id: request_id::current(),
+    };
+
+    let response = next.run(request).await;
+
+    entry.status = response.status().as_u16();
+    entry.bytes_sent = response.body().size_hint().exact();
+    entry.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    entry.identity = response
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.name.clone());
+    if let Some(upload) = response.extensions().get::<Upload>() {
+        entry.bytes_received = Some(upload.bytes);
+        entry.dedup_hit = upload.dedup_hit;
+    }
+    let line = entry.line(&access_log.format, &start);
+    // Written as a whole, so that lines of concurrent requests do not mix
+    let _ = access_log.writer.clone().write_all(line.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, TimeZone};
+    use serde_json::json;
+
+    fn start() -> DateTime<Local> {
+        FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2024, 1, 2, 3, 4, 5)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            time: start().to_rfc3339(),
+            client: Some("192.0.2.1".to_string()),
+            identity: Some("alice".to_string()),
+            method: "PUT".to_string(),
+            path: "/ft/files/a b".to_string(),
+            query: Some("last_modified=x".to_string()),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes_sent: Some(12),
+            bytes_received: Some(34),
+            duration_ms: 1500.0,
+            dedup_hit: Some(true),
+            referer: None,
+            user_agent: Some("curl \"quoted\"\n".to_string()),
+            request_id: Some("id-1".to_string()),
+        }
+    }
+
+    #[test]
+    fn formats_combined_lines() {
+        let start = start();
+        let line = entry().line(&AccessLogFormat::Combined, &start);
+        let time = start.format("%d/%b/%Y:%H:%M:%S %z");
+        assert_eq!(
+            line,
+            format!(
+                "192.0.2.1 - alice [{}] \"PUT /ft/files/a b?last_modified=x HTTP/1.1\" 200 12 \
+                 \"-\" \"curl \\\"quoted\\\"\\n\" 1.500 hit id-1\n",
+                time
+            )
+        );
+
+        let anonymous = Entry {
+            client: None,
+            identity: None,
+            query: None,
+            bytes_sent: None,
+            dedup_hit: None,
+            user_agent: None,
+            request_id: None,
+            ..entry()
+        };
+        let line = anonymous.line(&AccessLogFormat::Combined, &start);
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(
+            line.ends_with("\"PUT /ft/files/a b HTTP/1.1\" 200 - \"-\" \"-\" 1.500 - -\n"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = entry().line(&AccessLogFormat::Json, &start());
+        assert!(line.ends_with('\n') && line.matches('\n').count() == 1);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "time": start().to_rfc3339(),
+                "client": "192.0.2.1",
+                "identity": "alice",
+                "method": "PUT",
+                "path": "/ft/files/a b",
+                "query": "last_modified=x",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "bytes_sent": 12,
+                "bytes_received": 34,
+                "duration_ms": 1500.0,
+                "dedup_hit": true,
+                "referer": null,
+                "user_agent": "curl \"quoted\"\n",
+                "request_id": "id-1",
+            })
+        );
+    }
+}
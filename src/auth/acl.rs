@@ -0,0 +1,273 @@
+use crate::auth::Identity;
+use crate::error::AppError;
+use crate::request_id;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// Identity whose rules apply to every client
+pub const ANY_IDENTITY: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write")]
+    Write,
+    /// Reserved until files can be deleted, rejected by validation
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+impl Permission {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AclRule {
+    /// Path prefix, matched on whole path segments. Empty matches all paths.
+    pub prefix: String,
+    pub permissions: Vec<Permission>,
+}
+
+/**
+ * Access rules per credential name. For each request, the rule with the
+ * longest prefix matching the path decides, preferring rules of the
+ * credential over those of `*`. Requests no rule matches are denied.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AclConfig {
+    pub rules: BTreeMap<String, Vec<AclRule>>,
+}
+
+impl AclConfig {
+    pub fn validate(&self, at: &str, credentials: &[&str], problems: &mut Vec<String>) {
+        for (identity, rules) in &self.rules {
+            if identity != ANY_IDENTITY && !credentials.contains(&identity.as_str()) {
+                problems.push(format!(
+                    "{}.acl.rules.{}: no credential has this name",
+                    at, identity
+                ));
+            }
+            for (i, rule) in rules.iter().enumerate() {
+                if normalize_prefix(&rule.prefix).split('/').any(is_relative) {
+                    problems.push(format!(
+                        "{}.acl.rules.{}[{}].prefix: must not contain . or .. segments",
+                        at, identity, i
+                    ));
+                }
+                if rule.permissions.contains(&Permission::Delete) {
+                    problems.push(format!(
+                        "{}.acl.rules.{}[{}].permissions: delete is reserved, files cannot be deleted yet",
+                        at, identity, i
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Acl {
+    bucket: String,
+    config: AclConfig,
+}
+
+impl Acl {
+    pub fn new(bucket: &str, config: &AclConfig) -> Self {
+        Acl {
+            bucket: bucket.to_string(),
+            config: config.clone(),
+        }
+    }
+
+    /**
+     * Check that identity has permission on path, which must be normalized.
+     * Denials are logged to the audit log.
+     */
+    pub fn check(
+        &self,
+        identity: Option<&Identity>,
+        permission: Permission,
+        path: &str,
+    ) -> Result<(), AppError> {
+        let name = identity.map_or(ANY_IDENTITY, |identity| identity.name.as_str());
+        let own = self.config.rules.get(name).into_iter().flatten();
+        let any = self.config.rules.get(ANY_IDENTITY).into_iter().flatten();
+        let rule = own
+            .map(|rule| (rule, 1))
+            .chain(any.map(|rule| (rule, 0)))
+            .filter(|(rule, _)| prefix_matches(&rule.prefix, path))
+            .max_by_key(|(rule, own)| (normalize_prefix(&rule.prefix).len(), *own))
+            .map(|(rule, _)| rule);
+
+        if rule.is_some_and(|rule| rule.permissions.contains(&permission)) {
+            return Ok(());
+        }
+        audit_denial(&self.bucket, identity, permission.as_str(), path);
+        Err(AppError::Forbidden(format!(
+            "{} may not {} {}",
+            name,
+            permission.as_str(),
+            path
+        )))
+    }
+}
+
+/**
+ * Write a denied request to the audit log.
+ */
+pub fn audit_denial(bucket: &str, identity: Option<&Identity>, action: &str, target: &str) {
+    warn!(
+        target: "audit",
+        "Denied: {} on: {} in bucket: {} to: {} (request: {})",
+        action,
+        target,
+        bucket,
+        identity.map_or("anonymous", |identity| identity.name.as_str()),
+        request_id::current().as_deref().unwrap_or("-")
+    );
+}
+
+/**
+ * Bring a file path into canonical form: no leading, trailing or repeated
+ * slashes. Paths with `.` or `..` segments are rejected, so that they
+ * cannot escape the prefix they appear to be under.
+ */
+pub fn normalize_path(path: &str) -> Result<String, AppError> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        return Err(AppError::Validation("Path must not be empty".to_string()));
+    }
+    if segments.iter().any(|segment| is_relative(segment)) {
+        return Err(AppError::Validation(format!(
+            "Path must not contain . or .. segments: {}",
+            path
+        )));
+    }
+    Ok(segments.join("/"))
+}
+
+fn is_relative(segment: &str) -> bool {
+    segment == "." || segment == ".."
+}
+
+fn normalize_prefix(prefix: &str) -> &str {
+    prefix.trim_matches('/')
+}
+
+fn prefix_matches(prefix: &str, path: &str) -> bool {
+    let prefix = normalize_prefix(prefix);
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use serde_json::json;
+
+    fn acl() -> Acl {
+        let config = serde_json::from_value(json!({
+            "rules": {
+                "*": [
+                    {"prefix": "", "permissions": ["read"]},
+                    {"prefix": "public/", "permissions": ["read", "write"]}
+                ],
+                "alice": [
+                    {"prefix": "/", "permissions": []},
+                    {"prefix": "home/alice", "permissions": ["read", "write"]},
+                    {"prefix": "home/alice/archive", "permissions": ["read"]}
+                ]
+            }
+        }))
+        .unwrap();
+        Acl::new("test", &config)
+    }
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            name: name.to_string(),
+            role: Role::Write,
+        }
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("/a//b/c/").unwrap(), "a/b/c");
+        assert_eq!(normalize_path("a.b/..c").unwrap(), "a.b/..c");
+        assert!(normalize_path("//").is_err());
+        assert!(normalize_path("a/../b").is_err());
+        assert!(normalize_path("./a").is_err());
+    }
+
+    #[test]
+    fn matches_prefixes_on_whole_segments() {
+        assert!(prefix_matches("", "a/b"));
+        assert!(prefix_matches("/", "a/b"));
+        assert!(prefix_matches("a", "a"));
+        assert!(prefix_matches("/a/", "a/b"));
+        assert!(!prefix_matches("a", "ab"));
+        assert!(!prefix_matches("a/b", "a"));
+    }
+
+    #[test]
+    fn longest_prefix_decides() {
+        let acl = acl();
+        let alice = identity("alice");
+        let check = |permission, path| acl.check(Some(&alice), permission, path).is_ok();
+        assert!(check(Permission::Write, "home/alice/notes"));
+        assert!(!check(Permission::Write, "home/alice/archive/2024"));
+        assert!(check(Permission::Read, "home/alice/archive/2024"));
+        assert!(!check(Permission::Write, "home/alicebob"));
+    }
+
+    #[test]
+    fn own_rules_win_over_any_on_equal_prefixes() {
+        let acl = acl();
+        // alice's rule for "/" denies what "*" allows on ""
+        let alice = identity("alice");
+        assert!(acl.check(Some(&alice), Permission::Read, "other").is_err());
+        assert!(
+            acl.check(Some(&identity("bob")), Permission::Read, "other")
+                .is_ok()
+        );
+        // A longer prefix of "*" still beats a shorter one of alice
+        assert!(
+            acl.check(Some(&alice), Permission::Write, "public/x")
+                .is_ok()
+        );
+        assert!(acl.check(None, Permission::Write, "public/x").is_ok());
+        assert!(acl.check(None, Permission::Write, "other").is_err());
+    }
+
+    #[test]
+    fn rejects_reserved_and_relative_rules() {
+        let config: AclConfig = serde_json::from_value(json!({
+            "rules": {
+                "alice": [{"prefix": "a/../b", "permissions": ["delete"]}],
+                "nobody": []
+            }
+        }))
+        .unwrap();
+        let mut problems = vec![];
+        config.validate("buckets[0]", &["alice"], &mut problems);
+        assert_eq!(
+            problems,
+            [
+                "buckets[0].acl.rules.alice[0].prefix: must not contain . or .. segments",
+                "buckets[0].acl.rules.alice[0].permissions: delete is reserved, files cannot be deleted yet",
+                "buckets[0].acl.rules.nobody: no credential has this name",
+            ]
+        );
+    }
+}
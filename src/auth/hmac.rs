@@ -0,0 +1,263 @@
+/*
+ * Signed requests
+ *
+ * A client holding an HMAC secret sends
+ *
+ *   Authorization: HMAC-SHA256 <name>:<signature>
+ *   X-Timestamp: <unix seconds>
+ *
+ * where signature is the hex encoded HMAC-SHA256 with the secret of
+ *
+ *   <timestamp>\n<method>\n<path and query>\n<SHA256-Checksum header>
+ *
+ * using the path and query as sent. Requests with a body must send the
+ * checksum, requests without one may leave the last line empty. The
+ * checksum is verified against the body by the upload handler, so signing
+ * it protects the body without having to buffer it here.
+ *
+ * Each signature is accepted once: a request sent again within the allowed
+ * clock skew is rejected, so retries must be signed with a new timestamp.
+ * Signatures are remembered per process only.
+ */
+
+use crate::error::AppError;
+use axum::http::{HeaderMap, Method};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SCHEME: &str = "HMAC-SHA256 ";
+const TIMESTAMP_HEADER: &str = "X-Timestamp";
+const CHECKSUM_HEADER: &str = "SHA256-Checksum";
+
+pub struct SignedRequest {
+    pub name: String,
+    signature: Vec<u8>,
+    timestamp: u64,
+}
+
+impl SignedRequest {
+    /**
+     * Parse the Authorization header value after the scheme, along with
+     * the timestamp header.
+     */
+    pub fn parse(credentials: &str, headers: &HeaderMap) -> Result<Self, AppError> {
+        let malformed = || AppError::Unauthorized("Malformed signature".to_string());
+        let (name, signature) = credentials.trim().rsplit_once(':').ok_or_else(malformed)?;
+        let signature = hex::decode(signature).map_err(|_| malformed())?;
+        let timestamp = headers
+            .get(TIMESTAMP_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                AppError::Unauthorized(format!("Missing or invalid {} header", TIMESTAMP_HEADER))
+            })?;
+        Ok(SignedRequest {
+            name: name.to_string(),
+            signature,
+            timestamp,
+        })
+    }
+
+    /**
+     * Check the signature and that the request is recent.
+     * has_body tells whether the request has a body, which the checksum
+     * must then cover.
+     */
+    pub fn verify(
+        &self,
+        secret: &str,
+        method: &Method,
+        path_and_query: &str,
+        headers: &HeaderMap,
+        has_body: bool,
+        max_skew: Duration,
+    ) -> Result<(), AppError> {
+        if now().abs_diff(self.timestamp) > max_skew.as_secs() {
+            return Err(AppError::Unauthorized(format!(
+                "{} is more than {:?} off",
+                TIMESTAMP_HEADER, max_skew
+            )));
+        }
+
+        let checksum = match headers.get(CHECKSUM_HEADER) {
+            Some(checksum) => checksum.as_bytes(),
+            None if has_body => {
+                return Err(AppError::Unauthorized(format!(
+                    "Signed requests with a body must send {}",
+                    CHECKSUM_HEADER
+                )));
+            }
+            None => &[],
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}\n{}\n", self.timestamp, method, path_and_query).as_bytes());
+        mac.update(checksum);
+        mac.verify_slice(&self.signature)
+            .map_err(|_| AppError::Unauthorized("Invalid signature".to_string()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/**
+ * Signatures accepted recently, to reject requests sent again.
+ */
+#[derive(Default)]
+pub struct ReplayCache {
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    /// Signatures with the time after which their timestamp is rejected anyway
+    expires_at: HashMap<Vec<u8>, u64>,
+    swept_at: u64,
+}
+
+impl ReplayCache {
+    /**
+     * Remember the signature of a verified request, failing if it was
+     * seen before.
+     */
+    pub fn check(&self, signed: &SignedRequest, max_skew: Duration) -> Result<(), AppError> {
+        let now = now();
+        let mut seen = self.seen.lock().unwrap();
+        if seen.swept_at != now {
+            seen.expires_at.retain(|_, expires_at| *expires_at >= now);
+            seen.swept_at = now;
+        }
+        let expires_at = signed.timestamp + max_skew.as_secs();
+        if seen
+            .expires_at
+            .insert(signed.signature.clone(), expires_at)
+            .is_some()
+        {
+            return Err(AppError::Unauthorized(
+                "Signature was used already".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const SKEW: Duration = Duration::from_secs(300);
+
+    /**
+     * Sign a request like a client does, returning the credentials after the
+     * scheme and the headers.
+     */
+    fn sign(
+        secret: &str,
+        timestamp: u64,
+        method: &Method,
+        path_and_query: &str,
+        checksum: Option<&str>,
+    ) -> (String, HeaderMap) {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        let checksum_line = checksum.unwrap_or("");
+        mac.update(
+            format!(
+                "{}\n{}\n{}\n{}",
+                timestamp, method, path_and_query, checksum_line
+            )
+            .as_bytes(),
+        );
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        if let Some(checksum) = checksum {
+            headers.insert(CHECKSUM_HEADER, HeaderValue::from_str(checksum).unwrap());
+        }
+        (format!("client:{}", signature), headers)
+    }
+
+    fn verify(
+        credentials: &str,
+        headers: &HeaderMap,
+        method: &Method,
+        path_and_query: &str,
+        has_body: bool,
+    ) -> Result<(), AppError> {
+        SignedRequest::parse(credentials, headers)?.verify(
+            "secret",
+            method,
+            path_and_query,
+            headers,
+            has_body,
+            SKEW,
+        )
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let (credentials, headers) = sign("secret", now(), &Method::GET, "/a?x=1", None);
+        let signed = SignedRequest::parse(&credentials, &headers).unwrap();
+        assert_eq!(signed.name, "client");
+        assert!(verify(&credentials, &headers, &Method::GET, "/a?x=1", false).is_ok());
+
+        assert!(verify(&credentials, &headers, &Method::PUT, "/a?x=1", false).is_err());
+        assert!(verify(&credentials, &headers, &Method::GET, "/a?x=2", false).is_err());
+        let (other, headers) = sign("other", now(), &Method::GET, "/a?x=1", None);
+        assert!(verify(&other, &headers, &Method::GET, "/a?x=1", false).is_err());
+    }
+
+    #[test]
+    fn signatures_cover_the_checksum() {
+        let (credentials, headers) = sign("secret", now(), &Method::PUT, "/a", Some("abc"));
+        assert!(verify(&credentials, &headers, &Method::PUT, "/a", true).is_ok());
+
+        let mut changed = headers.clone();
+        changed.insert(CHECKSUM_HEADER, HeaderValue::from_static("abd"));
+        assert!(verify(&credentials, &changed, &Method::PUT, "/a", true).is_err());
+
+        let (credentials, headers) = sign("secret", now(), &Method::PUT, "/a", None);
+        assert!(verify(&credentials, &headers, &Method::PUT, "/a", true).is_err());
+    }
+
+    #[test]
+    fn rejects_skewed_and_malformed_requests() {
+        for timestamp in [now() - 400, now() + 400] {
+            let (credentials, headers) = sign("secret", timestamp, &Method::GET, "/a", None);
+            assert!(verify(&credentials, &headers, &Method::GET, "/a", false).is_err());
+        }
+
+        let (credentials, headers) = sign("secret", now(), &Method::GET, "/a", None);
+        assert!(SignedRequest::parse(&credentials, &HeaderMap::new()).is_err());
+        assert!(SignedRequest::parse("client", &headers).is_err());
+        assert!(SignedRequest::parse("client:xyz", &headers).is_err());
+    }
+
+    #[test]
+    fn accepts_each_signature_once() {
+        let cache = ReplayCache::default();
+        let signed = |timestamp| {
+            let (credentials, headers) = sign("secret", timestamp, &Method::GET, "/a", None);
+            SignedRequest::parse(&credentials, &headers).unwrap()
+        };
+        let timestamp = now();
+        assert!(cache.check(&signed(timestamp), SKEW).is_ok());
+        assert!(cache.check(&signed(timestamp), SKEW).is_err());
+        assert!(cache.check(&signed(timestamp + 1), SKEW).is_ok());
+
+        // Signatures are forgotten once their timestamp is rejected anyway
+        let old = timestamp - 2 * SKEW.as_secs();
+        assert!(cache.check(&signed(old), SKEW).is_ok());
+        cache.seen.lock().unwrap().swept_at = 0;
+        assert!(cache.check(&signed(old), SKEW).is_ok());
+        assert_eq!(cache.seen.lock().unwrap().expires_at.len(), 3);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::AppState;
+use crate::error::AppError;
+use axum::body::HttpBody;
+use axum::extract::{MatchedPath, OriginalUri, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, Method};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+pub mod acl;
+mod hmac;
+
+/// Routes answered without credentials when public_health is set
+const HEALTH_ROUTES: [&str; 2] = ["/healthz", "/readyz"];
+/// Route answered without credentials when public_version is set
+const VERSION_ROUTE: &str = "/ft/version";
+/// Route answered without credentials when public_metrics is set
+const METRICS_ROUTE: &str = "/metrics";
+/// Routes below this prefix require the admin role
+const ADMIN_ROUTES: &str = "/admin/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    /// GET and HEAD requests only, except for admin routes
+    #[serde(rename = "read")]
+    Read,
+    /// All requests, except for admin routes
+    #[serde(rename = "write")]
+    Write,
+    /// All requests, including admin routes, which expose stored paths
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Role {
+    fn allows(&self, method: &Method, route: Option<&str>) -> bool {
+        if route.is_some_and(|route| route.starts_with(ADMIN_ROUTES)) {
+            return *self == Role::Admin;
+        }
+        match self {
+            Role::Read => method == Method::GET || method == Method::HEAD,
+            Role::Write | Role::Admin => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CredentialConfig {
+    /// Identity of the client, shown in logs
+    pub name: String,
+    pub role: Role,
+    /// Static token sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Shared secret for signing requests, see `auth::hmac`
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    pub credentials: Vec<CredentialConfig>,
+    /// Answer /ft/version without credentials
+    #[serde(default)]
+    pub public_version: bool,
+    /// Answer /healthz and /readyz without credentials
+    #[serde(default = "default_public_health")]
+    pub public_health: bool,
+    /// Answer /metrics without credentials. The metrics cover all buckets
+    /// of the process.
+    #[serde(default)]
+    pub public_metrics: bool,
+    /// Signed requests whose timestamp is further off are rejected
+    #[serde(default = "default_max_clock_skew_secs")]
+    pub max_clock_skew_secs: u64,
+}
+
+fn default_public_health() -> bool {
+    true
+}
+
+fn default_max_clock_skew_secs() -> u64 {
+    300
+}
+
+impl AuthConfig {
+    pub fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        let mut problem = |field: &str, message: &str| {
+            problems.push(format!("{}.auth.{}: {}", at, field, message));
+        };
+
+        if self.credentials.is_empty() {
+            problem("credentials", "at least one credential is required");
+        }
+        for (i, credential) in self.credentials.iter().enumerate() {
+            let field = format!("credentials[{}]", i);
+            if credential.name.is_empty() {
+                problem(&field, "name must not be empty");
+            }
+            if self.credentials[..i]
+                .iter()
+                .any(|other| other.name == credential.name)
+            {
+                problem(&field, "duplicates the name of another credential");
+            }
+            match (&credential.token, &credential.hmac_secret) {
+                (Some(token), None) if token.is_empty() => {
+                    problem(&field, "token must not be empty")
+                }
+                (None, Some(secret)) if secret.is_empty() => {
+                    problem(&field, "hmac_secret must not be empty")
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => problem(&field, "exactly one of token and hmac_secret is required"),
+            }
+        }
+    }
+}
+
+/**
+ * Authenticated client of a request.
+ */
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+/**
+ * Credentials of a bucket, checked for every request to it.
+ */
+#[derive(Clone)]
+pub struct Auth {
+    config: AuthConfig,
+    replays: Arc<hmac::ReplayCache>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        Auth {
+            config: config.clone(),
+            replays: Arc::default(),
+        }
+    }
+
+    fn is_public(&self, route: &str) -> bool {
+        (self.config.public_health && HEALTH_ROUTES.contains(&route))
+            || (self.config.public_version && route == VERSION_ROUTE)
+            || (self.config.public_metrics && route == METRICS_ROUTE)
+    }
+
+    /**
+     * Find the credential the request was made with.
+     * path_and_query is the one the client sent, before routing.
+     */
+    fn authenticate(
+        &self,
+        method: &Method,
+        path_and_query: &str,
+        headers: &HeaderMap,
+        has_body: bool,
+    ) -> Result<Identity, AppError> {
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::Unauthorized("Missing Authorization header".to_string()))?;
+
+        let credential = if let Some(token) = authorization.strip_prefix("Bearer ") {
+            self.config.credentials.iter().find(|credential| {
+                credential
+                    .token
+                    .as_ref()
+                    .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes())))
+            })
+        } else if let Some(signed) = authorization.strip_prefix(hmac::SCHEME) {
+            let signed = hmac::SignedRequest::parse(signed, headers)?;
+            let credential = self
+                .config
+                .credentials
+                .iter()
+                .find(|credential| credential.name == signed.name);
+            match credential.and_then(|credential| credential.hmac_secret.as_ref()) {
+                Some(secret) => {
+                    let max_skew = Duration::from_secs(self.config.max_clock_skew_secs);
+                    signed.verify(secret, method, path_and_query, headers, has_body, max_skew)?;
+                    self.replays.check(&signed, max_skew)?;
+                    credential
+                }
+                None => None,
+            }
+        } else {
+            return Err(AppError::Unauthorized(
+                "Unsupported Authorization scheme".to_string(),
+            ));
+        };
+
+        credential
+            .map(|credential| Identity {
+                name: credential.name.clone(),
+                role: credential.role,
+            })
+            .ok_or_else(|| AppError::Unauthorized("Invalid credentials".to_string()))
+    }
+}
+
+/**
+ * Middleware rejecting requests without valid credentials, or with
+ * credentials whose role does not allow the request method.
+ * The identity is added to the request extensions.
+ * Must be added as route layer, so that the matched route is known.
+ */
+pub async fn require_auth(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(auth) = &state.auth else {
+        return Ok(next.run(request).await);
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_string());
+    if route.as_deref().is_some_and(|route| auth.is_public(route)) {
+        return Ok(next.run(request).await);
+    }
+
+    // Buckets on a shared listener see the path without the /b/{name} prefix
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| request.uri().clone(), |uri| uri.0.clone());
+    let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+    let body = request.body();
+    let has_body = !body.is_end_stream() && body.size_hint().exact() != Some(0);
+    let identity = auth.authenticate(
+        request.method(),
+        path_and_query,
+        request.headers(),
+        has_body,
+    )?;
+
+    if !identity.role.allows(request.method(), route.as_deref()) {
+        acl::audit_denial(
+            &state.bucket_name,
+            Some(&identity),
+            request.method().as_str(),
+            path_and_query,
+        );
+        return Err(AppError::Forbidden(format!(
+            "{} may not send {} requests to {}",
+            identity.name,
+            request.method(),
+            uri.path()
+        )));
+    }
+    debug!("Authenticated as: {}", identity.name);
+    request.extensions_mut().insert(identity.clone());
+    let mut response = next.run(request).await;
+    // For the access log, which runs before authentication
+    response.extensions_mut().insert(identity);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    async fn router(dir: &std::path::Path) -> Router {
+        router_with(dir, json!({})).await
+    }
+
+    /**
+     * Router with test credentials, auth settings of extra added.
+     */
+    async fn router_with(dir: &std::path::Path, extra: serde_json::Value) -> Router {
+        let mut auth = json!({
+            "credentials": [
+                {"name": "reader", "role": "read", "token": "read-token"},
+                {"name": "writer", "role": "write", "token": "write-token"},
+                {"name": "admin", "role": "admin", "token": "admin-token"},
+                {"name": "signer", "role": "write", "hmac_secret": "secret"}
+            ]
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            auth[key] = value.clone();
+        }
+        let config = testing::bucket_config(dir, json!({ "auth": auth }));
+        let state = testing::app_state(&config).await;
+        Router::new()
+            .route("/ft/files/{*path}", get(|| async {}).put(|| async {}))
+            .route("/admin/locks", get(|| async {}))
+            .route("/healthz", get(|| async {}))
+            .route("/metrics", get(|| async {}))
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                require_auth,
+            ))
+            .with_state(state)
+    }
+
+    async fn status(router: &Router, method: Method, uri: &str, token: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn roles_limit_methods() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+
+        let put = |token| status(&router, Method::PUT, "/ft/files/a", Some(token));
+        assert_eq!(put("read-token").await, StatusCode::FORBIDDEN);
+        assert_eq!(put("write-token").await, StatusCode::OK);
+        assert_eq!(put("admin-token").await, StatusCode::OK);
+        let get = status(&router, Method::GET, "/ft/files/a", Some("read-token"));
+        assert_eq!(get.await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_require_admin_role() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+
+        let get = |token| status(&router, Method::GET, "/admin/locks", token);
+        assert_eq!(get(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get(Some("read-token")).await, StatusCode::FORBIDDEN);
+        assert_eq!(get(Some("write-token")).await, StatusCode::FORBIDDEN);
+        assert_eq!(get(Some("admin-token")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tokens_except_on_public_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+
+        let get = |uri| status(&router, Method::GET, uri, Some("wrong"));
+        assert_eq!(get("/ft/files/a").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/healthz").await, StatusCode::OK);
+    }
+
+    /**
+     * Request signed by the signer credential, with body and its checksum
+     * if given.
+     */
+    fn signed(
+        method: Method,
+        uri: &str,
+        timestamp: u64,
+        body: &[u8],
+        checksum: Option<&str>,
+    ) -> Request {
+        use ::hmac::Mac;
+        let mut mac = ::hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+        let line = format!(
+            "{}\n{}\n{}\n{}",
+            timestamp,
+            method,
+            uri,
+            checksum.unwrap_or("")
+        );
+        mac.update(line.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("HMAC-SHA256 signer:{}", signature))
+            .header("X-Timestamp", timestamp.to_string());
+        if let Some(checksum) = checksum {
+            request = request.header("SHA256-Checksum", checksum);
+        }
+        request.body(Body::from(body.to_vec())).unwrap()
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    async fn send(router: &Router, request: Request) -> StatusCode {
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn accepts_signed_requests_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+
+        let timestamp = now();
+        let request = || signed(Method::GET, "/ft/files/a?x=1", timestamp, b"", None);
+        assert_eq!(send(&router, request()).await, StatusCode::OK);
+        assert_eq!(send(&router, request()).await, StatusCode::UNAUTHORIZED);
+        let later = signed(Method::GET, "/ft/files/a?x=1", timestamp + 1, b"", None);
+        assert_eq!(send(&router, later).await, StatusCode::OK);
+
+        let stale = signed(Method::GET, "/ft/files/a", now() - 3600, b"", None);
+        assert_eq!(send(&router, stale).await, StatusCode::UNAUTHORIZED);
+        let mut tampered = signed(Method::GET, "/ft/files/a", now(), b"", None);
+        *tampered.uri_mut() = "/ft/files/b".parse().unwrap();
+        assert_eq!(send(&router, tampered).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_with_a_body_need_a_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+
+        let unchecked = signed(Method::PUT, "/ft/files/a", now(), b"data", None);
+        assert_eq!(send(&router, unchecked).await, StatusCode::UNAUTHORIZED);
+        let checksum = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b"data"));
+        let checked = signed(Method::PUT, "/ft/files/a", now(), b"data", Some(&checksum));
+        assert_eq!(send(&router, checked).await, StatusCode::OK);
+        let mut swapped = signed(Method::PUT, "/ft/files/b", now(), b"", Some(&checksum));
+        swapped
+            .headers_mut()
+            .insert("SHA256-Checksum", "0".repeat(64).parse().unwrap());
+        assert_eq!(send(&router, swapped).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn metrics_require_credentials_unless_public() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = router(dir.path()).await;
+        let get =
+            |router, token| async move { status(&router, Method::GET, "/metrics", token).await };
+        assert_eq!(get(router.clone(), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get(router, Some("read-token")).await, StatusCode::OK);
+
+        let dir = tempfile::tempdir().unwrap();
+        let public = router_with(dir.path(), json!({"public_metrics": true})).await;
+        assert_eq!(get(public, None).await, StatusCode::OK);
+    }
+}
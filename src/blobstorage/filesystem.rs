@@ -0,0 +1,229 @@
+use crate::blobstorage::BlobStorageTrait;
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Directory below the root holding uploads in progress
+const STAGING_DIR: &str = "staging";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilesystemConfig {
+    pub path: String,
+}
+
+impl Default for FilesystemConfig {
+    fn default() -> Self {
+        Self {
+            path: "blobs".to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Filesystem {
+    root: PathBuf,
+}
+
+impl Filesystem {
+    /**
+     * Blobs are spread over subdirectories named after the first two characters
+     * of the hash, so that no single directory grows too large.
+     */
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+}
+
+/**
+ * Temporary file an upload is written to, removed unless committed.
+ */
+pub struct StagedFile {
+    path: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+    committed: bool,
+}
+
+impl StagedFile {
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), AppError> {
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed
+            && let Err(e) = std::fs::remove_file(&self.path)
+            && e.kind() != ErrorKind::NotFound
+        {
+            warn!(
+                "Failed to remove staged upload: {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl BlobStorageTrait for Filesystem {
+    async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError> {
+        let fs_config = config.filesystem.clone().unwrap_or_default();
+        let root = PathBuf::from(&fs_config.path);
+        debug!("Using blob directory: {}", root.display());
+        tokio::fs::create_dir_all(root.join(STAGING_DIR)).await?;
+        Ok(Box::new(Filesystem { root }))
+    }
+
+    type Staged = StagedFile;
+
+    async fn stage(&self) -> Result<StagedFile, AppError> {
+        let path = self
+            .root
+            .join(STAGING_DIR)
+            .join(format!("{}.tmp", Uuid::new_v4()));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(StagedFile {
+            path,
+            file,
+            size: 0,
+            committed: false,
+        })
+    }
+
+    async fn commit(&self, mut staged: StagedFile, hash: &str) -> Result<(), AppError> {
+        // Written to disk before it becomes visible, as renaming first could
+        // leave an empty or partial blob under the hash after a crash
+        staged.file.flush().await?;
+        staged.file.sync_all().await?;
+        let path = self.blob_path(hash);
+        let dir = path.parent().unwrap();
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::rename(&staged.path, &path).await?;
+        staged.committed = true;
+        // The rename itself only survives a crash once its directory is synced
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, hash: &str) -> Result<(), AppError> {
+        match tokio::fs::remove_file(self.blob_path(hash)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, hash: &str) -> Result<bool, AppError> {
+        Ok(tokio::fs::try_exists(self.blob_path(hash)).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, AppError> {
+        let mut hashes = Vec::new();
+        let mut dirs = tokio::fs::read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() || dir.file_name() == STAGING_DIR {
+                continue;
+            }
+            let mut blobs = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(blob) = blobs.next_entry().await? {
+                let name = blob.file_name().to_string_lossy().to_string();
+                // Skip leftovers of interrupted writes
+                if !name.ends_with(".tmp") {
+                    hashes.push(name);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    async fn probe(&self) -> Result<(), AppError> {
+        if !tokio::fs::metadata(&self.root).await?.is_dir() {
+            return Err(AppError::StorageUnavailable(format!(
+                "{} is not a directory",
+                self.root.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    async fn storage(dir: &std::path::Path) -> Filesystem {
+        let config = testing::bucket_config(dir, json!({}));
+        *Filesystem::new(&config).await.unwrap()
+    }
+
+    fn staged_files(storage: &Filesystem) -> usize {
+        std::fs::read_dir(storage.root.join(STAGING_DIR))
+            .unwrap()
+            .count()
+    }
+
+    #[tokio::test]
+    async fn commits_staged_blobs_under_their_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path()).await;
+        let mut staged = storage.stage().await.unwrap();
+        staged.write(b"data").await.unwrap();
+        assert_eq!(staged.size(), 4);
+        assert_eq!(staged_files(&storage), 1);
+        assert!(storage.list().await.unwrap().is_empty());
+
+        storage.commit(staged, "abcdef").await.unwrap();
+        assert_eq!(staged_files(&storage), 0);
+        assert!(storage.exists("abcdef").await.unwrap());
+        assert_eq!(storage.list().await.unwrap(), ["abcdef"]);
+        let data = std::fs::read(storage.root.join("ab").join("abcdef")).unwrap();
+        assert_eq!(data, b"data");
+    }
+
+    #[tokio::test]
+    async fn removes_uncommitted_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path()).await;
+        let mut staged = storage.stage().await.unwrap();
+        staged.write(b"data").await.unwrap();
+        drop(staged);
+        assert_eq!(staged_files(&storage), 0);
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_blobs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path()).await;
+        let staged = storage.stage().await.unwrap();
+        storage.commit(staged, "abcdef").await.unwrap();
+
+        storage.delete("abcdef").await.unwrap();
+        assert!(!storage.exists("abcdef").await.unwrap());
+        // Deleting a missing blob is not an error
+        storage.delete("abcdef").await.unwrap();
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn probe_fails_without_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path()).await;
+        storage.probe().await.unwrap();
+        std::fs::remove_dir_all(&storage.root).unwrap();
+        assert!(storage.probe().await.is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, debug, info, info_span};
+
+pub mod filesystem;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub enum BlobStorageType {
+    #[default]
+    #[serde(rename = "filesystem")]
+    Filesystem,
+}
+
+pub(crate) trait BlobStorageTrait {
+    /// Upload in progress, not visible as blob until committed
+    type Staged;
+
+    async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError>
+    where
+        Self: Sized;
+
+    async fn stage(&self) -> Result<Self::Staged, AppError>;
+    async fn commit(&self, staged: Self::Staged, hash: &str) -> Result<(), AppError>;
+    async fn delete(&self, hash: &str) -> Result<(), AppError>;
+    async fn exists(&self, hash: &str) -> Result<bool, AppError>;
+    async fn list(&self) -> Result<Vec<String>, AppError>;
+    async fn probe(&self) -> Result<(), AppError>;
+}
+
+#[derive(Clone)]
+pub enum BlobStorage {
+    Filesystem(filesystem::Filesystem),
+}
+
+/**
+ * Blob being written, before its hash is known. Dropping it without
+ * committing discards what was written.
+ */
+pub enum StagedBlob {
+    Filesystem(filesystem::StagedFile),
+}
+
+impl StagedBlob {
+    /**
+     * Append data, waiting until the storage has taken it.
+     */
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), AppError> {
+        match self {
+            StagedBlob::Filesystem(staged) => staged.write(data).await,
+        }
+    }
+
+    /**
+     * Number of bytes written so far.
+     */
+    pub fn size(&self) -> u64 {
+        match self {
+            StagedBlob::Filesystem(staged) => staged.size(),
+        }
+    }
+}
+
+impl BlobStorage {
+    pub async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError> {
+        match config.blobstorage_type {
+            BlobStorageType::Filesystem => {
+                info!("Using filesystem as blob storage");
+                let storage = filesystem::Filesystem::new(config).await?;
+                Ok(Box::new(BlobStorage::Filesystem(*storage)))
+            }
+        }
+    }
+
+    /**
+     * Start writing a blob whose hash is not known yet.
+     */
+    pub async fn stage(&self) -> Result<StagedBlob, AppError> {
+        match self {
+            BlobStorage::Filesystem(storage) => Ok(StagedBlob::Filesystem(storage.stage().await?)),
+        }
+    }
+
+    /**
+     * Store the staged blob under hash, which must be the hash of its contents.
+     * Caller must hold an exclusive lock on the hash.
+     */
+    pub async fn commit(&self, staged: StagedBlob, hash: &str) -> Result<(), AppError> {
+        debug!("Putting blob: {} ({} bytes)", hash, staged.size());
+        let commit = async {
+            match (self, staged) {
+                (BlobStorage::Filesystem(storage), StagedBlob::Filesystem(staged)) => {
+                    storage.commit(staged, hash).await
+                }
+            }
+        };
+        commit
+            .instrument(info_span!("blob", operation = "commit", hash))
+            .await
+    }
+
+    /**
+     * Delete the blob with given hash.
+     * Deleting a blob that does not exist is not an error.
+     * Caller must hold an exclusive lock on the hash.
+     */
+    pub async fn delete(&self, hash: &str) -> Result<(), AppError> {
+        debug!("Deleting blob: {}", hash);
+        let delete = async {
+            match self {
+                BlobStorage::Filesystem(storage) => storage.delete(hash).await,
+            }
+        };
+        delete
+            .instrument(info_span!("blob", operation = "delete", hash))
+            .await
+    }
+
+    /**
+     * Check whether the blob with given hash is stored.
+     */
+    pub async fn exists(&self, hash: &str) -> Result<bool, AppError> {
+        debug!("Checking blob: {}", hash);
+        match self {
+            BlobStorage::Filesystem(storage) => storage.exists(hash).await,
+        }
+    }
+
+    /**
+     * List hashes of all stored blobs.
+     */
+    pub async fn list(&self) -> Result<Vec<String>, AppError> {
+        debug!("Listing blobs");
+        match self {
+            BlobStorage::Filesystem(storage) => storage.list().await,
+        }
+    }
+
+    /**
+     * Cheaply check that the storage is usable.
+     */
+    pub async fn probe(&self) -> Result<(), AppError> {
+        match self {
+            BlobStorage::Filesystem(storage) => storage.probe().await,
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::AppState;
+use crate::commands::app_states;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::kvstorage::stats::Usage;
+use crate::locks;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+/**
+ * Inconsistencies found by one pass over a bucket. Uploads in progress
+ * cause them too, e.g. a blob is stored and counted shortly before the
+ * path referencing it is.
+ */
+#[derive(Default)]
+struct Suspects {
+    /// Stored reference count and number of referencing paths, by hash
+    ref_counts: HashMap<String, (i64, i64)>,
+    /// Blobs that no path references
+    unreferenced: HashSet<String>,
+    /// Tracked and counted usage
+    usage: Option<(Usage, Usage)>,
+    /// Hashes referenced by at least one path
+    referenced: HashSet<String>,
+}
+
+impl Suspects {
+    fn is_empty(&self) -> bool {
+        self.ref_counts.is_empty() && self.unreferenced.is_empty() && self.usage.is_none()
+    }
+
+    /**
+     * Keep only what is still inconsistent in a later pass, with its
+     * current values.
+     */
+    fn persisting(self, later: Suspects) -> Suspects {
+        Suspects {
+            ref_counts: later
+                .ref_counts
+                .into_iter()
+                .filter(|(hash, _)| self.ref_counts.contains_key(hash))
+                .collect(),
+            unreferenced: later
+                .unreferenced
+                .intersection(&self.unreferenced)
+                .cloned()
+                .collect(),
+            usage: later.usage.filter(|_| self.usage.is_some()),
+            referenced: later.referenced,
+        }
+    }
+}
+
+async fn find_suspects(state: &AppState) -> Result<Suspects, AppError> {
+    let bucket = &state.bucket_name;
+    let stored: HashMap<String, i32> = state
+        .kvstorage
+        .list_ref_counts(bucket)
+        .await?
+        .into_iter()
+        .collect();
+    let actual: HashMap<String, i64> = state
+        .kvstorage
+        .count_refs_by_hash(bucket)
+        .await?
+        .into_iter()
+        .collect();
+
+    let mut suspects = Suspects::default();
+    let hashes: HashSet<&String> = stored.keys().chain(actual.keys()).collect();
+    for hash in hashes {
+        let ref_count = stored.get(hash).copied().unwrap_or(0) as i64;
+        let refs = actual.get(hash).copied().unwrap_or(0);
+        if ref_count != refs {
+            suspects.ref_counts.insert(hash.clone(), (ref_count, refs));
+        }
+    }
+
+    // Usage is tracked once a server started for the bucket
+    let tracked = state.kvstorage.get_usage(bucket).await?;
+    let counted = state.kvstorage.count_usage(bucket).await?;
+    if let Some(tracked) = tracked
+        && tracked != counted
+    {
+        suspects.usage = Some((tracked, counted));
+    }
+
+    // Uploads in progress are staged apart from the blobs and not listed
+    for hash in state.blobstorage.list().await? {
+        if !actual.contains_key(&hash) {
+            suspects.unreferenced.insert(hash);
+        }
+    }
+    suspects.referenced = actual.into_keys().collect();
+    Ok(suspects)
+}
+
+/**
+ * Check that reference counts match the paths referencing each hash and that
+ * every referenced blob is stored. Return the number of problems found.
+ *
+ * Also check that the tracked usage matches the paths and blobs.
+ *
+ * Inconsistencies are only reported if they are still found after grace,
+ * so that uploads in progress are not reported.
+ *
+ * With repair, reference counts are set to the number of referencing paths
+ * and the usage is recounted.
+ * Repair must only be run while no server is using the bucket, as uploads
+ * briefly leave reference counts ahead of the paths.
+ */
+pub async fn fsck(
+    config: &Config,
+    bucket: Option<&str>,
+    repair: bool,
+    grace: Duration,
+) -> Result<usize, Box<dyn Error>> {
+    let mut problems = 0;
+    for state in app_states(config, bucket).await? {
+        let bucket = &state.bucket_name;
+        info!("Checking bucket: {}", bucket);
+
+        let mut suspects = find_suspects(&state).await?;
+        if !suspects.is_empty() && !grace.is_zero() {
+            info!("Checking bucket: {} again in {:?}", bucket, grace);
+            tokio::time::sleep(grace).await;
+            suspects = suspects.persisting(find_suspects(&state).await?);
+        }
+
+        for (hash, (ref_count, refs)) in &suspects.ref_counts {
+            problems += 1;
+            println!(
+                "{}: hash {} has ref count {}, but is referenced by {} paths",
+                bucket, hash, ref_count, refs
+            );
+            if repair {
+                state
+                    .kvstorage
+                    .set_ref_count(bucket, hash, *refs as i32)
+                    .await?;
+            }
+        }
+
+        for hash in &suspects.referenced {
+            // Rechecked under the hash lock, as the blob may have been
+            // unlinked and deleted since the paths were counted
+            let hash_lock = locks::hash_lock(bucket, hash);
+            state.locks.acquire_shared(&hash_lock).await?;
+            let missing = async {
+                Ok::<bool, AppError>(
+                    state.kvstorage.get_ref_count(bucket, hash).await? > 0
+                        && !state.blobstorage.exists(hash).await?,
+                )
+            }
+            .await;
+            state.locks.release(&hash_lock);
+            if missing? {
+                problems += 1;
+                println!("{}: blob {} is referenced, but missing", bucket, hash);
+            }
+        }
+
+        if let Some((tracked, counted)) = &suspects.usage {
+            problems += 1;
+            println!(
+                "{}: tracked usage {:?} does not match counted usage {:?}",
+                bucket, tracked, counted
+            );
+            if repair {
+                // Counted again, as repaired reference counts change it
+                let counted = state.kvstorage.count_usage(bucket).await?;
+                state.kvstorage.set_usage(bucket, &counted).await?;
+            }
+        }
+
+        for hash in &suspects.unreferenced {
+            problems += 1;
+            println!(
+                "{}: blob {} is not referenced, run gc to remove it",
+                bucket, hash
+            );
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    /**
+     * Store a blob and count a reference to it, like an upload does before
+     * it stores the path. Return the hash.
+     */
+    async fn store_blob(state: &AppState, data: &[u8]) -> String {
+        let hash = hex::encode(Sha256::digest(data));
+        let mut staged = state.blobstorage.stage().await.unwrap();
+        staged.write(data).await.unwrap();
+        state.blobstorage.commit(staged, &hash).await.unwrap();
+        let bucket = &state.bucket_name;
+        state
+            .kvstorage
+            .increment_ref_count(bucket, &hash, None)
+            .await
+            .unwrap();
+        let size = data.len() as i64;
+        state
+            .kvstorage
+            .set_blob_size(bucket, &hash, size)
+            .await
+            .unwrap();
+        hash
+    }
+
+    /**
+     * Store path referencing hash, finishing the upload.
+     */
+    async fn store_path(state: &AppState, path: &str, hash: &str, size: i64) {
+        let bucket = &state.bucket_name;
+        state
+            .kvstorage
+            .set_ref_file(bucket, path, hash)
+            .await
+            .unwrap();
+        let delta = Usage {
+            logical_bytes: size,
+            physical_bytes: size,
+            paths: 1,
+        };
+        state.kvstorage.add_usage(bucket, &delta).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_and_repairs_inconsistencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = testing::bucket_config(dir.path(), json!({}));
+        let state = testing::app_state(&bucket).await;
+        let config = testing::config(bucket);
+
+        let hash = store_blob(&state, b"data").await;
+        store_path(&state, "a", &hash, 4).await;
+        assert_eq!(fsck(&config, None, false, Duration::ZERO).await.unwrap(), 0);
+
+        state
+            .kvstorage
+            .set_ref_count("test", &hash, 3)
+            .await
+            .unwrap();
+        assert_eq!(fsck(&config, None, true, Duration::ZERO).await.unwrap(), 2);
+        assert_eq!(fsck(&config, None, false, Duration::ZERO).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_and_unreferenced_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = testing::bucket_config(dir.path(), json!({}));
+        let state = testing::app_state(&bucket).await;
+        let config = testing::config(bucket);
+
+        let hash = store_blob(&state, b"data").await;
+        store_path(&state, "a", &hash, 4).await;
+        state.blobstorage.delete(&hash).await.unwrap();
+        let mut staged = state.blobstorage.stage().await.unwrap();
+        staged.write(b"orphan").await.unwrap();
+        let orphan = hex::encode(Sha256::digest(b"orphan"));
+        state.blobstorage.commit(staged, &orphan).await.unwrap();
+
+        assert_eq!(fsck(&config, None, false, Duration::ZERO).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn ignores_uploads_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = testing::bucket_config(dir.path(), json!({}));
+        let state = testing::app_state(&bucket).await;
+        let config = testing::config(bucket);
+
+        // Staged uploads are not listed as blobs
+        let mut staged = state.blobstorage.stage().await.unwrap();
+        staged.write(b"staged").await.unwrap();
+
+        let hash = store_blob(&state, b"data").await;
+        let finish = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            store_path(&state, "a", &hash, 4).await;
+        };
+        let (problems, ()) = tokio::join!(
+            fsck(&config, None, false, Duration::from_millis(500)),
+            finish
+        );
+        assert_eq!(problems.unwrap(), 0);
+        drop(staged);
+    }
+}
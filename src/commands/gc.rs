@@ -0,0 +1,108 @@
+use crate::commands::app_states;
+use crate::config::Config;
+use crate::locks;
+use crate::locks::LocksType;
+use std::collections::HashSet;
+use std::error::Error;
+use tracing::{info, warn};
+
+/**
+ * Delete blobs that are no longer referenced, together with their zero
+ * reference counts. Each blob is rechecked under an exclusive hash lock,
+ * so gc is safe to run next to a server as long as they share locks,
+ * i.e. use file or lease locks.
+ */
+pub async fn gc(
+    config: &Config,
+    bucket: Option<&str>,
+    dry_run: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let mut removed = 0;
+    for state in app_states(config, bucket).await? {
+        let bucket = &state.bucket_name;
+        info!("Collecting garbage in bucket: {}", bucket);
+        if let Some(bucket_config) = config.buckets.iter().find(|b| &b.name == bucket)
+            && matches!(bucket_config.locks_type, LocksType::Memory)
+        {
+            warn!(
+                "Bucket {} uses memory locks, gc is not safe while a server is running",
+                bucket
+            );
+        }
+
+        let mut candidates: HashSet<String> = state
+            .kvstorage
+            .list_ref_counts(bucket)
+            .await?
+            .into_iter()
+            .filter(|(_, ref_count)| *ref_count <= 0)
+            .map(|(hash, _)| hash)
+            .collect();
+        candidates.extend(state.blobstorage.list().await?);
+
+        for hash in candidates {
+            let hash_lock = locks::hash_lock(bucket, &hash);
+            state.locks.acquire_exclusive(&hash_lock).await?;
+            let result = async {
+                if state.kvstorage.get_ref_count(bucket, &hash).await? > 0 {
+                    return Ok(false);
+                }
+                if !dry_run {
+                    state.blobstorage.delete(&hash).await?;
+                    state.kvstorage.delete_ref_count(bucket, &hash).await?;
+                }
+                Ok::<bool, Box<dyn Error>>(true)
+            }
+            .await;
+            state.locks.release(&hash_lock);
+
+            if result? {
+                removed += 1;
+                if dry_run {
+                    println!("{}: would remove blob {}", bucket, hash);
+                } else {
+                    println!("{}: removed blob {}", bucket, hash);
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn removes_unreferenced_blobs_unless_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = testing::bucket_config(dir.path(), json!({}));
+        let state = testing::app_state(&bucket).await;
+        let config = testing::config(bucket);
+
+        let mut staged = state.blobstorage.stage().await.unwrap();
+        staged.write(b"orphan").await.unwrap();
+        state.blobstorage.commit(staged, "ab12").await.unwrap();
+        state
+            .kvstorage
+            .set_ref_count("test", "cd34", 0)
+            .await
+            .unwrap();
+
+        assert_eq!(gc(&config, None, true).await.unwrap(), 2);
+        assert!(state.blobstorage.exists("ab12").await.unwrap());
+        assert_eq!(gc(&config, None, false).await.unwrap(), 2);
+        assert!(!state.blobstorage.exists("ab12").await.unwrap());
+        assert!(
+            state
+                .kvstorage
+                .list_ref_counts("test")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(gc(&config, None, false).await.unwrap(), 0);
+    }
+}
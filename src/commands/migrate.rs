@@ -0,0 +1,15 @@
+use crate::commands::app_states;
+use crate::config::Config;
+use std::error::Error;
+use tracing::info;
+
+/**
+ * Create or update the KV storage schema of the buckets.
+ */
+pub async fn migrate(config: &Config, bucket: Option<&str>) -> Result<(), Box<dyn Error>> {
+    for state in app_states(config, bucket).await? {
+        info!("Migrating KV storage for bucket: {}", state.bucket_name);
+        state.kvstorage.setup().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use crate::AppState;
+use crate::config::Config;
+use std::error::Error;
+
+pub mod fsck;
+pub mod gc;
+pub mod migrate;
+pub mod serve;
+pub mod stats;
+
+/**
+ * Build app state for every bucket, or only for the bucket with given name.
+ */
+pub async fn app_states(
+    config: &Config,
+    bucket: Option<&str>,
+) -> Result<Vec<AppState>, Box<dyn Error>> {
+    let buckets: Vec<_> = config
+        .buckets
+        .iter()
+        .filter(|b| bucket.is_none_or(|name| b.name == name))
+        .collect();
+    if buckets.is_empty() {
+        return Err(format!("No bucket named: {}", bucket.unwrap_or_default()).into());
+    }
+
+    let mut states = vec![];
+    for bucket in buckets {
+        states.push(AppState::new(bucket).await?);
+    }
+    Ok(states)
+}
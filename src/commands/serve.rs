@@ -0,0 +1,689 @@
+use crate::AppState;
+use crate::access_log;
+use crate::auth;
+use crate::config::{BucketConfig, Config};
+use crate::error::AppError;
+use crate::in_flight;
+use crate::listen::{self, ClientAddr, ListenAddr, UnixSocketConfig};
+use crate::metrics;
+use crate::ratelimit;
+use crate::request_id;
+use crate::routes;
+use crate::routes::admin::locks::admin_locks;
+use crate::routes::admin::stats::admin_stats;
+use crate::routes::dispatch::{BucketRoute, BucketRoutes, dispatch};
+use crate::routes::ft::put_file::ft_put_file;
+use crate::routes::ft::version::ft_version;
+use crate::routes::health::{healthz, readyz, readyz_draining};
+use crate::routes::metrics::metrics;
+use crate::tls::{TlsConfig, TlsListener};
+use axum::Router;
+use axum::extract::connect_info::Connected;
+use axum::middleware;
+use axum::routing::{get, put};
+use axum::serve::{IncomingStream, Listener};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{Level, error, info, warn};
+
+/**
+ * Listener shared by all buckets with the same address and port.
+ */
+struct RunningListener {
+    address: ListenAddr,
+    tls: Option<TlsConfig>,
+    unix_socket: Option<UnixSocketConfig>,
+    routes: BucketRoutes,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl RunningListener {
+    async fn start(address: &ListenAddr, bucket: &BucketConfig) -> Result<Self, Box<dyn Error>> {
+        let routes = BucketRoutes::default();
+        let app = Router::new()
+            .route("/healthz", get(healthz))
+            .fallback(dispatch)
+            .layer(middleware::from_fn(access_log::log_requests))
+            // Outside of the bucket routers, so that every response has an id
+            .layer(middleware::from_fn(request_id::assign))
+            .with_state(routes.clone());
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let handle = match address {
+            ListenAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                spawn_listener(listener, bucket.tls.as_ref(), app, shutdown_rx)?
+            }
+            ListenAddr::Unix(path) => {
+                let unix_socket = bucket.unix_socket.clone().unwrap_or_default();
+                let listener = listen::bind_unix(path, &unix_socket).await?;
+                spawn_listener(listener, bucket.tls.as_ref(), app, shutdown_rx)
+                    .inspect_err(|_| listen::remove_socket(path))?
+            }
+        };
+        let scheme = if bucket.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        info!("Listening on: {} ({})", address, scheme);
+
+        Ok(RunningListener {
+            address: address.clone(),
+            tls: bucket.tls.clone(),
+            unix_socket: bucket.unix_socket.clone(),
+            routes,
+            shutdown,
+            handle,
+        })
+    }
+
+    /**
+     * Replace the router of the bucket with one answering as draining.
+     */
+    fn drain(&self, name: &str) {
+        if let Some(route) = self.routes.write().unwrap().get_mut(name) {
+            route.router = draining_router(name);
+        }
+    }
+
+    /**
+     * Stop accepting connections, without waiting for open ones.
+     */
+    fn close(self) -> JoinHandle<()> {
+        let _ = self.shutdown.send(());
+        // Removed right away, so that a restarted listener can bind again
+        if let ListenAddr::Unix(path) = &self.address {
+            listen::remove_socket(path);
+        }
+        self.handle
+    }
+
+    /**
+     * Stop accepting connections and wait for open ones to be closed,
+     * aborting them after drain_timeout.
+     */
+    async fn stop(self, drain_timeout: Duration) {
+        let address = self.address.clone();
+        let handle = self.close();
+        let abort = handle.abort_handle();
+        if tokio::time::timeout(drain_timeout, handle).await.is_err() {
+            warn!(
+                "Connections to: {} were not closed within {:?}, aborted them",
+                address, drain_timeout
+            );
+            abort.abort();
+        }
+    }
+}
+
+/**
+ * Serve app on listener until shutdown, terminating TLS if configured.
+ */
+fn spawn_listener<L>(
+    listener: L,
+    tls: Option<&TlsConfig>,
+    app: Router,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<JoinHandle<()>, Box<dyn Error>>
+where
+    L: Listener,
+    L::Addr: Debug,
+    for<'a> ClientAddr:
+        Connected<IncomingStream<'a, L>> + Connected<IncomingStream<'a, TlsListener<L>>>,
+{
+    Ok(match tls {
+        None => tokio::spawn(run_listener(listener, app, shutdown)),
+        Some(tls) => {
+            let listener = TlsListener::new(listener, tls)?;
+            tokio::spawn(run_listener(listener, app, shutdown))
+        }
+    })
+}
+
+async fn run_listener<L>(listener: L, app: Router, shutdown: oneshot::Receiver<()>)
+where
+    L: Listener,
+    L::Addr: Debug,
+    for<'a> ClientAddr: Connected<IncomingStream<'a, L>>,
+{
+    let address = listener.local_addr();
+    let app = app.into_make_service_with_connect_info::<ClientAddr>();
+    let served = axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        })
+        .await;
+    if let Err(e) = served {
+        error!("Listener on: {:?} failed: {}", address, e);
+    }
+}
+
+/**
+ * Storages and background tasks of a single bucket.
+ */
+struct RunningBucket {
+    config: BucketConfig,
+    address: ListenAddr,
+    state: Arc<AppState>,
+    watchdog: JoinHandle<()>,
+}
+
+impl RunningBucket {
+    /**
+     * Set up storages for the bucket.
+     */
+    async fn start(bucket: &BucketConfig) -> Result<Self, Box<dyn Error>> {
+        info!("Starting server for bucket: {}", bucket.name);
+        let address = bucket.listen_addr()?;
+        let state = Arc::new(AppState::new(bucket).await?);
+        state.kvstorage.setup().await?;
+        state.kvstorage.init_usage(&bucket.name).await?;
+
+        let locks = state.locks.clone();
+        let warn_after = Duration::from_secs(bucket.locks_warn_after_secs);
+        let watchdog = tokio::spawn(async move { locks.warn_long_holds(warn_after).await });
+
+        Ok(RunningBucket {
+            config: bucket.clone(),
+            address,
+            state,
+            watchdog,
+        })
+    }
+
+    fn route(&self) -> BucketRoute {
+        BucketRoute {
+            hosts: self.config.hosts.clone(),
+            router: router(self.state.clone()),
+        }
+    }
+
+    /**
+     * Wait for in-flight requests to finish, then release the locks and
+     * close the KV storage. The bucket must not be routed to anymore.
+     *
+     * Requests still running after the drain timeout are aborted.
+     */
+    async fn stop(self) {
+        let name = &self.config.name;
+        let drain_timeout = Duration::from_secs(self.config.drain_timeout_secs);
+        let drained = self.state.in_flight.drain(drain_timeout).await;
+        self.watchdog.abort();
+        if !drained {
+            warn!(
+                "Requests to bucket: {} did not finish within {:?}, aborted them",
+                name, drain_timeout
+            );
+        }
+
+        self.state.locks.close().await;
+        self.state.kvstorage.close().await;
+        info!("Stopped server for bucket: {}", name);
+    }
+}
+
+fn router(app_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/ft/version", get(ft_version))
+        .route("/ft/files/{*path}", put(ft_put_file))
+        .route("/admin/locks", get(admin_locks))
+        .route("/admin/stats", get(admin_stats))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            ratelimit::limit_requests,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.bucket_name.clone(),
+            metrics::track_requests,
+        ))
+        .fallback(routes::fallback)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            in_flight::track_requests,
+        ))
+        .layer(
+            // Logging middleware
+            TraceLayer::new_for_http()
+                .make_span_with(request_id::make_span(&app_state.bucket_name))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .with_state(app_state)
+}
+
+/**
+ * Router of a bucket that is being stopped, answering new requests as
+ * draining while the running ones finish.
+ */
+fn draining_router(name: &str) -> Router {
+    let message = format!("Bucket: {} is shutting down", name);
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz_draining))
+        .fallback(async move || AppError::ShuttingDown(message))
+}
+
+/**
+ * Running buckets and the listeners they are served on.
+ */
+#[derive(Default)]
+struct Server {
+    listeners: HashMap<ListenAddr, RunningListener>,
+    buckets: HashMap<String, RunningBucket>,
+}
+
+impl Server {
+    /**
+     * Start the bucket and serve it on its listener, binding the listener
+     * unless another bucket already uses it.
+     */
+    async fn start_bucket(&mut self, bucket: &BucketConfig) -> Result<(), Box<dyn Error>> {
+        let running = RunningBucket::start(bucket).await?;
+        let listener = match self.listeners.entry(running.address.clone()) {
+            Entry::Occupied(entry)
+                if entry.get().tls != bucket.tls
+                    || entry.get().unix_socket != bucket.unix_socket =>
+            {
+                running.stop().await;
+                return Err(format!(
+                    "Listener settings differ from the other buckets on: {}",
+                    entry.key()
+                )
+                .into());
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match RunningListener::start(&running.address, bucket).await {
+                Ok(listener) => entry.insert(listener),
+                Err(e) => {
+                    running.stop().await;
+                    return Err(e);
+                }
+            },
+        };
+        listener
+            .routes
+            .write()
+            .unwrap()
+            .insert(bucket.name.clone(), running.route());
+        self.buckets.insert(bucket.name.clone(), running);
+        Ok(())
+    }
+
+    /**
+     * Answer new requests to the bucket as draining until it is stopped,
+     * then stop routing to it. Its listener is stopped once it serves no
+     * bucket anymore.
+     */
+    async fn stop_bucket(&mut self, name: &str) {
+        let Some(bucket) = self.buckets.remove(name) else {
+            return;
+        };
+        info!("Stopping server for bucket: {}", name);
+        let drain_timeout = Duration::from_secs(bucket.config.drain_timeout_secs);
+        let address = bucket.address.clone();
+        if let Some(listener) = self.listeners.get(&address) {
+            listener.drain(name);
+        }
+        bucket.stop().await;
+
+        if let Entry::Occupied(entry) = self.listeners.entry(address) {
+            let unused = {
+                let mut routes = entry.get().routes.write().unwrap();
+                routes.remove(name);
+                routes.is_empty()
+            };
+            if unused {
+                entry.remove().stop(drain_timeout).await;
+            }
+        }
+    }
+
+    /**
+     * Apply a new configuration. Removed buckets are drained, changed
+     * buckets are restarted once drained, new buckets are started and
+     * unchanged buckets keep serving.
+     */
+    async fn reload(&mut self, config: &Config) {
+        let names: Vec<String> = self.buckets.keys().cloned().collect();
+        for name in names {
+            let changed = config
+                .buckets
+                .iter()
+                .find(|bucket| bucket.name == name)
+                .is_none_or(|bucket| *bucket != self.buckets[&name].config);
+            // Stop all changed buckets first, so that their listeners are free
+            if changed {
+                self.stop_bucket(&name).await;
+            }
+        }
+
+        for bucket in &config.buckets {
+            if self.buckets.contains_key(&bucket.name) {
+                continue;
+            }
+            if let Err(e) = self.start_bucket(bucket).await {
+                error!("Failed to start server for bucket: {}: {}", bucket.name, e);
+            }
+        }
+    }
+
+    /**
+     * Stop accepting connections on all listeners, then drain all buckets
+     * at once. Requests and connections still open after the drain timeout
+     * are aborted.
+     */
+    async fn shutdown(self) {
+        let drain_timeout = self
+            .buckets
+            .values()
+            .map(|bucket| Duration::from_secs(bucket.config.drain_timeout_secs))
+            .max()
+            .unwrap_or_default();
+        let mut serving = JoinSet::new();
+        for listener in self.listeners.into_values() {
+            let names: Vec<String> = listener.routes.read().unwrap().keys().cloned().collect();
+            for name in names {
+                listener.drain(&name);
+            }
+            serving.spawn(listener.stop(drain_timeout));
+        }
+        let mut stopping = JoinSet::new();
+        for bucket in self.buckets.into_values() {
+            stopping.spawn(bucket.stop());
+        }
+        stopping.join_all().await;
+        serving.join_all().await;
+    }
+}
+
+/**
+ * Start a server for every bucket, reload the configuration from
+ * config_path on SIGHUP and shut down gracefully on SIGTERM or SIGINT.
+ */
+pub async fn serve(config_path: &str, config: Config) -> Result<(), Box<dyn Error>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let mut server = Server::default();
+    for bucket in &config.buckets {
+        server.start_bucket(bucket).await?;
+    }
+
+    let mut config = config;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+        info!("Reloading configuration from: {}", config_path);
+        let new_config = match Config::new(config_path) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                error!("Failed to reload configuration: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = new_config.validate() {
+            error!("Failed to reload configuration: {}", e);
+            continue;
+        }
+        if new_config.logging != config.logging {
+            warn!("Logging configuration changes take effect after a restart");
+        }
+        server.reload(&new_config).await;
+        config = new_config;
+    }
+
+    info!("Shutting down");
+    server.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use std::sync::Weak;
+
+    /**
+     * Bucket on an ephemeral port of the loopback listener, with storages
+     * below dir.
+     */
+    fn bucket(dir: &std::path::Path, name: &str) -> BucketConfig {
+        let dir = dir.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        testing::bucket_config(&dir, json!({"name": name, "port": 0}))
+    }
+
+    fn config(buckets: &[&BucketConfig]) -> Config {
+        let mut config = testing::config(buckets[0].clone());
+        config.buckets = buckets.iter().map(|bucket| (*bucket).clone()).collect();
+        config
+    }
+
+    fn state(server: &Server, name: &str) -> Weak<AppState> {
+        Arc::downgrade(&server.buckets[name].state)
+    }
+
+    fn routed(server: &Server) -> Vec<String> {
+        let mut names: Vec<String> = server
+            .listeners
+            .values()
+            .flat_map(|listener| {
+                listener
+                    .routes
+                    .read()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn reload_restarts_only_changed_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = bucket(dir.path(), "a");
+        let b = bucket(dir.path(), "b");
+        let mut server = Server::default();
+        server.start_bucket(&a).await.unwrap();
+        let first_a = state(&server, "a");
+
+        server.reload(&config(&[&a, &b])).await;
+        assert!(first_a.ptr_eq(&state(&server, "a")), "a was restarted");
+        assert_eq!(routed(&server), ["a", "b"]);
+
+        let mut changed_a = a.clone();
+        changed_a.max_upload_bytes += 1;
+        let first_b = state(&server, "b");
+        server.reload(&config(&[&changed_a, &b])).await;
+        assert!(first_a.upgrade().is_none(), "a was not stopped");
+        assert_eq!(server.buckets["a"].config, changed_a);
+        assert!(first_b.ptr_eq(&state(&server, "b")), "b was restarted");
+
+        server.reload(&config(&[&changed_a])).await;
+        assert!(first_b.upgrade().is_none(), "b was not stopped");
+        assert_eq!(routed(&server), ["a"]);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn failed_start_keeps_other_buckets_serving() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = bucket(dir.path(), "a");
+        let mut broken = bucket(dir.path(), "broken");
+        broken.sqlite.as_mut().unwrap().path =
+            dir.path().join("missing/kv.db").display().to_string();
+        let mut server = Server::default();
+        server.start_bucket(&a).await.unwrap();
+        let first_a = state(&server, "a");
+
+        server.reload(&config(&[&a, &broken])).await;
+        assert!(first_a.ptr_eq(&state(&server, "a")));
+        assert!(!server.buckets.contains_key("broken"));
+        assert_eq!(routed(&server), ["a"]);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn uploads_to_nested_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&bucket(dir.path(), "a")).await;
+        let request = axum::extract::Request::put(
+            "/ft/files/dir/sub/file?last_modified=Mon,%2001%20Jan%202024%2000:00:00%20GMT",
+        )
+        .body(axum::body::Body::from("data"))
+        .unwrap();
+        let response = tower::ServiceExt::oneshot(router(state.clone()), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let hash = state.kvstorage.get_ref_file("a", "dir/sub/file").await;
+        assert!(!hash.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn exports_nested_spans_of_traced_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&bucket(dir.path(), "a")).await;
+        testing::init_tracing();
+
+        let upload = |traceparent: Option<&str>, path: &str| {
+            let mut request = axum::extract::Request::put(format!(
+                "/ft/files/{}?last_modified=Mon,%2001%20Jan%202024%2000:00:00%20GMT",
+                path
+            ));
+            if let Some(traceparent) = traceparent {
+                request = request.header("traceparent", traceparent);
+            }
+            let request = request
+                .body(axum::body::Body::from(path.to_string()))
+                .unwrap();
+            tower::ServiceExt::oneshot(router(state.clone()), request)
+        };
+        assert_eq!(upload(None, "untraced").await.unwrap().status(), 200);
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let client_span_id = "00f067aa0ba902b7";
+        let traceparent = format!("00-{}-{}-01", trace_id, client_span_id);
+        let response = upload(Some(&traceparent), "traced").await.unwrap();
+        assert_eq!(response.status(), 200);
+        drop(response);
+        // The threads running SQLite queries may close their spans later
+        let traced = || {
+            testing::exported_spans()
+                .into_iter()
+                .filter(|span| span.span_context.trace_id().to_string() == trace_id)
+                .collect::<Vec<_>>()
+        };
+        for _ in 0..100 {
+            if traced().iter().any(|span| span.name == "request") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let untraced = testing::exported_spans().into_iter().any(|span| {
+            span.attributes
+                .iter()
+                .any(|attribute| attribute.value.as_str().contains("/files/untraced?"))
+        });
+        assert!(!untraced, "spans of an untraced request were exported");
+        let spans = traced();
+        let by_id: HashMap<_, _> = spans
+            .iter()
+            .map(|span| (span.span_context.span_id(), span))
+            .collect();
+        let request = spans.iter().find(|span| span.name == "request").unwrap();
+        assert_eq!(request.parent_span_id.to_string(), client_span_id);
+        assert!(request.parent_span_is_remote);
+        for name in ["kv", "lock", "blob"] {
+            let span = spans.iter().find(|span| span.name == name);
+            let mut span = span.unwrap_or_else(|| panic!("no {} span", name));
+            // Every span nests below the request span
+            while span.name != "request" {
+                span = by_id[&span.parent_span_id];
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn draining_bucket_is_not_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = bucket(dir.path(), "a");
+        let b = bucket(dir.path(), "b");
+        let mut server = Server::default();
+        server.start_bucket(&a).await.unwrap();
+        server.start_bucket(&b).await.unwrap();
+        let listener = server.listeners.values().next().unwrap();
+        listener.drain("a");
+
+        let readyz = async |name: &str| {
+            let router = listener.routes.read().unwrap()[name].router.clone();
+            let request = axum::extract::Request::get("/readyz")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            tower::ServiceExt::oneshot(router, request)
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(readyz("a").await, 503);
+        assert_eq!(readyz("b").await, 200);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stopping_aborts_requests_after_drain_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = bucket(dir.path(), "a");
+        a.drain_timeout_secs = 1;
+        // Memory locks vanish with the bucket, file locks must be released
+        a.locks_type = crate::locks::LocksType::File;
+        a.file_locks = Some(crate::locks::file::FileLocksConfig {
+            path: dir.path().join("locks").display().to_string(),
+        });
+        let mut server = Server::default();
+        server.start_bucket(&a).await.unwrap();
+        let state = server.buckets["a"].state.clone();
+        let key = crate::locks::file_lock("a", "stuck");
+        state.locks.acquire_exclusive(&key).await.unwrap();
+        let stuck = tokio::spawn({
+            let state = state.clone();
+            async move { state.in_flight.run(std::future::pending::<()>()).await }
+        });
+        // Let the request start before the bucket is stopped
+        tokio::task::yield_now().await;
+
+        server.stop_bucket("a").await;
+        assert_eq!(stuck.await.unwrap(), None);
+        assert!(
+            state.kvstorage.ping("a").await.is_err(),
+            "KV storage still open"
+        );
+        // Closing released the lock of the aborted request
+        assert!(!state.locks.release(&key));
+    }
+}
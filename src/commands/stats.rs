@@ -0,0 +1,62 @@
+use crate::commands::app_states;
+use crate::config::Config;
+use crate::kvstorage::stats::BucketStats;
+use std::error::Error;
+
+/**
+ * Print deduplication statistics of the buckets, listing the top most
+ * shared hashes of each.
+ */
+pub async fn stats(
+    config: &Config,
+    bucket: Option<&str>,
+    top: i64,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    for state in app_states(config, bucket).await? {
+        let mut stats = state.kvstorage.stats(&state.bucket_name, top).await?;
+        stats.quota = state.quota.clone();
+        if json {
+            println!("{}", serde_json::to_string(&stats)?);
+        } else {
+            print_stats(&stats);
+        }
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &BucketStats) {
+    println!("{}:", stats.bucket);
+    println!("  paths: {}", stats.paths);
+    println!("  unique blobs: {}", stats.unique_blobs);
+    println!("  logical bytes: {}", stats.logical_bytes);
+    println!("  physical bytes: {}", stats.physical_bytes);
+    if let Some(ratio) = stats.dedup_ratio {
+        println!("  dedup ratio: {:.2}", ratio);
+    }
+    if let Some(quota) = &stats.quota {
+        let limit = |limit: Option<i64>| limit.map_or("unlimited".to_string(), |l| l.to_string());
+        println!(
+            "  quota: {} logical bytes, {} physical bytes, {} paths",
+            limit(quota.max_logical_bytes),
+            limit(quota.max_physical_bytes),
+            limit(quota.max_paths)
+        );
+    }
+    println!("  refcount distribution:");
+    for range in &stats.refcount_distribution {
+        if range.min == range.max {
+            println!("    {}: {} blobs", range.min, range.blobs);
+        } else {
+            println!("    {}-{}: {} blobs", range.min, range.max, range.blobs);
+        }
+    }
+    println!("  most shared:");
+    for blob in &stats.most_shared {
+        let size = blob.size.map_or_else(
+            || "unknown size".to_string(),
+            |size| format!("{} bytes", size),
+        );
+        println!("    {}: {} refs, {}", blob.hash, blob.refcount, size);
+    }
+}
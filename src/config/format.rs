@@ -0,0 +1,166 @@
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+
+/// Fields whose values are replaced when dumping the configuration
+const SECRET_FIELDS: [&str; 3] = ["password", "secret", "token"];
+/// Placeholder of redacted values
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /**
+     * Pick the format from the file extension, defaulting to JSON.
+     */
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn Error>> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            None | Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml") | Some("yml") => Ok(ConfigFormat::Yaml),
+            Some(other) => Err(format!(
+                "{}: unsupported configuration format: {}, expected json, toml or yaml",
+                path, other
+            )
+            .into()),
+        }
+    }
+
+    pub fn parse(&self, s: &str) -> Result<Value, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(s)?,
+            ConfigFormat::Toml => toml::from_str(s)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(s)?,
+        })
+    }
+
+    pub fn render(&self, value: &Value) -> Result<String, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+            ConfigFormat::Toml => toml::to_string_pretty(value)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+        })
+    }
+}
+
+/**
+ * Replace the values of secret fields, so that the result can be shared.
+ */
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let secret = SECRET_FIELDS.iter().any(|field| key.contains(field));
+                if secret && !value.is_null() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/**
+ * Remove unset fields, as TOML cannot represent them.
+ */
+pub fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn picks_format_from_extension() {
+        assert_eq!(
+            ConfigFormat::from_path("config").unwrap(),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_path("a.JSON").unwrap(),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_path("a.toml").unwrap(),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path("a.yml").unwrap(),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path("a.yaml").unwrap(),
+            ConfigFormat::Yaml
+        );
+        let error = ConfigFormat::from_path("a.ini").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "a.ini: unsupported configuration format: ini, expected json, toml or yaml"
+        );
+    }
+
+    #[test]
+    fn parses_all_formats_alike() {
+        let json = r#"{"buckets": [{"name": "a", "port": 3000, "hosts": ["x"]}]}"#;
+        let toml =
+            "# Comments are allowed\n[[buckets]]\nname = \"a\"\nport = 3000\nhosts = [\"x\"]\n";
+        let yaml =
+            "# Comments are allowed\nbuckets:\n  - name: a\n    port: 3000\n    hosts: [x]\n";
+        let expected = ConfigFormat::Json.parse(json).unwrap();
+        assert_eq!(ConfigFormat::Toml.parse(toml).unwrap(), expected);
+        assert_eq!(ConfigFormat::Yaml.parse(yaml).unwrap(), expected);
+    }
+
+    #[test]
+    fn renders_what_it_parses() {
+        let value = json!({"buckets": [{"name": "a", "port": 3000}]});
+        for format in [ConfigFormat::Json, ConfigFormat::Toml, ConfigFormat::Yaml] {
+            let rendered = format.render(&value).unwrap();
+            assert_eq!(format.parse(&rendered).unwrap(), value, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn redacts_secrets_and_strips_nulls() {
+        let mut value = json!({
+            "postgres": {"user": "app", "password": "secret"},
+            "credentials": [
+                {"name": "a", "token": "t", "hmac_secret": null},
+                {"name": "b", "token": null, "hmac_secret": "s"}
+            ]
+        });
+        redact(&mut value);
+        strip_nulls(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "postgres": {"user": "app", "password": "<redacted>"},
+                "credentials": [
+                    {"name": "a", "token": "<redacted>"},
+                    {"name": "b", "hmac_secret": "<redacted>"}
+                ]
+            })
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use crate::auth::AuthConfig;
+use crate::auth::acl::AclConfig;
+use crate::blobstorage::BlobStorageType;
+use crate::blobstorage::filesystem::FilesystemConfig;
+use crate::kvstorage::KVStorageType;
+use crate::kvstorage::postgres::PostgresConfig;
+use crate::kvstorage::sqlite::SQLiteConfig;
+use crate::listen::{ListenAddr, UnixSocketConfig};
+use crate::locks::LocksType;
+use crate::locks::file::FileLocksConfig;
+use crate::locks::lease::LeaseLocksConfig;
+use crate::logging::LoggingConfig;
+use crate::quota::QuotaConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::tls::TlsConfig;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+mod format;
+mod overrides;
+
+pub use format::ConfigFormat;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+pub struct Config {
+    pub logging: LoggingConfig,
+    pub buckets: Vec<BucketConfig>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+pub struct BucketConfig {
+    pub name: String,
+    /// IP address, or `unix:` followed by a socket path
+    pub address: String,
+    /// Not used for Unix sockets
+    #[serde(default)]
+    pub port: u16,
+
+    /// Permissions and ownership of the socket file
+    #[serde(default)]
+    pub unix_socket: Option<UnixSocketConfig>,
+
+    /// Host header values routed to this bucket. Buckets sharing a listener
+    /// are also reachable under `/b/{name}`. Other hosts are routed to the
+    /// one bucket of the listener without hosts, if there is exactly one.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Serve HTTPS instead of HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    /// Require credentials for requests to the bucket
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+
+    /// Per path prefix permissions of the credentials
+    #[serde(default)]
+    pub acl: Option<AclConfig>,
+
+    /// Limits of what the bucket may store
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+
+    /// Limits of requests per client and of the bucket as a whole
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+
+    pub kvstorage_type: KVStorageType,
+
+    #[serde(default)]
+    pub postgres: Option<PostgresConfig>,
+
+    #[serde(default)]
+    pub sqlite: Option<SQLiteConfig>,
+
+    /// Configurations written before blobs were stored default to filesystem
+    #[serde(default)]
+    pub blobstorage_type: BlobStorageType,
+
+    /// Blobs are stored in `blobs` if not set
+    #[serde(default)]
+    pub filesystem: Option<FilesystemConfig>,
+
+    pub locks_type: LocksType,
+
+    #[serde(default)]
+    pub file_locks: Option<FileLocksConfig>,
+
+    /// Leases are exclusive: shared locks, e.g. of fsck checking a blob,
+    /// wait for each other like exclusive ones
+    #[serde(default)]
+    pub lease_locks: Option<LeaseLocksConfig>,
+
+    /// Locks held longer than this are logged as warnings
+    #[serde(default = "default_locks_warn_after_secs")]
+    pub locks_warn_after_secs: u64,
+
+    /// Requests waiting longer than this for a lock fail
+    #[serde(default = "default_locks_timeout_secs")]
+    pub locks_timeout_secs: u64,
+
+    /// How long in-flight requests may take to finish when the server stops
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+
+    /// Uploads larger than this are rejected
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+}
+
+fn default_locks_warn_after_secs() -> u64 {
+    30
+}
+
+fn default_locks_timeout_secs() -> u64 {
+    60
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_upload_bytes() -> u64 {
+    1 << 30
+}
+
+/**
+ * Every problem found while validating the configuration.
+ */
+#[derive(Debug)]
+pub struct ConfigErrors(Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+impl Config {
+    /**
+     * Read the configuration file, then apply environment variable
+     * overrides and read secrets from files.
+     * The file is parsed as JSON, TOML or YAML depending on its extension.
+     */
+    pub fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        let config_str = std::fs::read_to_string(path)?;
+        let mut value = ConfigFormat::from_path(path)?.parse(&config_str)?;
+        overrides::apply_env_overrides(&mut value, std::env::vars())?;
+        overrides::resolve_secret_files(&mut value)?;
+        let config: Config = overrides::deserialize(value)?;
+        Ok(config)
+    }
+
+    /**
+     * Render the effective configuration, with defaults filled in
+     * and secrets redacted.
+     */
+    pub fn dump(&self, format: ConfigFormat) -> Result<String, Box<dyn Error>> {
+        let mut value = serde_json::to_value(self)?;
+        format::redact(&mut value);
+        format::strip_nulls(&mut value);
+        format.render(&value)
+    }
+
+    /**
+     * Check the whole configuration, reporting all problems at once.
+     */
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut problems = vec![];
+        self.logging.validate(&mut problems);
+        if self.buckets.is_empty() {
+            problems.push("buckets: at least one bucket is required".to_string());
+        }
+
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut listeners: Vec<(usize, SocketAddr)> = vec![];
+        let mut hosts: HashMap<(ListenAddr, String), usize> = HashMap::new();
+        let mut shared: HashMap<ListenAddr, usize> = HashMap::new();
+        let mut blob_dirs: HashMap<String, usize> = HashMap::new();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let at = format!("buckets[{}] ({})", i, bucket.name);
+            bucket.validate(&at, &mut problems);
+
+            if let Some(other) = names.get(bucket.name.as_str()) {
+                problems.push(format!(
+                    "{}.name: duplicates the name of buckets[{}]",
+                    at, other
+                ));
+            } else {
+                names.insert(&bucket.name, i);
+            }
+
+            if let Ok(address) = bucket.listen_addr() {
+                // Buckets with the same address share a listener
+                if let ListenAddr::Tcp(addr) = address {
+                    let conflict = listeners.iter().find(|(_, other)| {
+                        other.port() == addr.port()
+                            && other.ip() != addr.ip()
+                            && (other.ip().is_unspecified() || addr.ip().is_unspecified())
+                    });
+                    if let Some((other, _)) = conflict {
+                        problems.push(format!(
+                            "{}.port: {} conflicts with the listener of buckets[{}]",
+                            at, addr, other
+                        ));
+                    }
+                    listeners.push((i, addr));
+                }
+
+                // TLS is terminated by the listener, before a bucket is chosen
+                match shared.get(&address) {
+                    Some(other) if self.buckets[*other].tls != bucket.tls => {
+                        problems.push(format!(
+                            "{}.tls: differs from the TLS settings of buckets[{}], which shares its listener",
+                            at, other
+                        ))
+                    }
+                    Some(other) if self.buckets[*other].unix_socket != bucket.unix_socket => {
+                        problems.push(format!(
+                            "{}.unix_socket: differs from the socket settings of buckets[{}], which shares its listener",
+                            at, other
+                        ))
+                    }
+                    Some(_) => {}
+                    None => {
+                        shared.insert(address.clone(), i);
+                    }
+                }
+
+                for host in &bucket.hosts {
+                    let key = (address.clone(), host.to_lowercase());
+                    match hosts.get(&key) {
+                        Some(other) => problems.push(format!(
+                            "{}.hosts: {} is already routed to buckets[{}]",
+                            at, host, other
+                        )),
+                        None => {
+                            hosts.insert(key, i);
+                        }
+                    }
+                }
+            }
+
+            // Blobs are deleted once unreferenced in their bucket,
+            // so a blob directory cannot be shared
+            if matches!(bucket.blobstorage_type, BlobStorageType::Filesystem) {
+                let path = bucket.filesystem.clone().unwrap_or_default().path;
+                match blob_dirs.get(&path) {
+                    Some(other) => problems.push(format!(
+                        "{}.filesystem.path: {} is already used by buckets[{}]",
+                        at, path, other
+                    )),
+                    None => {
+                        blob_dirs.insert(path, i);
+                    }
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(problems))
+        }
+    }
+}
+
+impl BucketConfig {
+    pub fn listen_addr(&self) -> Result<ListenAddr, String> {
+        ListenAddr::parse(&self.address, self.port)
+    }
+
+    fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        let mut problem = |field: &str, message: &str| {
+            problems.push(format!("{}.{}: {}", at, field, message));
+        };
+
+        if self.name.is_empty() {
+            problem("name", "must not be empty");
+        }
+        // The name is used as path segment
+        if self.name.contains('/') {
+            problem("name", "must not contain '/'");
+        }
+        if self.hosts.iter().any(|host| host.is_empty()) {
+            problem("hosts", "must not contain empty host names");
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_empty() {
+                problem("tls.cert_path", "must not be empty");
+            }
+            if tls.key_path.is_empty() {
+                problem("tls.key_path", "must not be empty");
+            }
+            if tls
+                .client_ca_path
+                .as_ref()
+                .is_some_and(|path| path.is_empty())
+            {
+                problem("tls.client_ca_path", "must not be empty");
+            }
+        }
+        match self.listen_addr() {
+            Err(e) => problem("address", &e),
+            Ok(ListenAddr::Tcp(_)) => {
+                if self.port == 0 {
+                    problem("port", "is required for IP addresses");
+                }
+                if self.unix_socket.is_some() {
+                    problem("unix_socket", "only applies to unix:<path> addresses");
+                }
+            }
+            Ok(ListenAddr::Unix(_)) => {
+                if let Some(Err(e)) = self.unix_socket.as_ref().map(|u| u.parse_mode()) {
+                    problem("unix_socket.mode", &e);
+                }
+            }
+        }
+
+        match self.kvstorage_type {
+            KVStorageType::Postgres => match &self.postgres {
+                None => problem("postgres", "is required when kvstorage_type is postgres"),
+                Some(postgres) if postgres.pool_size == 0 => {
+                    problem("postgres.pool_size", "must be positive")
+                }
+                Some(_) => {}
+            },
+            KVStorageType::SQLite => match &self.sqlite {
+                None => problem("sqlite", "is required when kvstorage_type is sqlite"),
+                Some(sqlite) if sqlite.pool_size == 0 => {
+                    problem("sqlite.pool_size", "must be positive")
+                }
+                Some(sqlite) if sqlite.path.is_empty() => {
+                    problem("sqlite.path", "must not be empty")
+                }
+                Some(_) => {}
+            },
+        }
+
+        match self.blobstorage_type {
+            BlobStorageType::Filesystem => {
+                if self
+                    .filesystem
+                    .as_ref()
+                    .is_some_and(|filesystem| filesystem.path.is_empty())
+                {
+                    problem("filesystem.path", "must not be empty")
+                }
+            }
+        }
+
+        match self.locks_type {
+            LocksType::Memory => {}
+            LocksType::File => match &self.file_locks {
+                None => problem("file_locks", "is required when locks_type is file"),
+                Some(file_locks) if file_locks.path.is_empty() => {
+                    problem("file_locks.path", "must not be empty")
+                }
+                Some(_) => {}
+            },
+            LocksType::Lease => {
+                if let Some(lease_locks) = &self.lease_locks {
+                    if lease_locks.ttl_secs < 3 {
+                        problem("lease_locks.ttl_secs", "must be at least 3");
+                    }
+                    if lease_locks.retry_interval_ms == 0 {
+                        problem("lease_locks.retry_interval_ms", "must be positive");
+                    }
+                }
+            }
+        }
+
+        if self.locks_timeout_secs == 0 {
+            problem("locks_timeout_secs", "must be positive");
+        }
+        if self.max_upload_bytes == 0 {
+            problem("max_upload_bytes", "must be positive");
+        }
+
+        if let Some(quota) = &self.quota {
+            quota.validate(at, problems);
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate(at, problems);
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate(at, problems);
+        }
+        match (&self.acl, &self.auth) {
+            (Some(_), None) => problems.push(format!("{}.acl: requires auth", at)),
+            (Some(acl), Some(auth)) => {
+                let names: Vec<&str> = auth.credentials.iter().map(|c| c.name.as_str()).collect();
+                acl.validate(at, &names, problems);
+            }
+            (None, _) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobstorage::filesystem::FilesystemConfig;
+
+    /// Configuration of the first release, before blobs were stored
+    const OLD_CONFIG: &str = r#"{
+        "logging": {"level": "info", "json": false},
+        "buckets": [{
+            "name": "bucket1",
+            "address": "0.0.0.0",
+            "port": 3000,
+            "kvstorage_type": "sqlite",
+            "sqlite": {"path": "kv.db", "pool_size": 10},
+            "locks_type": "memory"
+        }]
+    }"#;
+
+    #[test]
+    fn loads_configs_without_blob_storage() {
+        let config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        config.validate().unwrap();
+        let bucket = &config.buckets[0];
+        assert_eq!(bucket.blobstorage_type, BlobStorageType::Filesystem);
+        assert_eq!(
+            bucket.filesystem.clone().unwrap_or_default(),
+            FilesystemConfig {
+                path: "blobs".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_buckets_sharing_default_blob_directory() {
+        let mut config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        let mut other = config.buckets[0].clone();
+        other.name = "bucket2".to_string();
+        other.port = 3001;
+        config.buckets.push(other);
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(
+            problems,
+            ["buckets[1] (bucket2).filesystem.path: blobs is already used by buckets[0]"]
+        );
+    }
+
+    #[test]
+    fn rejects_lease_ttls_too_short_to_renew() {
+        let mut config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        let bucket = &mut config.buckets[0];
+        bucket.locks_type = LocksType::Lease;
+        bucket.lease_locks = Some(LeaseLocksConfig {
+            ttl_secs: 2,
+            ..LeaseLocksConfig::default()
+        });
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(
+            problems,
+            ["buckets[0] (bucket1).lease_locks.ttl_secs: must be at least 3"]
+        );
+
+        config.buckets[0].lease_locks.as_mut().unwrap().ttl_secs = 3;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_bucket_and_field() {
+        let mut config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        let mut other = config.buckets[0].clone();
+        other.address = "127.0.0.1".to_string();
+        other.kvstorage_type = KVStorageType::Postgres;
+        other.filesystem = Some(FilesystemConfig {
+            path: "other".to_string(),
+        });
+        config.buckets.push(other);
+
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(
+            problems,
+            [
+                "buckets[1] (bucket1).postgres: is required when kvstorage_type is postgres",
+                "buckets[1] (bucket1).name: duplicates the name of buckets[0]",
+                "buckets[1] (bucket1).port: 127.0.0.1:3000 conflicts with the listener of buckets[0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses_and_limits() {
+        let mut config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        let bucket = &mut config.buckets[0];
+        bucket.address = "not an address".to_string();
+        bucket.locks_timeout_secs = 0;
+        bucket.sqlite.as_mut().unwrap().pool_size = 0;
+
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("buckets[0] (bucket1).address: "));
+        assert_eq!(
+            problems[1..],
+            [
+                "buckets[0] (bucket1).sqlite.pool_size: must be positive",
+                "buckets[0] (bucket1).locks_timeout_secs: must be positive",
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_a_bucket() {
+        let mut config: Config = serde_json::from_str(OLD_CONFIG).unwrap();
+        config.buckets.clear();
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(problems, ["buckets: at least one bucket is required"]);
+    }
+}
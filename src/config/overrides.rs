@@ -0,0 +1,362 @@
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+use serde_json::{Map, Value};
+use std::error::Error;
+
+/// Prefix of environment variables overriding configuration fields
+const ENV_PREFIX: &str = "S3DEDUP_";
+/// Separator of path segments in environment variable names
+const ENV_SEPARATOR: &str = "__";
+/// Suffix of fields whose value is read from the file they point to
+const FILE_SUFFIX: &str = "_file";
+
+/**
+ * Override configuration fields with environment variables.
+ *
+ * `S3DEDUP_BUCKETS__0__POSTGRES__PASSWORD` sets `password` in the `postgres`
+ * section of the first bucket. Missing sections are created. Values are set
+ * as strings and parsed by [`deserialize`] according to the type of the field.
+ */
+pub fn apply_env_overrides(
+    config: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    // Apply in a stable order, so that sections are created before their fields
+    vars.sort();
+
+    for (name, value) in vars {
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_SEPARATOR)
+            .map(|segment| segment.to_lowercase())
+            .collect();
+        if path.iter().any(|segment| segment.is_empty()) {
+            return Err(format!("{}: invalid configuration path", name).into());
+        }
+        set_path(config, &path, &value).map_err(|e| format!("{}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+fn set_path(node: &mut Value, path: &[String], value: &str) -> Result<(), String> {
+    let (segment, rest) = path.split_first().unwrap();
+    let child = match node {
+        Value::Object(map) => {
+            if rest.is_empty() {
+                map.insert(segment.clone(), Value::String(value.to_string()));
+                return Ok(());
+            }
+            map.entry(segment.clone())
+                .or_insert_with(|| Value::Object(Map::new()))
+        }
+        Value::Array(items) => {
+            let index: usize = segment
+                .parse()
+                .map_err(|_| format!("{} is not an array index", segment))?;
+            let len = items.len();
+            let item = items.get_mut(index).ok_or_else(|| {
+                format!("index {} is out of range, there are {} items", index, len)
+            })?;
+            if rest.is_empty() {
+                *item = Value::String(value.to_string());
+                return Ok(());
+            }
+            item
+        }
+        _ => return Err(format!("{} is not a section", segment)),
+    };
+    set_path(child, rest, value)
+}
+
+/**
+ * Deserialize the configuration, parsing strings as JSON where the field
+ * is not a string, e.g. numbers set by environment variables. A password
+ * of `123456` stays a string, while a port of `5432` becomes a number.
+ */
+pub fn deserialize<T: DeserializeOwned>(value: Value) -> Result<T, serde_json::Error> {
+    T::deserialize(Typed(value))
+}
+
+/// Value deserialized according to the type of the field it is read into
+struct Typed(Value);
+
+impl Typed {
+    /// Parse a string holding JSON of another type, e.g. `5432` or `[1, 2]`
+    fn parsed(self) -> Self {
+        match self.0 {
+            Value::String(s) => match serde_json::from_str(&s) {
+                Ok(Value::String(_)) | Err(_) => Typed(Value::String(s)),
+                Ok(value) => Typed(value),
+            },
+            value => Typed(value),
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, serde_json::Error> for Typed {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.parsed().deserialize_any(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Typed {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Number(n) => n.deserialize_any(visitor),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Array(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter().map(Typed));
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Object(map) => {
+                let mut map = MapDeserializer::new(map.into_iter().map(|(k, v)| (k, Typed(v))));
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_unit deserialize_seq deserialize_map
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        char str string bytes byte_buf identifier ignored_any
+    }
+}
+
+/**
+ * Replace every `<field>_file` entry with `<field>` set to the contents
+ * of the file it points to, without the trailing newline. This keeps
+ * secrets, e.g. `postgres.password_file`, out of the configuration.
+ */
+pub fn resolve_secret_files(node: &mut Value) -> Result<(), Box<dyn Error>> {
+    match node {
+        Value::Object(map) => {
+            let file_keys: Vec<String> = map
+                .keys()
+                .filter(|key| key.ends_with(FILE_SUFFIX) && key.len() > FILE_SUFFIX.len())
+                .cloned()
+                .collect();
+            for file_key in file_keys {
+                let Some(Value::String(path)) = map.remove(&file_key) else {
+                    return Err(format!("{}: must be a file path", file_key).into());
+                };
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: failed to read {}: {}", file_key, path, e))?;
+                let key = file_key[..file_key.len() - FILE_SUFFIX.len()].to_string();
+                let secret = contents.trim_end_matches(['\r', '\n']).to_string();
+                map.insert(key, Value::String(secret));
+            }
+            for child in map.values_mut() {
+                resolve_secret_files(child)?;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                resolve_secret_files(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstorage::postgres::PostgresConfig;
+    use serde_json::json;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn overrides_fields_by_path() {
+        let mut config = json!({
+            "logging": {"level": "info"},
+            "buckets": [{"name": "a", "port": 3000}]
+        });
+        let env = vars(&[
+            ("S3DEDUP_LOGGING__LEVEL", "debug"),
+            ("S3DEDUP_BUCKETS__0__PORT", "4000"),
+            ("S3DEDUP_BUCKETS__0__NAME", "123"),
+            ("S3DEDUP_BUCKETS__0__POSTGRES__PASSWORD", "secret"),
+            ("OTHER_VARIABLE", "ignored"),
+        ]);
+        apply_env_overrides(&mut config, env).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "logging": {"level": "debug"},
+                "buckets": [{
+                    "name": "123",
+                    "port": "4000",
+                    "postgres": {"password": "secret"}
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn parses_overrides_by_field_type() {
+        for password in ["123456", "true", "1e3", "null"] {
+            let mut config = json!({
+                "postgres": {"host": "db", "user": "s3dedup", "dbname": "s3dedup"}
+            });
+            let env = [
+                ("S3DEDUP_POSTGRES__PASSWORD", password),
+                ("S3DEDUP_POSTGRES__PORT", "5432"),
+                ("S3DEDUP_POSTGRES__POOL_SIZE", "10"),
+            ];
+            apply_env_overrides(&mut config, vars(&env)).unwrap();
+            let postgres: PostgresConfig = deserialize(config["postgres"].take()).unwrap();
+            assert_eq!(
+                postgres,
+                PostgresConfig {
+                    host: "db".to_string(),
+                    port: 5432,
+                    user: "s3dedup".to_string(),
+                    password: password.to_string(),
+                    dbname: "s3dedup".to_string(),
+                    pool_size: 10,
+                }
+            );
+        }
+
+        let error = deserialize::<PostgresConfig>(json!({
+            "host": "db", "port": "not a port", "user": "u",
+            "password": "p", "dbname": "d", "pool_size": 1
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("expected u16"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        let mut config = json!({"buckets": [{"name": "a"}]});
+        let error = apply_env_overrides(&mut config, vars(&[("S3DEDUP_BUCKETS__1__NAME", "b")]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "S3DEDUP_BUCKETS__1__NAME: index 1 is out of range, there are 1 items"
+        );
+        let error = apply_env_overrides(&mut config, vars(&[("S3DEDUP_BUCKETS____NAME", "b")]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "S3DEDUP_BUCKETS____NAME: invalid configuration path"
+        );
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("password");
+        std::fs::write(&secret, "secret\n").unwrap();
+        let mut config = json!({
+            "buckets": [{"postgres": {"password_file": secret.to_str().unwrap()}}]
+        });
+        resolve_secret_files(&mut config).unwrap();
+        assert_eq!(
+            config,
+            json!({"buckets": [{"postgres": {"password": "secret"}}]})
+        );
+    }
+
+    #[test]
+    fn names_unreadable_secret_files() {
+        let mut config = json!({"postgres": {"password_file": "/nonexistent/password"}});
+        let error = resolve_secret_files(&mut config).unwrap_err().to_string();
+        assert!(
+            error.starts_with("password_file: failed to read /nonexistent/password: "),
+            "{}",
+            error
+        );
+    }
+}
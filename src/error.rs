@@ -0,0 +1,221 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::response::{IntoResponse, Response};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/**
+ * Error returned by storages, locks and route handlers.
+ * Each variant maps to a stable HTTP status code and error code.
+ */
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Misdirected(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Timed out waiting for lock on key: {0}")]
+    LockTimeout(String),
+    #[error("Lost lock on key: {0}")]
+    LockLost(String),
+    #[error("Storage unavailable: {0}")]
+    StorageUnavailable(String),
+    #[error("Shutting down: {0}")]
+    ShuttingDown(String),
+    #[error("{0}")]
+    Integrity(String),
+    #[error("{0}")]
+    QuotaExceeded(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    /// Message and how long until the request would be admitted
+    #[error("{0}")]
+    RateLimited(String, Duration),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ErrorResponse {
+    error: &'static str,
+    message: String,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Misdirected(_) => StatusCode::MISDIRECTED_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::LockTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::LockLost(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::ShuttingDown(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Integrity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /**
+     * Machine-readable error code, returned in the response body.
+     */
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Misdirected(_) => "misdirected",
+            AppError::Conflict(_) => "conflict",
+            AppError::LockTimeout(_) => "lock_timeout",
+            AppError::LockLost(_) => "lock_lost",
+            AppError::StorageUnavailable(_) => "storage_unavailable",
+            AppError::ShuttingDown(_) => "shutting_down",
+            AppError::Integrity(_) => "integrity",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::RateLimited(..) => "rate_limited",
+            AppError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("{}", self);
+        } else {
+            warn!("{}", self);
+        }
+
+        let body = Json(ErrorResponse {
+            error: self.code(),
+            message: self.to_string(),
+        });
+        match &self {
+            // The lock is likely free again soon
+            AppError::LockTimeout(_) | AppError::LockLost(_) => {
+                (status, [(RETRY_AFTER, "1")], body).into_response()
+            }
+            AppError::RateLimited(_, wait) => {
+                // Whole seconds, rounded up so that retrying right away is not admitted
+                let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+                (status, [(RETRY_AFTER, secs.max(1).to_string())], body).into_response()
+            }
+            AppError::Unauthorized(_) => {
+                (status, [(WWW_AUTHENTICATE, "Bearer")], body).into_response()
+            }
+            _ => (status, body).into_response(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => {
+                AppError::Conflict(e.to_string())
+            }
+            sqlx::Error::ColumnDecode { .. }
+            | sqlx::Error::ColumnNotFound(_)
+            | sqlx::Error::ColumnIndexOutOfBounds { .. }
+            | sqlx::Error::TypeNotFound { .. }
+            | sqlx::Error::Decode(_) => AppError::Internal(e.to_string()),
+            _ => AppError::StorageUnavailable(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::StorageUnavailable(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for AppError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use serde_json::{Value, json};
+
+    async fn respond(error: AppError) -> (StatusCode, axum::http::HeaderMap, Value) {
+        let response = error.into_response();
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn maps_errors_to_status_and_code() {
+        let cases = [
+            (AppError::Validation("x".into()), 400, "validation"),
+            (AppError::NotFound("x".into()), 404, "not_found"),
+            (AppError::Misdirected("x".into()), 421, "misdirected"),
+            (AppError::Conflict("x".into()), 409, "conflict"),
+            (AppError::LockTimeout("k".into()), 503, "lock_timeout"),
+            (AppError::LockLost("k".into()), 503, "lock_lost"),
+            (
+                AppError::StorageUnavailable("x".into()),
+                503,
+                "storage_unavailable",
+            ),
+            (AppError::Integrity("x".into()), 422, "integrity"),
+            (AppError::QuotaExceeded("x".into()), 507, "quota_exceeded"),
+            (AppError::Internal("x".into()), 500, "internal"),
+        ];
+        for (error, status, code) in cases {
+            let message = error.to_string();
+            let (actual, _, body) = respond(error).await;
+            assert_eq!(actual.as_u16(), status, "{}", code);
+            assert_eq!(body, json!({"error": code, "message": message}));
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_clients_when_to_retry() {
+        let (_, headers, _) = respond(AppError::LockTimeout("k".into())).await;
+        assert_eq!(headers[RETRY_AFTER], "1");
+
+        let wait = Duration::from_millis(1500);
+        let (status, headers, _) = respond(AppError::RateLimited("x".into(), wait)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[RETRY_AFTER], "2");
+
+        let (_, headers, _) = respond(AppError::Unauthorized("x".into())).await;
+        assert_eq!(headers[WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[test]
+    fn maps_storage_errors() {
+        let error = AppError::from(sqlx::Error::PoolTimedOut);
+        assert!(matches!(error, AppError::StorageUnavailable(_)));
+        let error = AppError::from(sqlx::Error::ColumnNotFound("size".into()));
+        assert!(matches!(error, AppError::Internal(_)));
+    }
+}
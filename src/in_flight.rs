@@ -0,0 +1,130 @@
+use crate::error::AppError;
+use crate::{AppState, request_id};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/**
+ * Requests of a bucket that are still running, so that stopping the bucket
+ * can wait for them and abort those that take too long.
+ */
+#[derive(Clone, Default)]
+pub struct InFlight {
+    tasks: TaskTracker,
+    abort: CancellationToken,
+}
+
+impl InFlight {
+    /**
+     * Run future as part of a request. Returns None if it was aborted.
+     */
+    pub async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        let abort = self.abort.clone();
+        self.tasks
+            .track_future(async move {
+                tokio::select! {
+                    biased;
+                    _ = abort.cancelled() => None,
+                    output = future => Some(output),
+                }
+            })
+            .await
+    }
+
+    /**
+     * Run future in its own task, so that a client disconnecting cannot
+     * drop it halfway, while still aborting it with the other requests.
+     */
+    pub async fn spawn<T, F>(&self, future: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<T, AppError>> + Send + 'static,
+    {
+        let in_flight = self.clone();
+        request_id::spawn(async move { in_flight.run(future).await })
+            .await?
+            .unwrap_or_else(|| Err(aborted()))
+    }
+
+    /**
+     * Wait for running requests to finish, aborting them after timeout.
+     * Returns whether they finished in time.
+     */
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        self.abort.cancel();
+        self.tasks.wait().await;
+        false
+    }
+}
+
+fn aborted() -> AppError {
+    AppError::ShuttingDown("request aborted after the drain timeout".to_string())
+}
+
+/**
+ * Middleware tracking every request to a bucket in its `InFlight`.
+ */
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match state.in_flight.run(next.run(request)).await {
+        Some(response) => response,
+        None => aborted().into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_running_requests() {
+        let in_flight = InFlight::default();
+        let request = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight
+                    .run(tokio::time::sleep(Duration::from_millis(100)))
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(in_flight.drain(Duration::from_secs(5)).await);
+        assert_eq!(request.await.unwrap(), Some(()));
+    }
+
+    #[tokio::test]
+    async fn drain_aborts_requests_after_timeout() {
+        let in_flight = InFlight::default();
+        let request = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move { in_flight.run(std::future::pending::<()>()).await }
+        });
+        let spawned = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight
+                    .spawn(std::future::pending::<Result<(), AppError>>())
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!in_flight.drain(Duration::from_millis(50)).await);
+        assert_eq!(request.await.unwrap(), None);
+        let result = spawned.await.unwrap();
+        assert!(matches!(result, Err(AppError::ShuttingDown(_))));
+    }
+}
@@ -1,13 +1,19 @@
 use crate::config::BucketConfig;
-use serde::Deserialize;
-use std::error::Error;
-use tracing::{debug, info};
+use crate::error::AppError;
+use crate::locks::Fence;
+use crate::metrics;
+use crate::quota::QuotaConfig;
+use serde::{Deserialize, Serialize};
+use stats::{BlobTotals, BucketStats, SharedBlob, Usage};
+use std::time::Instant;
+use tracing::{Instrument, debug, info, info_span};
 
 mod pooled;
 pub mod postgres;
 pub mod sqlite;
+pub mod stats;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum KVStorageType {
     #[serde(rename = "postgres")]
     Postgres,
@@ -16,56 +22,120 @@ pub enum KVStorageType {
 }
 
 pub(crate) trait KVStorageTrait {
-    async fn new(config: &BucketConfig) -> Result<Box<Self>, Box<dyn Error>>
+    async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError>
     where
         Self: Sized;
 
-    async fn setup(&mut self) -> Result<(), Box<dyn Error>>;
-    async fn get_ref_count(&mut self, bucket: &str, hash: &str) -> Result<i32, Box<dyn Error>>;
-    async fn set_ref_count(
-        &mut self,
+    async fn setup(&self) -> Result<(), AppError>;
+    async fn ping(&self) -> Result<(), AppError>;
+    async fn get_ref_count(&self, bucket: &str, hash: &str) -> Result<i32, AppError>;
+    async fn set_ref_count(&self, bucket: &str, hash: &str, ref_cnt: i32) -> Result<(), AppError>;
+    /**
+     * Set the reference count only if the lease of fence is still held with
+     * its token. Return whether it was set.
+     */
+    async fn set_ref_count_fenced(
+        &self,
+        bucket: &str,
+        hash: &str,
+        ref_cnt: i32,
+        fence: &Fence,
+    ) -> Result<bool, AppError>;
+
+    async fn write_ref_count(
+        &self,
         bucket: &str,
         hash: &str,
         ref_cnt: i32,
-    ) -> Result<(), Box<dyn Error>>;
+        fence: Option<&Fence>,
+    ) -> Result<(), AppError> {
+        let Some(fence) = fence else {
+            return self.set_ref_count(bucket, hash, ref_cnt).await;
+        };
+        if !self
+            .set_ref_count_fenced(bucket, hash, ref_cnt, fence)
+            .await?
+        {
+            return Err(AppError::LockLost(fence.key.clone()));
+        }
+        Ok(())
+    }
+
     async fn increment_ref_count(
-        &mut self,
+        &self,
         bucket: &str,
         hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        fence: Option<&Fence>,
+    ) -> Result<(), AppError> {
         let cnt = self.get_ref_count(bucket, hash).await?;
-        self.set_ref_count(bucket, hash, cnt + 1).await
+        self.write_ref_count(bucket, hash, cnt + 1, fence).await
     }
 
     async fn decrement_ref_count(
-        &mut self,
+        &self,
         bucket: &str,
         hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        fence: Option<&Fence>,
+    ) -> Result<(), AppError> {
         let cnt = self.get_ref_count(bucket, hash).await?;
         if cnt == 0 {
             return Ok(());
         }
-        self.set_ref_count(bucket, hash, cnt - 1).await
+        self.write_ref_count(bucket, hash, cnt - 1, fence).await
     }
 
-    async fn get_modified(&mut self, bucket: &str, path: &str) -> Result<i64, Box<dyn Error>>;
-    async fn set_modified(
-        &mut self,
-        bucket: &str,
-        path: &str,
-        modified: i64,
-    ) -> Result<(), Box<dyn Error>>;
-    async fn delete_modified(&mut self, bucket: &str, path: &str) -> Result<(), Box<dyn Error>>;
+    async fn get_modified(&self, bucket: &str, path: &str) -> Result<i64, AppError>;
+    async fn set_modified(&self, bucket: &str, path: &str, modified: i64) -> Result<(), AppError>;
 
-    async fn get_ref_file(&mut self, bucket: &str, path: &str) -> Result<String, Box<dyn Error>>;
-    async fn set_ref_file(
-        &mut self,
+    async fn get_ref_file(&self, bucket: &str, path: &str) -> Result<String, AppError>;
+    async fn set_ref_file(&self, bucket: &str, path: &str, hash: &str) -> Result<(), AppError>;
+
+    async fn list_ref_counts(&self, bucket: &str) -> Result<Vec<(String, i32)>, AppError>;
+    async fn delete_ref_count(&self, bucket: &str, hash: &str) -> Result<(), AppError>;
+    async fn count_refs_by_hash(&self, bucket: &str) -> Result<Vec<(String, i64)>, AppError>;
+    async fn count_paths(&self, bucket: &str) -> Result<i64, AppError>;
+    async fn get_blob_size(&self, bucket: &str, hash: &str) -> Result<Option<i64>, AppError>;
+    async fn set_blob_size(&self, bucket: &str, hash: &str, size: i64) -> Result<bool, AppError>;
+    async fn sum_blobs(&self, bucket: &str) -> Result<BlobTotals, AppError>;
+    async fn count_hashes_by_refcount(&self, bucket: &str) -> Result<Vec<(i32, i64)>, AppError>;
+    async fn most_shared(&self, bucket: &str, limit: i64) -> Result<Vec<SharedBlob>, AppError>;
+
+    async fn get_usage(&self, bucket: &str) -> Result<Option<Usage>, AppError>;
+    async fn set_usage(&self, bucket: &str, usage: &Usage) -> Result<(), AppError>;
+    /**
+     * Start tracking the usage of a bucket at usage, unless it is tracked
+     * already. Return whether tracking started.
+     */
+    async fn insert_usage(&self, bucket: &str, usage: &Usage) -> Result<bool, AppError>;
+    /**
+     * Add delta to the tracked usage of a bucket, returning the new usage,
+     * or None if the usage of the bucket is not tracked.
+     */
+    async fn add_usage(&self, bucket: &str, delta: &Usage) -> Result<Option<Usage>, AppError>;
+    /**
+     * Like add_usage, but add nothing and return None if a growing count
+     * would exceed its limit.
+     */
+    async fn add_usage_within(
+        &self,
         bucket: &str,
-        path: &str,
-        hash: &str,
-    ) -> Result<(), Box<dyn Error>>;
-    async fn delete_ref_file(&mut self, bucket: &str, path: &str) -> Result<(), Box<dyn Error>>;
+        delta: &Usage,
+        limits: &QuotaConfig,
+    ) -> Result<Option<Usage>, AppError>;
+
+    async fn try_acquire_lease(
+        &self,
+        key: &str,
+        owner: &str,
+        token: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> Result<bool, AppError>;
+    async fn renew_lease(&self, key: &str, token: &str, expires_at: i64) -> Result<bool, AppError>;
+    async fn release_lease(&self, key: &str, token: &str) -> Result<(), AppError>;
+    async fn release_leases(&self, owner: &str) -> Result<(), AppError>;
+
+    async fn close(&self);
 }
 
 #[derive(Clone)]
@@ -75,7 +145,7 @@ pub enum KVStorage {
 }
 
 impl KVStorage {
-    pub async fn new(config: &BucketConfig) -> Result<Box<Self>, Box<dyn Error>> {
+    pub async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError> {
         match config.kvstorage_type {
             KVStorageType::Postgres => {
                 info!("Using Postgres as KV storage");
@@ -90,171 +160,594 @@ impl KVStorage {
         }
     }
 
+    fn backend(&self) -> &'static str {
+        match self {
+            KVStorage::Postgres(_) => "postgres",
+            KVStorage::SQLite(_) => "sqlite",
+        }
+    }
+
+    /**
+     * Run operation in its own span, recording its duration in the metrics.
+     */
+    async fn timed<T>(
+        &self,
+        bucket: &str,
+        operation: &str,
+        future: impl Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        let start = Instant::now();
+        let span = info_span!("kv", backend = self.backend(), operation);
+        let result = future.instrument(span).await;
+        let elapsed = start.elapsed();
+        metrics::observe_kv_operation(bucket, self.backend(), operation, elapsed, result.is_ok());
+        result
+    }
+
     /**
      * Setup the KV storage.
      */
-    pub async fn setup(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn setup(&self) -> Result<(), AppError> {
         match self {
             KVStorage::Postgres(storage) => storage.setup().await,
             KVStorage::SQLite(storage) => storage.setup().await,
         }
     }
 
+    /**
+     * Run a trivial query, to check that the storage is reachable.
+     */
+    pub async fn ping(&self, bucket: &str) -> Result<(), AppError> {
+        self.timed(bucket, "ping", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.ping().await,
+                KVStorage::SQLite(storage) => storage.ping().await,
+            }
+        })
+        .await
+    }
+
     /**
      * Get the reference count for a hash.
      * If the hash does not exist, return 0.
      */
-    pub async fn get_ref_count(&mut self, bucket: &str, hash: &str) -> Result<i32, Box<dyn Error>> {
+    pub async fn get_ref_count(&self, bucket: &str, hash: &str) -> Result<i32, AppError> {
         debug!("Getting ref count for bucket: {}, hash: {}", bucket, hash);
-        match self {
-            KVStorage::Postgres(storage) => storage.get_ref_count(bucket, hash).await,
-            KVStorage::SQLite(storage) => storage.get_ref_count(bucket, hash).await,
-        }
+        self.timed(bucket, "get_ref_count", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.get_ref_count(bucket, hash).await,
+                KVStorage::SQLite(storage) => storage.get_ref_count(bucket, hash).await,
+            }
+        })
+        .await
     }
 
     /**
      * Set the reference count for a hash.
      */
     pub async fn set_ref_count(
-        &mut self,
+        &self,
         bucket: &str,
         hash: &str,
         ref_cnt: i32,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         debug!(
             "Setting ref count for bucket: {}, hash: {} to {}",
             bucket, hash, ref_cnt
         );
-        match self {
-            KVStorage::Postgres(storage) => storage.set_ref_count(bucket, hash, ref_cnt).await,
-            KVStorage::SQLite(storage) => storage.set_ref_count(bucket, hash, ref_cnt).await,
-        }
+        self.timed(bucket, "set_ref_count", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.set_ref_count(bucket, hash, ref_cnt).await,
+                KVStorage::SQLite(storage) => storage.set_ref_count(bucket, hash, ref_cnt).await,
+            }
+        })
+        .await
     }
 
     /**
      * Increment the reference count for a hash.
+     * With a fence, fail with `LockLost` if its lease is no longer held.
      */
     pub async fn increment_ref_count(
-        &mut self,
+        &self,
         bucket: &str,
         hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        fence: Option<&Fence>,
+    ) -> Result<(), AppError> {
         debug!("Incrementing ref count for bucket: {}, hash: {}", bucket, hash);
-        match self {
-            KVStorage::Postgres(storage) => storage.increment_ref_count(bucket, hash).await,
-            KVStorage::SQLite(storage) => storage.increment_ref_count(bucket, hash).await,
-        }
+        self.timed(bucket, "increment_ref_count", async {
+            match self {
+                KVStorage::Postgres(storage) => {
+                    storage.increment_ref_count(bucket, hash, fence).await
+                }
+                KVStorage::SQLite(storage) => {
+                    storage.increment_ref_count(bucket, hash, fence).await
+                }
+            }
+        })
+        .await
     }
 
     /**
      * Decrement the reference count for a hash.
      * If the reference count is already 0, do nothing.
+     * With a fence, fail with `LockLost` if its lease is no longer held.
      */
     pub async fn decrement_ref_count(
-        &mut self,
+        &self,
         bucket: &str,
         hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        fence: Option<&Fence>,
+    ) -> Result<(), AppError> {
         debug!("Decrementing ref count for bucket: {}, hash: {}", bucket, hash);
-        match self {
-            KVStorage::Postgres(storage) => storage.decrement_ref_count(bucket, hash).await,
-            KVStorage::SQLite(storage) => storage.decrement_ref_count(bucket, hash).await,
-        }
+        self.timed(bucket, "decrement_ref_count", async {
+            match self {
+                KVStorage::Postgres(storage) => {
+                    storage.decrement_ref_count(bucket, hash, fence).await
+                }
+                KVStorage::SQLite(storage) => {
+                    storage.decrement_ref_count(bucket, hash, fence).await
+                }
+            }
+        })
+        .await
     }
 
     /**
      * Get the modified time for a path.
      * If the path does not exist, return 0.
      */
-    pub async fn get_modified(&mut self, bucket: &str, path: &str) -> Result<i64, Box<dyn Error>> {
+    pub async fn get_modified(&self, bucket: &str, path: &str) -> Result<i64, AppError> {
         debug!("Getting modified time for bucket: {}, path: {}", bucket, path);
-        match self {
-            KVStorage::Postgres(storage) => storage.get_modified(bucket, path).await,
-            KVStorage::SQLite(storage) => storage.get_modified(bucket, path).await,
-        }
+        self.timed(bucket, "get_modified", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.get_modified(bucket, path).await,
+                KVStorage::SQLite(storage) => storage.get_modified(bucket, path).await,
+            }
+        })
+        .await
     }
 
     /**
      * Set the modified time for a path.
      */
     pub async fn set_modified(
-        &mut self,
+        &self,
         bucket: &str,
         path: &str,
         modified: i64,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), AppError> {
         debug!(
             "Setting modified time for bucket: {}, path: {} to {}",
             bucket, path, modified
         );
-        match self {
-            KVStorage::Postgres(storage) => storage.set_modified(bucket, path, modified).await,
-            KVStorage::SQLite(storage) => storage.set_modified(bucket, path, modified).await,
-        }
+        self.timed(bucket, "set_modified", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.set_modified(bucket, path, modified).await,
+                KVStorage::SQLite(storage) => storage.set_modified(bucket, path, modified).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Get the reference file for a path.
+     * If the path does not exist, return an empty string.
+     */
+    pub async fn get_ref_file(&self, bucket: &str, path: &str) -> Result<String, AppError> {
+        debug!("Getting ref file for bucket: {}, path: {}", bucket, path);
+        self.timed(bucket, "get_ref_file", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.get_ref_file(bucket, path).await,
+                KVStorage::SQLite(storage) => storage.get_ref_file(bucket, path).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Set the reference file for a path.
+     */
+    pub async fn set_ref_file(&self, bucket: &str, path: &str, hash: &str) -> Result<(), AppError> {
+        debug!(
+            "Setting ref file for bucket: {}, path: {} to {}",
+            bucket, path, hash
+        );
+        self.timed(bucket, "set_ref_file", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.set_ref_file(bucket, path, hash).await,
+                KVStorage::SQLite(storage) => storage.set_ref_file(bucket, path, hash).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * List hashes of a bucket with their reference counts.
+     */
+    pub async fn list_ref_counts(&self, bucket: &str) -> Result<Vec<(String, i32)>, AppError> {
+        debug!("Listing ref counts for bucket: {}", bucket);
+        self.timed(bucket, "list_ref_counts", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.list_ref_counts(bucket).await,
+                KVStorage::SQLite(storage) => storage.list_ref_counts(bucket).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Delete the reference count row for a hash.
+     */
+    pub async fn delete_ref_count(&self, bucket: &str, hash: &str) -> Result<(), AppError> {
+        debug!("Deleting ref count for bucket: {}, hash: {}", bucket, hash);
+        self.timed(bucket, "delete_ref_count", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.delete_ref_count(bucket, hash).await,
+                KVStorage::SQLite(storage) => storage.delete_ref_count(bucket, hash).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Count paths referencing each hash of a bucket.
+     * Hashes not referenced by any path are not listed.
+     */
+    pub async fn count_refs_by_hash(&self, bucket: &str) -> Result<Vec<(String, i64)>, AppError> {
+        debug!("Counting refs by hash for bucket: {}", bucket);
+        self.timed(bucket, "count_refs_by_hash", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.count_refs_by_hash(bucket).await,
+                KVStorage::SQLite(storage) => storage.count_refs_by_hash(bucket).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Count paths stored in a bucket.
+     */
+    pub async fn count_paths(&self, bucket: &str) -> Result<i64, AppError> {
+        debug!("Counting paths for bucket: {}", bucket);
+        self.timed(bucket, "count_paths", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.count_paths(bucket).await,
+                KVStorage::SQLite(storage) => storage.count_paths(bucket).await,
+            }
+        })
+        .await
     }
 
     /**
-     * Delete the modified time for a path.
+     * Get the size of the blob with given hash, if it is known.
      */
-    pub async fn delete_modified(
-        &mut self,
+    pub async fn get_blob_size(&self, bucket: &str, hash: &str) -> Result<Option<i64>, AppError> {
+        debug!("Getting blob size for bucket: {}, hash: {}", bucket, hash);
+        self.timed(bucket, "get_blob_size", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.get_blob_size(bucket, hash).await,
+                KVStorage::SQLite(storage) => storage.get_blob_size(bucket, hash).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Record the size of the blob with given hash, unless it is known already.
+     * The hash must have a reference count row. Return whether it was recorded.
+     */
+    pub async fn set_blob_size(
+        &self,
         bucket: &str,
-        path: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        debug!("Deleting modified time for bucket: {}, path: {}", bucket, path);
-        match self {
-            KVStorage::Postgres(storage) => storage.delete_modified(bucket, path).await,
-            KVStorage::SQLite(storage) => storage.delete_modified(bucket, path).await,
-        }
+        hash: &str,
+        size: i64,
+    ) -> Result<bool, AppError> {
+        debug!(
+            "Setting blob size for bucket: {}, hash: {} to {}",
+            bucket, hash, size
+        );
+        self.timed(bucket, "set_blob_size", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.set_blob_size(bucket, hash, size).await,
+                KVStorage::SQLite(storage) => storage.set_blob_size(bucket, hash, size).await,
+            }
+        })
+        .await
     }
 
     /**
-     * Get the reference file for a path.
-     * If the path does not exist, return an empty string.
+     * Sum up blob counts and sizes of a bucket, in a single pass.
+     */
+    pub async fn sum_blobs(&self, bucket: &str) -> Result<BlobTotals, AppError> {
+        debug!("Summing blobs for bucket: {}", bucket);
+        self.timed(bucket, "sum_blobs", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.sum_blobs(bucket).await,
+                KVStorage::SQLite(storage) => storage.sum_blobs(bucket).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Count referenced hashes of a bucket per reference count.
      */
-    pub async fn get_ref_file(
-        &mut self,
+    pub async fn count_hashes_by_refcount(
+        &self,
         bucket: &str,
-        path: &str,
-    ) -> Result<String, Box<dyn Error>> {
-        debug!("Getting ref file for bucket: {}, path: {}", bucket, path);
-        match self {
-            KVStorage::Postgres(storage) => storage.get_ref_file(bucket, path).await,
-            KVStorage::SQLite(storage) => storage.get_ref_file(bucket, path).await,
+    ) -> Result<Vec<(i32, i64)>, AppError> {
+        debug!("Counting hashes by refcount for bucket: {}", bucket);
+        self.timed(bucket, "count_hashes_by_refcount", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.count_hashes_by_refcount(bucket).await,
+                KVStorage::SQLite(storage) => storage.count_hashes_by_refcount(bucket).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * List the limit hashes of a bucket with the highest reference counts.
+     */
+    pub async fn most_shared(&self, bucket: &str, limit: i64) -> Result<Vec<SharedBlob>, AppError> {
+        debug!("Listing most shared hashes for bucket: {}", bucket);
+        self.timed(bucket, "most_shared", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.most_shared(bucket, limit).await,
+                KVStorage::SQLite(storage) => storage.most_shared(bucket, limit).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Get the tracked usage of a bucket, if tracking has started.
+     */
+    pub async fn get_usage(&self, bucket: &str) -> Result<Option<Usage>, AppError> {
+        debug!("Getting usage for bucket: {}", bucket);
+        self.timed(bucket, "get_usage", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.get_usage(bucket).await,
+                KVStorage::SQLite(storage) => storage.get_usage(bucket).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Overwrite the tracked usage of a bucket.
+     */
+    pub async fn set_usage(&self, bucket: &str, usage: &Usage) -> Result<(), AppError> {
+        debug!("Setting usage for bucket: {} to {:?}", bucket, usage);
+        self.timed(bucket, "set_usage", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.set_usage(bucket, usage).await,
+                KVStorage::SQLite(storage) => storage.set_usage(bucket, usage).await,
+            }
+        })
+        .await?;
+        metrics::record_usage(bucket, usage);
+        Ok(())
+    }
+
+    /**
+     * Add delta to the tracked usage of a bucket, atomically.
+     */
+    pub async fn add_usage(&self, bucket: &str, delta: &Usage) -> Result<(), AppError> {
+        if delta.is_zero() {
+            return Ok(());
+        }
+        debug!("Adding usage for bucket: {}: {:?}", bucket, delta);
+        let usage = self
+            .timed(bucket, "add_usage", async {
+                match self {
+                    KVStorage::Postgres(storage) => storage.add_usage(bucket, delta).await,
+                    KVStorage::SQLite(storage) => storage.add_usage(bucket, delta).await,
+                }
+            })
+            .await?;
+        if let Some(usage) = usage {
+            metrics::record_usage(bucket, &usage);
         }
+        Ok(())
     }
 
     /**
-     * Set the reference file for a path.
+     * Add delta to the tracked usage of a bucket unless a growing count
+     * would exceed its limit, checking and adding atomically.
+     * Return whether delta was added.
      */
-    pub async fn set_ref_file(
-        &mut self,
+    pub async fn add_usage_within(
+        &self,
         bucket: &str,
-        path: &str,
-        hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        delta: &Usage,
+        limits: &QuotaConfig,
+    ) -> Result<bool, AppError> {
+        if delta.is_zero() {
+            return Ok(true);
+        }
         debug!(
-            "Setting ref file for bucket: {}, path: {} to {}",
-            bucket, path, hash
+            "Adding usage within quota for bucket: {}: {:?}",
+            bucket, delta
         );
-        match self {
-            KVStorage::Postgres(storage) => storage.set_ref_file(bucket, path, hash).await,
-            KVStorage::SQLite(storage) => storage.set_ref_file(bucket, path, hash).await,
+        let usage = self
+            .timed(bucket, "add_usage_within", async {
+                match self {
+                    KVStorage::Postgres(storage) => {
+                        storage.add_usage_within(bucket, delta, limits).await
+                    }
+                    KVStorage::SQLite(storage) => {
+                        storage.add_usage_within(bucket, delta, limits).await
+                    }
+                }
+            })
+            .await?;
+        if let Some(usage) = &usage {
+            metrics::record_usage(bucket, usage);
         }
+        Ok(usage.is_some())
+    }
+
+    /**
+     * Start tracking the usage of a bucket at usage, unless it is tracked
+     * already. Return whether tracking started.
+     */
+    pub async fn insert_usage(&self, bucket: &str, usage: &Usage) -> Result<bool, AppError> {
+        debug!("Inserting usage for bucket: {}: {:?}", bucket, usage);
+        self.timed(bucket, "insert_usage", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.insert_usage(bucket, usage).await,
+                KVStorage::SQLite(storage) => storage.insert_usage(bucket, usage).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Count the usage of a bucket from its paths and blobs.
+     */
+    pub async fn count_usage(&self, bucket: &str) -> Result<Usage, AppError> {
+        let paths = self.count_paths(bucket).await?;
+        let totals = self.sum_blobs(bucket).await?;
+        Ok(Usage {
+            logical_bytes: totals.logical_bytes,
+            physical_bytes: totals.physical_bytes,
+            paths,
+        })
+    }
+
+    /**
+     * Start tracking the usage of a bucket, unless it is tracked already.
+     *
+     * Usage is counted while uploads of other processes may run, as locking
+     * the whole bucket would stall them all. Uploads racing with the count
+     * can leave the usage off, which fsck reports and `--repair` corrects.
+     * Uploads only add to usage once it is tracked, and of processes
+     * counting at the same time, the first one to finish wins.
+     */
+    pub async fn init_usage(&self, bucket: &str) -> Result<(), AppError> {
+        if let Some(usage) = self.get_usage(bucket).await? {
+            metrics::record_usage(bucket, &usage);
+            return Ok(());
+        }
+        info!("Counting usage of bucket: {}", bucket);
+        let counted = self.count_usage(bucket).await?;
+        let usage = if self.insert_usage(bucket, &counted).await? {
+            counted
+        } else {
+            self.get_usage(bucket).await?.unwrap_or(counted)
+        };
+        metrics::record_usage(bucket, &usage);
+        Ok(())
+    }
+
+    /**
+     * Gather statistics of a bucket, listing the top most shared hashes.
+     * Totals are taken from the tracked usage rather than counted, which
+     * only fsck does.
+     */
+    pub async fn stats(&self, bucket: &str, top: i64) -> Result<BucketStats, AppError> {
+        let usage = self.get_usage(bucket).await?.unwrap_or_default();
+        let by_refcount = self.count_hashes_by_refcount(bucket).await?;
+        let most_shared = self.most_shared(bucket, top).await?;
+        Ok(BucketStats::new(bucket, usage, by_refcount, most_shared))
     }
 
     /**
-     * Delete the reference file for a path.
+     * Take the lease on key for owner, unless someone else holds an unexpired one.
+     * An expired lease is taken over. Return whether the lease was taken.
+     * token must be unique to this acquisition, writes fenced with it fail
+     * once the lease is lost.
      */
-    pub async fn delete_ref_file(
-        &mut self,
+    pub async fn try_acquire_lease(
+        &self,
         bucket: &str,
-        path: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        debug!("Deleting ref file for bucket: {}, path: {}", bucket, path);
+        key: &str,
+        owner: &str,
+        token: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> Result<bool, AppError> {
+        debug!("Trying to acquire lease for key: {}, owner: {}", key, owner);
+        self.timed(bucket, "try_acquire_lease", async {
+            match self {
+                KVStorage::Postgres(storage) => {
+                    storage
+                        .try_acquire_lease(key, owner, token, now, expires_at)
+                        .await
+                }
+                KVStorage::SQLite(storage) => {
+                    storage
+                        .try_acquire_lease(key, owner, token, now, expires_at)
+                        .await
+                }
+            }
+        })
+        .await
+    }
+
+    /**
+     * Extend the lease on key taken with token.
+     * Return false if the lease was lost since.
+     */
+    pub async fn renew_lease(
+        &self,
+        bucket: &str,
+        key: &str,
+        token: &str,
+        expires_at: i64,
+    ) -> Result<bool, AppError> {
+        debug!("Renewing lease for key: {}, token: {}", key, token);
+        self.timed(bucket, "renew_lease", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.renew_lease(key, token, expires_at).await,
+                KVStorage::SQLite(storage) => storage.renew_lease(key, token, expires_at).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Release the lease on key if it is still held with token.
+     */
+    pub async fn release_lease(
+        &self,
+        bucket: &str,
+        key: &str,
+        token: &str,
+    ) -> Result<(), AppError> {
+        debug!("Releasing lease for key: {}, token: {}", key, token);
+        self.timed(bucket, "release_lease", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.release_lease(key, token).await,
+                KVStorage::SQLite(storage) => storage.release_lease(key, token).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Release all leases held by owner.
+     */
+    pub async fn release_leases(&self, bucket: &str, owner: &str) -> Result<(), AppError> {
+        debug!("Releasing all leases of owner: {}", owner);
+        self.timed(bucket, "release_leases", async {
+            match self {
+                KVStorage::Postgres(storage) => storage.release_leases(owner).await,
+                KVStorage::SQLite(storage) => storage.release_leases(owner).await,
+            }
+        })
+        .await
+    }
+
+    /**
+     * Close all connections, waiting for connections in use to be returned.
+     */
+    pub async fn close(&self) {
         match self {
-            KVStorage::Postgres(storage) => storage.delete_ref_file(bucket, path).await,
-            KVStorage::SQLite(storage) => storage.delete_ref_file(bucket, path).await,
+            KVStorage::Postgres(storage) => storage.close().await,
+            KVStorage::SQLite(storage) => storage.close().await,
         }
     }
 }
@@ -1,13 +1,16 @@
 use crate::config::BucketConfig;
+use crate::error::AppError;
 use crate::kvstorage::KVStorageTrait;
 use crate::kvstorage::pooled::{RowModified, RowRefFile, RowRefcount};
-use serde::Deserialize;
+use crate::kvstorage::stats::{BlobTotals, SharedBlob, Usage};
+use crate::locks::Fence;
+use crate::quota::QuotaConfig;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use sqlx::postgres::PgPoolOptions;
-use std::error::Error;
 use tracing::debug;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PostgresConfig {
     pub host: String,
     pub port: u16,
@@ -23,8 +26,13 @@ pub struct Postgres {
 }
 
 impl KVStorageTrait for Postgres {
-    async fn new(config: &BucketConfig) -> Result<Box<Self>, Box<dyn Error>> {
-        let pg_config = config.postgres.as_ref().unwrap();
+    async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError> {
+        let pg_config = config.postgres.as_ref().ok_or_else(|| {
+            AppError::Validation(format!(
+                "Missing postgres config for bucket: {}",
+                config.name
+            ))
+        })?;
         let db_url = format!(
             "postgres://{}:{}@{}:{}/{}",
             pg_config.user, pg_config.password, pg_config.host, pg_config.port, pg_config.dbname
@@ -36,14 +44,17 @@ impl KVStorageTrait for Postgres {
             .await?;
         Ok(Box::new(Postgres { pool }))
     }
-    async fn setup(&mut self) -> Result<(), Box<dyn Error>> {
+    async fn setup(&self) -> Result<(), AppError> {
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS refcount (
                 bucket VARCHAR(255) NOT NULL,
                 hash VARCHAR(255) NOT NULL,
                 refcount INT NOT NULL,
+                size BIGINT,
                 PRIMARY KEY (bucket, hash)
             );
+            ALTER TABLE refcount ADD COLUMN IF NOT EXISTS size BIGINT;
+            CREATE INDEX IF NOT EXISTS refcount_by_refcount ON refcount (bucket, refcount);
             CREATE TABLE IF NOT EXISTS modified (
                 bucket VARCHAR(255) NOT NULL,
                 path VARCHAR(255) NOT NULL,
@@ -55,29 +66,39 @@ impl KVStorageTrait for Postgres {
                 path VARCHAR(255) NOT NULL,
                 hash VARCHAR(255) NOT NULL,
                 PRIMARY KEY (bucket, path)
-            );",
+            );
+            CREATE TABLE IF NOT EXISTS bucket_usage (
+                bucket VARCHAR(255) NOT NULL,
+                logical_bytes BIGINT NOT NULL,
+                physical_bytes BIGINT NOT NULL,
+                paths BIGINT NOT NULL,
+                PRIMARY KEY (bucket)
+            );
+            CREATE TABLE IF NOT EXISTS lease (
+                lock_key TEXT NOT NULL,
+                owner VARCHAR(255) NOT NULL,
+                token VARCHAR(255),
+                expires_at BIGINT NOT NULL,
+                PRIMARY KEY (lock_key)
+            );
+            ALTER TABLE lease ADD COLUMN IF NOT EXISTS token VARCHAR(255);",
         )
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    async fn get_ref_count(&mut self, bucket: &str, hash: &str) -> Result<i32, Box<dyn Error>> {
-        sqlx::query_as("SELECT refcount FROM refcount WHERE bucket = $1 AND hash = $2")
-            .bind(bucket)
-            .bind(hash)
-            .fetch_one(&self.pool)
-            .await
-            .map(|row: RowRefcount| row.refcount)
-            .or_else(|_| Ok(0))
+    async fn get_ref_count(&self, bucket: &str, hash: &str) -> Result<i32, AppError> {
+        let row: Option<RowRefcount> =
+            sqlx::query_as("SELECT hash, refcount FROM refcount WHERE bucket = $1 AND hash = $2")
+                .bind(bucket)
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.refcount).unwrap_or(0))
     }
 
-    async fn set_ref_count(
-        &mut self,
-        bucket: &str,
-        hash: &str,
-        ref_cnt: i32,
-    ) -> Result<(), Box<dyn Error>> {
+    async fn set_ref_count(&self, bucket: &str, hash: &str, ref_cnt: i32) -> Result<(), AppError> {
         sqlx::query(
             "INSERT INTO refcount (bucket, hash, refcount) VALUES ($1, $2, $3)
             ON CONFLICT (bucket, hash) DO UPDATE SET refcount = $3",
@@ -90,22 +111,41 @@ impl KVStorageTrait for Postgres {
         Ok(())
     }
 
-    async fn get_modified(&mut self, bucket: &str, path: &str) -> Result<i64, Box<dyn Error>> {
-        sqlx::query_as("SELECT modified FROM modified WHERE bucket = $1 AND path = $2")
-            .bind(bucket)
-            .bind(path)
-            .fetch_one(&self.pool)
-            .await
-            .map(|row: RowModified| row.modified)
-            .or_else(|_| Ok(0))
+    async fn set_ref_count_fenced(
+        &self,
+        bucket: &str,
+        hash: &str,
+        ref_cnt: i32,
+        fence: &Fence,
+    ) -> Result<bool, AppError> {
+        // The WHERE clause also keeps the upsert apart from the SELECT
+        let result = sqlx::query(
+            "INSERT INTO refcount (bucket, hash, refcount)
+            SELECT $1, $2, $3
+            WHERE EXISTS (SELECT 1 FROM lease WHERE lock_key = $4 AND token = $5)
+            ON CONFLICT (bucket, hash) DO UPDATE SET refcount = $3",
+        )
+        .bind(bucket)
+        .bind(hash)
+        .bind(ref_cnt)
+        .bind(&fence.key)
+        .bind(&fence.token)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
     }
 
-    async fn set_modified(
-        &mut self,
-        bucket: &str,
-        path: &str,
-        modified: i64,
-    ) -> Result<(), Box<dyn Error>> {
+    async fn get_modified(&self, bucket: &str, path: &str) -> Result<i64, AppError> {
+        let row: Option<RowModified> =
+            sqlx::query_as("SELECT modified FROM modified WHERE bucket = $1 AND path = $2")
+                .bind(bucket)
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.modified).unwrap_or(0))
+    }
+
+    async fn set_modified(&self, bucket: &str, path: &str, modified: i64) -> Result<(), AppError> {
         sqlx::query(
             "INSERT INTO modified (bucket, path, modified) VALUES ($1, $2, $3)
             ON CONFLICT (bucket, path) DO UPDATE SET modified = $3",
@@ -118,49 +158,270 @@ impl KVStorageTrait for Postgres {
         Ok(())
     }
 
-    async fn delete_modified(&mut self, bucket: &str, path: &str) -> Result<(), Box<dyn Error>> {
-        sqlx::query("DELETE FROM modified WHERE bucket = $1 AND path = $2")
+    async fn get_ref_file(&self, bucket: &str, path: &str) -> Result<String, AppError> {
+        let row: Option<RowRefFile> =
+            sqlx::query_as("SELECT hash FROM ref_file WHERE bucket = $1 AND path = $2")
+                .bind(bucket)
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.hash).unwrap_or("".to_string()))
+    }
+
+    async fn set_ref_file(&self, bucket: &str, path: &str, hash: &str) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO ref_file (bucket, path, hash) VALUES ($1, $2, $3)
+            ON CONFLICT (bucket, path) DO UPDATE SET hash = $3",
+        )
+        .bind(bucket)
+        .bind(path)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_ref_counts(&self, bucket: &str) -> Result<Vec<(String, i32)>, AppError> {
+        let rows: Vec<RowRefcount> =
+            sqlx::query_as("SELECT hash, refcount FROM refcount WHERE bucket = $1")
+                .bind(bucket)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.hash, row.refcount))
+            .collect())
+    }
+
+    async fn delete_ref_count(&self, bucket: &str, hash: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM refcount WHERE bucket = $1 AND hash = $2")
             .bind(bucket)
-            .bind(path)
+            .bind(hash)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn get_ref_file(&mut self, bucket: &str, path: &str) -> Result<String, Box<dyn Error>> {
-        sqlx::query_as("SELECT hash FROM ref_file WHERE bucket = $1 AND path = $2")
+    async fn count_refs_by_hash(&self, bucket: &str) -> Result<Vec<(String, i64)>, AppError> {
+        let rows =
+            sqlx::query_as("SELECT hash, COUNT(*) FROM ref_file WHERE bucket = $1 GROUP BY hash")
+                .bind(bucket)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows)
+    }
+
+    async fn count_paths(&self, bucket: &str) -> Result<i64, AppError> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM ref_file WHERE bucket = $1")
             .bind(bucket)
-            .bind(path)
             .fetch_one(&self.pool)
-            .await
-            .map(|row: RowRefFile| row.hash)
-            .or_else(|_| Ok("".to_string()))
+            .await?;
+        Ok(count)
     }
 
-    async fn set_ref_file(
-        &mut self,
-        bucket: &str,
-        path: &str,
-        hash: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        sqlx::query(
-            "INSERT INTO ref_file (bucket, path, hash) VALUES ($1, $2, $3)
-            ON CONFLICT (bucket, path) DO UPDATE SET hash = $3",
+    async fn get_blob_size(&self, bucket: &str, hash: &str) -> Result<Option<i64>, AppError> {
+        let size: Option<Option<i64>> =
+            sqlx::query_scalar("SELECT size FROM refcount WHERE bucket = $1 AND hash = $2")
+                .bind(bucket)
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(size.flatten())
+    }
+
+    async fn set_blob_size(&self, bucket: &str, hash: &str, size: i64) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "UPDATE refcount SET size = $3 WHERE bucket = $1 AND hash = $2 AND size IS NULL",
         )
         .bind(bucket)
-        .bind(path)
         .bind(hash)
+        .bind(size)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn sum_blobs(&self, bucket: &str) -> Result<BlobTotals, AppError> {
+        // SUM over BIGINT yields NUMERIC
+        let totals = sqlx::query_as(
+            "SELECT COALESCE(SUM(size), 0)::BIGINT AS physical_bytes,
+                COALESCE(SUM(size * refcount), 0)::BIGINT AS logical_bytes
+            FROM refcount WHERE bucket = $1 AND refcount > 0",
+        )
+        .bind(bucket)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(totals)
+    }
+
+    async fn count_hashes_by_refcount(&self, bucket: &str) -> Result<Vec<(i32, i64)>, AppError> {
+        let rows = sqlx::query_as(
+            "SELECT refcount, COUNT(*) FROM refcount
+            WHERE bucket = $1 AND refcount > 0 GROUP BY refcount",
+        )
+        .bind(bucket)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn most_shared(&self, bucket: &str, limit: i64) -> Result<Vec<SharedBlob>, AppError> {
+        let rows = sqlx::query_as(
+            "SELECT hash, refcount, size FROM refcount
+            WHERE bucket = $1 AND refcount > 0 ORDER BY refcount DESC LIMIT $2",
+        )
+        .bind(bucket)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_usage(&self, bucket: &str) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "SELECT logical_bytes, physical_bytes, paths FROM bucket_usage WHERE bucket = $1",
+        )
+        .bind(bucket)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn set_usage(&self, bucket: &str, usage: &Usage) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO bucket_usage (bucket, logical_bytes, physical_bytes, paths)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (bucket) DO UPDATE
+            SET logical_bytes = $2, physical_bytes = $3, paths = $4",
+        )
+        .bind(bucket)
+        .bind(usage.logical_bytes)
+        .bind(usage.physical_bytes)
+        .bind(usage.paths)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    async fn delete_ref_file(&mut self, bucket: &str, path: &str) -> Result<(), Box<dyn Error>> {
-        sqlx::query("DELETE FROM ref_file WHERE bucket = $1 AND path = $2")
-            .bind(bucket)
-            .bind(path)
+    async fn insert_usage(&self, bucket: &str, usage: &Usage) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "INSERT INTO bucket_usage (bucket, logical_bytes, physical_bytes, paths)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (bucket) DO NOTHING",
+        )
+        .bind(bucket)
+        .bind(usage.logical_bytes)
+        .bind(usage.physical_bytes)
+        .bind(usage.paths)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn add_usage(&self, bucket: &str, delta: &Usage) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "UPDATE bucket_usage
+            SET logical_bytes = logical_bytes + $2,
+                physical_bytes = physical_bytes + $3,
+                paths = paths + $4
+            WHERE bucket = $1
+            RETURNING logical_bytes, physical_bytes, paths",
+        )
+        .bind(bucket)
+        .bind(delta.logical_bytes)
+        .bind(delta.physical_bytes)
+        .bind(delta.paths)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn add_usage_within(
+        &self,
+        bucket: &str,
+        delta: &Usage,
+        limits: &QuotaConfig,
+    ) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "UPDATE bucket_usage
+            SET logical_bytes = logical_bytes + $2::BIGINT,
+                physical_bytes = physical_bytes + $3::BIGINT,
+                paths = paths + $4::BIGINT
+            WHERE bucket = $1
+                AND ($2::BIGINT <= 0 OR $5::BIGINT IS NULL OR logical_bytes + $2::BIGINT <= $5::BIGINT)
+                AND ($3::BIGINT <= 0 OR $6::BIGINT IS NULL OR physical_bytes + $3::BIGINT <= $6::BIGINT)
+                AND ($4::BIGINT <= 0 OR $7::BIGINT IS NULL OR paths + $4::BIGINT <= $7::BIGINT)
+            RETURNING logical_bytes, physical_bytes, paths",
+        )
+        .bind(bucket)
+        .bind(delta.logical_bytes)
+        .bind(delta.physical_bytes)
+        .bind(delta.paths)
+        .bind(limits.max_logical_bytes)
+        .bind(limits.max_physical_bytes)
+        .bind(limits.max_paths)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn try_acquire_lease(
+        &self,
+        key: &str,
+        owner: &str,
+        token: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "INSERT INTO lease (lock_key, owner, token, expires_at) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (lock_key) DO UPDATE SET owner = $2, token = $3, expires_at = $4
+            WHERE lease.expires_at < $5",
+        )
+        .bind(key)
+        .bind(owner)
+        .bind(token)
+        .bind(expires_at)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn renew_lease(&self, key: &str, token: &str, expires_at: i64) -> Result<bool, AppError> {
+        let result =
+            sqlx::query("UPDATE lease SET expires_at = $3 WHERE lock_key = $1 AND token = $2")
+                .bind(key)
+                .bind(token)
+                .bind(expires_at)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn release_lease(&self, key: &str, token: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM lease WHERE lock_key = $1 AND token = $2")
+            .bind(key)
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_leases(&self, owner: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM lease WHERE owner = $1")
+            .bind(owner)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
 }
@@ -1,13 +1,17 @@
-use std::path::Path;
 use crate::config::BucketConfig;
+use crate::error::AppError;
 use crate::kvstorage::KVStorageTrait;
 use crate::kvstorage::pooled::{RowModified, RowRefFile, RowRefcount};
-use serde::Deserialize;
+use crate::kvstorage::stats::{BlobTotals, SharedBlob, Usage};
+use crate::locks::Fence;
+use crate::quota::QuotaConfig;
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use std::path::Path;
 use tracing::debug;
-use tracing::field::debug;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SQLiteConfig {
     pub path: String,
     pub pool_size: u32,
@@ -19,8 +23,10 @@ pub struct SQLite {
 }
 
 impl KVStorageTrait for SQLite {
-    async fn new(config: &BucketConfig) -> Result<Box<Self>, Box<dyn std::error::Error>> {
-        let sqlite_config = config.sqlite.as_ref().unwrap();
+    async fn new(config: &BucketConfig) -> Result<Box<Self>, AppError> {
+        let sqlite_config = config.sqlite.as_ref().ok_or_else(|| {
+            AppError::Validation(format!("Missing sqlite config for bucket: {}", config.name))
+        })?;
 
         if !Path::new(&sqlite_config.path).exists() {
             std::fs::File::create(&sqlite_config.path)?;
@@ -28,18 +34,23 @@ impl KVStorageTrait for SQLite {
 
         let db_url = format!("sqlite://{}", sqlite_config.path);
         debug!("Connecting to SQLite database: {}", db_url);
-        let pool = SqlitePool::connect(&db_url).await?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(sqlite_config.pool_size)
+            .connect(&db_url)
+            .await?;
         Ok(Box::new(SQLite { pool }))
     }
 
-    async fn setup(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn setup(&self) -> Result<(), AppError> {
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS refcount (
                 bucket TEXT NOT NULL,
                 hash TEXT NOT NULL,
                 refcount INTEGER NOT NULL,
+                size INTEGER,
                 PRIMARY KEY (bucket, hash)
             );
+            CREATE INDEX IF NOT EXISTS refcount_by_refcount ON refcount (bucket, refcount);
             CREATE TABLE IF NOT EXISTS modified (
                 bucket TEXT NOT NULL,
                 path TEXT NOT NULL,
@@ -51,123 +62,377 @@ impl KVStorageTrait for SQLite {
                 path TEXT NOT NULL,
                 hash TEXT NOT NULL,
                 PRIMARY KEY (bucket, path)
+            );
+            CREATE TABLE IF NOT EXISTS bucket_usage (
+                bucket TEXT NOT NULL,
+                logical_bytes INTEGER NOT NULL,
+                physical_bytes INTEGER NOT NULL,
+                paths INTEGER NOT NULL,
+                PRIMARY KEY (bucket)
+            );
+            CREATE TABLE IF NOT EXISTS lease (
+                lock_key TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                token TEXT,
+                expires_at INTEGER NOT NULL,
+                PRIMARY KEY (lock_key)
             );",
         )
         .execute(&self.pool)
         .await?;
+
+        // Added after the first release, SQLite cannot add columns conditionally
+        let has_size: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('refcount') WHERE name = 'size'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !has_size {
+            sqlx::query("ALTER TABLE refcount ADD COLUMN size INTEGER")
+                .execute(&self.pool)
+                .await?;
+        }
+        let has_token: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('lease') WHERE name = 'token'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !has_token {
+            sqlx::query("ALTER TABLE lease ADD COLUMN token TEXT")
+                .execute(&self.pool)
+                .await?;
+        }
         Ok(())
     }
 
-    async fn get_ref_count(
-        &mut self,
-        bucket: &str,
-        hash: &str,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        sqlx::query_as("SELECT refcount FROM refcount WHERE bucket = ?1 AND hash = ?2")
-            .bind(bucket)
-            .bind(hash)
-            .fetch_one(&self.pool)
-            .await
-            .map(|row: RowRefcount| row.refcount)
-            .or(Ok(0))
+    async fn get_ref_count(&self, bucket: &str, hash: &str) -> Result<i32, AppError> {
+        let row: Option<RowRefcount> =
+            sqlx::query_as("SELECT hash, refcount FROM refcount WHERE bucket = ?1 AND hash = ?2")
+                .bind(bucket)
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.refcount).unwrap_or(0))
     }
 
-    async fn set_ref_count(
-        &mut self,
+    async fn set_ref_count(&self, bucket: &str, hash: &str, ref_cnt: i32) -> Result<(), AppError> {
+        // Updates in place, keeping the size
+        sqlx::query(
+            "INSERT INTO refcount (bucket, hash, refcount) VALUES (?1, ?2, ?3)
+            ON CONFLICT (bucket, hash) DO UPDATE SET refcount = ?3",
+        )
+        .bind(bucket)
+        .bind(hash)
+        .bind(ref_cnt)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_ref_count_fenced(
+        &self,
         bucket: &str,
         hash: &str,
         ref_cnt: i32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        sqlx::query("INSERT OR REPLACE INTO refcount (bucket, hash, refcount) VALUES (?1, ?2, ?3)")
+        fence: &Fence,
+    ) -> Result<bool, AppError> {
+        // The WHERE clause also keeps the upsert apart from the SELECT
+        let result = sqlx::query(
+            "INSERT INTO refcount (bucket, hash, refcount)
+            SELECT ?1, ?2, ?3
+            WHERE EXISTS (SELECT 1 FROM lease WHERE lock_key = ?4 AND token = ?5)
+            ON CONFLICT (bucket, hash) DO UPDATE SET refcount = ?3",
+        )
+        .bind(bucket)
+        .bind(hash)
+        .bind(ref_cnt)
+        .bind(&fence.key)
+        .bind(&fence.token)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn get_modified(&self, bucket: &str, path: &str) -> Result<i64, AppError> {
+        let row: Option<RowModified> =
+            sqlx::query_as("SELECT modified FROM modified WHERE bucket = ?1 AND path = ?2")
+                .bind(bucket)
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.modified).unwrap_or(0))
+    }
+
+    async fn set_modified(&self, bucket: &str, path: &str, modified: i64) -> Result<(), AppError> {
+        sqlx::query("INSERT OR REPLACE INTO modified (bucket, path, modified) VALUES (?1, ?2, ?3)")
             .bind(bucket)
-            .bind(hash)
-            .bind(ref_cnt)
+            .bind(path)
+            .bind(modified)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn get_modified(
-        &mut self,
-        bucket: &str,
-        path: &str,
-    ) -> Result<i64, Box<dyn std::error::Error>> {
-        sqlx::query_as("SELECT modified FROM modified WHERE bucket = ?1 AND path = ?2")
-            .bind(bucket)
-            .bind(path)
-            .fetch_one(&self.pool)
-            .await
-            .map(|row: RowModified| row.modified)
-            .or(Ok(0))
+    async fn get_ref_file(&self, bucket: &str, path: &str) -> Result<String, AppError> {
+        let row: Option<RowRefFile> =
+            sqlx::query_as("SELECT hash FROM ref_file WHERE bucket = ?1 AND path = ?2")
+                .bind(bucket)
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|row| row.hash).unwrap_or("".to_string()))
     }
 
-    async fn set_modified(
-        &mut self,
-        bucket: &str,
-        path: &str,
-        modified: i64,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        sqlx::query("INSERT OR REPLACE INTO modified (bucket, path, modified) VALUES (?1, ?2, ?3)")
+    async fn set_ref_file(&self, bucket: &str, path: &str, hash: &str) -> Result<(), AppError> {
+        sqlx::query("INSERT OR REPLACE INTO ref_file (bucket, path, hash) VALUES (?1, ?2, ?3)")
             .bind(bucket)
             .bind(path)
-            .bind(modified)
+            .bind(hash)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn delete_modified(
-        &mut self,
-        bucket: &str,
-        path: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        sqlx::query("DELETE FROM modified WHERE bucket = ?1 AND path = ?2")
+    async fn list_ref_counts(&self, bucket: &str) -> Result<Vec<(String, i32)>, AppError> {
+        let rows: Vec<RowRefcount> =
+            sqlx::query_as("SELECT hash, refcount FROM refcount WHERE bucket = ?1")
+                .bind(bucket)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.hash, row.refcount))
+            .collect())
+    }
+
+    async fn delete_ref_count(&self, bucket: &str, hash: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM refcount WHERE bucket = ?1 AND hash = ?2")
             .bind(bucket)
-            .bind(path)
+            .bind(hash)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn get_ref_file(
-        &mut self,
-        bucket: &str,
-        path: &str,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        sqlx::query_as("SELECT hash FROM ref_file WHERE bucket = ?1 AND path = ?2")
+    async fn count_refs_by_hash(&self, bucket: &str) -> Result<Vec<(String, i64)>, AppError> {
+        let rows =
+            sqlx::query_as("SELECT hash, COUNT(*) FROM ref_file WHERE bucket = ?1 GROUP BY hash")
+                .bind(bucket)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows)
+    }
+
+    async fn count_paths(&self, bucket: &str) -> Result<i64, AppError> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM ref_file WHERE bucket = ?1")
             .bind(bucket)
-            .bind(path)
             .fetch_one(&self.pool)
-            .await
-            .map(|row: RowRefFile| row.hash)
-            .or(Ok("".to_string()))
+            .await?;
+        Ok(count)
+    }
+
+    async fn get_blob_size(&self, bucket: &str, hash: &str) -> Result<Option<i64>, AppError> {
+        let size: Option<Option<i64>> =
+            sqlx::query_scalar("SELECT size FROM refcount WHERE bucket = ?1 AND hash = ?2")
+                .bind(bucket)
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(size.flatten())
+    }
+
+    async fn set_blob_size(&self, bucket: &str, hash: &str, size: i64) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "UPDATE refcount SET size = ?3 WHERE bucket = ?1 AND hash = ?2 AND size IS NULL",
+        )
+        .bind(bucket)
+        .bind(hash)
+        .bind(size)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn sum_blobs(&self, bucket: &str) -> Result<BlobTotals, AppError> {
+        let totals = sqlx::query_as(
+            "SELECT COALESCE(SUM(size), 0) AS physical_bytes,
+                COALESCE(SUM(size * refcount), 0) AS logical_bytes
+            FROM refcount WHERE bucket = ?1 AND refcount > 0",
+        )
+        .bind(bucket)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(totals)
+    }
+
+    async fn count_hashes_by_refcount(&self, bucket: &str) -> Result<Vec<(i32, i64)>, AppError> {
+        let rows = sqlx::query_as(
+            "SELECT refcount, COUNT(*) FROM refcount
+            WHERE bucket = ?1 AND refcount > 0 GROUP BY refcount",
+        )
+        .bind(bucket)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn most_shared(&self, bucket: &str, limit: i64) -> Result<Vec<SharedBlob>, AppError> {
+        let rows = sqlx::query_as(
+            "SELECT hash, refcount, size FROM refcount
+            WHERE bucket = ?1 AND refcount > 0 ORDER BY refcount DESC LIMIT ?2",
+        )
+        .bind(bucket)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
     }
 
-    async fn set_ref_file(
-        &mut self,
+    async fn get_usage(&self, bucket: &str) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "SELECT logical_bytes, physical_bytes, paths FROM bucket_usage WHERE bucket = ?1",
+        )
+        .bind(bucket)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn set_usage(&self, bucket: &str, usage: &Usage) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO bucket_usage (bucket, logical_bytes, physical_bytes, paths)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (bucket) DO UPDATE
+            SET logical_bytes = ?2, physical_bytes = ?3, paths = ?4",
+        )
+        .bind(bucket)
+        .bind(usage.logical_bytes)
+        .bind(usage.physical_bytes)
+        .bind(usage.paths)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_usage(&self, bucket: &str, usage: &Usage) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "INSERT INTO bucket_usage (bucket, logical_bytes, physical_bytes, paths)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (bucket) DO NOTHING",
+        )
+        .bind(bucket)
+        .bind(usage.logical_bytes)
+        .bind(usage.physical_bytes)
+        .bind(usage.paths)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn add_usage(&self, bucket: &str, delta: &Usage) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "UPDATE bucket_usage
+            SET logical_bytes = logical_bytes + ?2,
+                physical_bytes = physical_bytes + ?3,
+                paths = paths + ?4
+            WHERE bucket = ?1
+            RETURNING logical_bytes, physical_bytes, paths",
+        )
+        .bind(bucket)
+        .bind(delta.logical_bytes)
+        .bind(delta.physical_bytes)
+        .bind(delta.paths)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn add_usage_within(
+        &self,
         bucket: &str,
-        path: &str,
-        hash: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        sqlx::query("INSERT OR REPLACE INTO ref_file (bucket, path, hash) VALUES (?1, ?2, ?3)")
-            .bind(bucket)
-            .bind(path)
-            .bind(hash)
+        delta: &Usage,
+        limits: &QuotaConfig,
+    ) -> Result<Option<Usage>, AppError> {
+        let usage = sqlx::query_as(
+            "UPDATE bucket_usage
+            SET logical_bytes = logical_bytes + ?2,
+                physical_bytes = physical_bytes + ?3,
+                paths = paths + ?4
+            WHERE bucket = ?1
+                AND (?2 <= 0 OR ?5 IS NULL OR logical_bytes + ?2 <= ?5)
+                AND (?3 <= 0 OR ?6 IS NULL OR physical_bytes + ?3 <= ?6)
+                AND (?4 <= 0 OR ?7 IS NULL OR paths + ?4 <= ?7)
+            RETURNING logical_bytes, physical_bytes, paths",
+        )
+        .bind(bucket)
+        .bind(delta.logical_bytes)
+        .bind(delta.physical_bytes)
+        .bind(delta.paths)
+        .bind(limits.max_logical_bytes)
+        .bind(limits.max_physical_bytes)
+        .bind(limits.max_paths)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+
+    async fn try_acquire_lease(
+        &self,
+        key: &str,
+        owner: &str,
+        token: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "INSERT INTO lease (lock_key, owner, token, expires_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (lock_key) DO UPDATE SET owner = ?2, token = ?3, expires_at = ?4
+            WHERE lease.expires_at < ?5",
+        )
+        .bind(key)
+        .bind(owner)
+        .bind(token)
+        .bind(expires_at)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn renew_lease(&self, key: &str, token: &str, expires_at: i64) -> Result<bool, AppError> {
+        let result =
+            sqlx::query("UPDATE lease SET expires_at = ?3 WHERE lock_key = ?1 AND token = ?2")
+                .bind(key)
+                .bind(token)
+                .bind(expires_at)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn release_lease(&self, key: &str, token: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM lease WHERE lock_key = ?1 AND token = ?2")
+            .bind(key)
+            .bind(token)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn delete_ref_file(
-        &mut self,
-        bucket: &str,
-        path: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        sqlx::query("DELETE FROM ref_file WHERE bucket = ?1 AND path = ?2")
-            .bind(bucket)
-            .bind(path)
+    async fn release_leases(&self, owner: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM lease WHERE owner = ?1")
+            .bind(owner)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,211 @@
+use crate::quota::QuotaConfig;
+use serde::Serialize;
+use sqlx::FromRow;
+
+/**
+ * Totals over the blobs of a bucket that are still referenced. Blobs
+ * stored before sizes were recorded are missing from them.
+ */
+#[derive(Debug, FromRow)]
+pub struct BlobTotals {
+    /// Bytes stored once per blob
+    pub physical_bytes: i64,
+    /// Bytes counted once per referencing path
+    pub logical_bytes: i64,
+}
+
+/**
+ * What a bucket stores, kept up to date on every upload.
+ * Blobs of unknown size are not included.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, FromRow)]
+pub struct Usage {
+    pub logical_bytes: i64,
+    pub physical_bytes: i64,
+    pub paths: i64,
+}
+
+impl Usage {
+    pub fn is_zero(&self) -> bool {
+        *self == Usage::default()
+    }
+
+    pub fn negated(&self) -> Usage {
+        Usage {
+            logical_bytes: -self.logical_bytes,
+            physical_bytes: -self.physical_bytes,
+            paths: -self.paths,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct SharedBlob {
+    pub hash: String,
+    pub refcount: i32,
+    pub size: Option<i64>,
+}
+
+/**
+ * Number of blobs with a reference count between min and max, inclusive.
+ */
+#[derive(Debug, Serialize)]
+pub struct RefcountRange {
+    pub min: i32,
+    pub max: i32,
+    pub blobs: i64,
+}
+
+/**
+ * Statistics of a bucket. Paths and bytes are the tracked usage, which the
+ * quota applies to and `fsck --repair` corrects.
+ */
+#[derive(Debug, Serialize)]
+pub struct BucketStats {
+    pub bucket: String,
+    pub paths: i64,
+    pub unique_blobs: i64,
+    pub logical_bytes: i64,
+    pub physical_bytes: i64,
+    /// Logical bytes per physical byte, absent while nothing is stored
+    pub dedup_ratio: Option<f64>,
+    pub refcount_distribution: Vec<RefcountRange>,
+    pub most_shared: Vec<SharedBlob>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaConfig>,
+}
+
+impl BucketStats {
+    pub fn new(
+        bucket: &str,
+        usage: Usage,
+        by_refcount: Vec<(i32, i64)>,
+        most_shared: Vec<SharedBlob>,
+    ) -> Self {
+        let dedup_ratio = (usage.physical_bytes > 0)
+            .then(|| usage.logical_bytes as f64 / usage.physical_bytes as f64);
+        BucketStats {
+            bucket: bucket.to_string(),
+            paths: usage.paths,
+            unique_blobs: by_refcount.iter().map(|(_, blobs)| blobs).sum(),
+            logical_bytes: usage.logical_bytes,
+            physical_bytes: usage.physical_bytes,
+            dedup_ratio,
+            refcount_distribution: refcount_ranges(by_refcount),
+            most_shared,
+            quota: None,
+        }
+    }
+}
+
+/**
+ * Group blob counts per reference count into the ranges 1, 2, 3-4, 5-8, ...
+ */
+fn refcount_ranges(by_refcount: Vec<(i32, i64)>) -> Vec<RefcountRange> {
+    let mut ranges: Vec<RefcountRange> = vec![];
+    let mut by_refcount = by_refcount;
+    by_refcount.sort();
+    for (refcount, blobs) in by_refcount {
+        let max = (refcount as u32).next_power_of_two() as i32;
+        let min = if max <= 2 { max } else { max / 2 + 1 };
+        match ranges.last_mut() {
+            Some(range) if range.max == max => range.blobs += blobs,
+            _ => ranges.push(RefcountRange { min, max, blobs }),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    #[test]
+    fn groups_refcounts_by_powers_of_two() {
+        let ranges = refcount_ranges(vec![(5, 1), (1, 10), (2, 4), (3, 2), (4, 1), (9, 1)]);
+        let ranges: Vec<(i32, i32, i64)> = ranges
+            .iter()
+            .map(|range| (range.min, range.max, range.blobs))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(1, 1, 10), (2, 2, 4), (3, 4, 3), (5, 8, 1), (9, 16, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_totals_from_tracked_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+        let kvstorage = &state.kvstorage;
+        for (hash, refcount) in [("a", 1), ("b", 3), ("c", 2), ("gone", 0)] {
+            kvstorage
+                .set_ref_count("test", hash, refcount)
+                .await
+                .unwrap();
+            kvstorage.set_blob_size("test", hash, 10).await.unwrap();
+        }
+        // Differs from what counting would find, as only fsck counts
+        let usage = Usage {
+            logical_bytes: 600,
+            physical_bytes: 200,
+            paths: 7,
+        };
+        kvstorage.set_usage("test", &usage).await.unwrap();
+
+        let stats = kvstorage.stats("test", 2).await.unwrap();
+        assert_eq!(
+            (stats.paths, stats.logical_bytes, stats.physical_bytes),
+            (7, 600, 200)
+        );
+        assert_eq!(stats.dedup_ratio, Some(3.0));
+        assert_eq!(stats.unique_blobs, 3);
+        let most_shared: Vec<(&str, i32)> = stats
+            .most_shared
+            .iter()
+            .map(|blob| (blob.hash.as_str(), blob.refcount))
+            .collect();
+        assert_eq!(most_shared, [("b", 3), ("c", 2)]);
+    }
+
+    #[tokio::test]
+    async fn empty_bucket_has_no_dedup_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+        let stats = state.kvstorage.stats("test", 10).await.unwrap();
+        assert_eq!((stats.paths, stats.unique_blobs), (0, 0));
+        assert_eq!(stats.dedup_ratio, None);
+        assert!(stats.refcount_distribution.is_empty());
+    }
+
+    #[tokio::test]
+    async fn init_counts_usage_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({}));
+        let state = testing::app_state(&config).await;
+        let kvstorage = &state.kvstorage;
+        kvstorage.set_ref_count("test", "a", 2).await.unwrap();
+        kvstorage.set_blob_size("test", "a", 10).await.unwrap();
+        for path in ["x", "y"] {
+            kvstorage.set_ref_file("test", path, "a").await.unwrap();
+        }
+
+        // Tracked already, so uploads keep adding to it
+        kvstorage.init_usage("test").await.unwrap();
+        let tracked = kvstorage.get_usage("test").await.unwrap().unwrap();
+        assert_eq!(tracked, Usage::default());
+
+        let counted = Usage {
+            logical_bytes: 20,
+            physical_bytes: 10,
+            paths: 2,
+        };
+        assert_eq!(kvstorage.count_usage("test").await.unwrap(), counted);
+        kvstorage.init_usage("other").await.unwrap();
+        assert!(!kvstorage.insert_usage("other", &counted).await.unwrap());
+        kvstorage.add_usage("untracked", &counted).await.unwrap();
+        assert_eq!(kvstorage.get_usage("untracked").await.unwrap(), None);
+    }
+}
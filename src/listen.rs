@@ -0,0 +1,288 @@
+use crate::tls::TlsListener;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tracing::{info, warn};
+
+/// Prefix of addresses that are Unix socket paths
+const UNIX_PREFIX: &str = "unix:";
+
+/**
+ * Address of the peer of a connection, added to every request on it.
+ * Peers on Unix sockets have no IP address.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+/**
+ * Address type of a listener, telling the IP address of peers.
+ */
+pub trait PeerAddr {
+    fn ip(&self) -> Option<IpAddr>;
+}
+
+impl PeerAddr for SocketAddr {
+    fn ip(&self) -> Option<IpAddr> {
+        Some(SocketAddr::ip(self))
+    }
+}
+
+impl PeerAddr for tokio::net::unix::SocketAddr {
+    fn ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientAddr(PeerAddr::ip(stream.remote_addr()))
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, UnixListener>) -> Self {
+        ClientAddr(stream.remote_addr().ip())
+    }
+}
+
+impl<L> Connected<IncomingStream<'_, TlsListener<L>>> for ClientAddr
+where
+    L: Listener,
+    L::Addr: PeerAddr + fmt::Debug,
+{
+    fn connect_info(stream: IncomingStream<'_, TlsListener<L>>) -> Self {
+        ClientAddr(PeerAddr::ip(stream.remote_addr()))
+    }
+}
+
+/**
+ * Address a listener is bound to.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /**
+     * Parse an IP address with its port, or `unix:` followed by a socket path.
+     */
+    pub fn parse(address: &str, port: u16) -> Result<Self, String> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err("socket path must not be empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let ip = address
+            .parse::<IpAddr>()
+            .map_err(|_| "must be an IP address or unix:<path>".to_string())?;
+        Ok(ListenAddr::Tcp(SocketAddr::new(ip, port)))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnixSocketConfig {
+    /// Octal permissions of the socket file, e.g. "660"
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Numeric user id owning the socket file
+    #[serde(default)]
+    pub owner: Option<u32>,
+    /// Numeric group id owning the socket file
+    #[serde(default)]
+    pub group: Option<u32>,
+}
+
+impl UnixSocketConfig {
+    pub fn parse_mode(&self) -> Result<Option<u32>, String> {
+        self.mode
+            .as_deref()
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| format!("{} is not an octal file mode", mode))
+            })
+            .transpose()
+    }
+}
+
+/**
+ * Bind a Unix socket at path, replacing a socket file left behind by
+ * a process that is gone, with the configured permissions and ownership.
+ *
+ * The socket is bound in a private directory and linked into place once
+ * set up, so that it is never reachable with other permissions.
+ */
+pub async fn bind_unix(path: &Path, config: &UnixSocketConfig) -> io::Result<UnixListener> {
+    let mode = config
+        .parse_mode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    remove_stale_socket(path).await?;
+
+    let private = private_dir(path)?;
+    let bound = bind_private(path, &private, mode, config);
+    if let Err(e) = std::fs::remove_dir_all(&private) {
+        warn!("Failed to remove directory: {}: {}", private.display(), e);
+    }
+    bound
+}
+
+/**
+ * Directory next to path only accessible to this process's user, so that
+ * linking from it stays on the same filesystem.
+ */
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = parent.join(format!(".{}.{}", name, std::process::id()));
+    // Left behind by a crashed process that had the same id
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+fn bind_private(
+    path: &Path,
+    private: &Path,
+    mode: Option<u32>,
+    config: &UnixSocketConfig,
+) -> io::Result<UnixListener> {
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+    }
+    if config.owner.is_some() || config.group.is_some() {
+        std::os::unix::fs::chown(&bound, config.owner, config.group)?;
+    }
+    // Unlike renaming, fails instead of replacing a socket bound meanwhile
+    std::fs::hard_link(&bound, path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(listener)
+}
+
+async fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // Never remove anything that is not a socket
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    // A socket that accepts connections is still in use
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        ));
+    }
+    info!("Removing stale socket: {}", path.display());
+    std::fs::remove_file(path)
+}
+
+/**
+ * Remove the socket file of a stopped listener.
+ */
+pub fn remove_socket(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove socket: {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: Option<&str>) -> UnixSocketConfig {
+        UnixSocketConfig {
+            mode: mode.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            ListenAddr::parse("unix:/run/s3dedup.sock", 80),
+            Ok(ListenAddr::Unix(PathBuf::from("/run/s3dedup.sock")))
+        );
+        assert_eq!(
+            ListenAddr::parse("::1", 80),
+            Ok(ListenAddr::Tcp("[::1]:80".parse().unwrap()))
+        );
+        assert!(ListenAddr::parse("unix:", 80).is_err());
+        assert!(ListenAddr::parse("localhost", 80).is_err());
+        assert_eq!(config(Some("0o660")).parse_mode(), Ok(Some(0o660)));
+        assert!(config(Some("9")).parse_mode().is_err());
+    }
+
+    #[tokio::test]
+    async fn binds_with_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s3dedup.sock");
+
+        let listener = bind_unix(&path, &config(Some("600"))).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(entries, 1, "private directory left behind");
+
+        let client = tokio::spawn(UnixStream::connect(path));
+        listener.accept().await.unwrap();
+        client.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_sockets_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s3dedup.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = bind_unix(&path, &config(None)).await.unwrap();
+        let in_use = bind_unix(&path, &config(None)).await.unwrap_err();
+        assert_eq!(in_use.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        bind_unix(&path, &config(None)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_removes_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s3dedup.sock");
+        std::fs::write(&path, "data").unwrap();
+
+        let error = bind_unix(&path, &config(None)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+}
@@ -0,0 +1,323 @@
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use crate::kvstorage::KVStorage;
+use crate::locks::Locks;
+use crate::locks::tracker::LockTracker;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileLocksConfig {
+    pub path: String,
+}
+
+/// First wait before retrying a lock held by someone else
+const RETRY_MIN: Duration = Duration::from_millis(1);
+/// Longest wait between retries
+const RETRY_MAX: Duration = Duration::from_millis(100);
+
+/**
+ * Locks taken with flock(2) on one file per key, so that they are shared
+ * by all processes on the host using the same directory.
+ *
+ * The last holder of a lock removes its file on release, while still
+ * holding the lock. Whoever opened the file before it was removed notices
+ * once it gets the lock, as the path then no longer refers to the locked
+ * file, and starts over.
+ */
+#[derive(Clone)]
+pub(crate) struct FileLocks {
+    dir: PathBuf,
+    held: Arc<Mutex<HashMap<String, Vec<File>>>>,
+    tracker: LockTracker,
+}
+
+impl FileLocks {
+    /**
+     * Keys contain arbitrary paths, so they are hashed into safe file names.
+     */
+    fn lock_path(&self, key: &str) -> PathBuf {
+        let name = hex::encode(Sha256::digest(key.as_bytes()));
+        self.dir.join(format!("{}.lock", name))
+    }
+
+    async fn acquire(&self, key: &str, exclusive: bool) -> Result<(), AppError> {
+        let path = self.lock_path(key);
+        let file = loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            // Retried without blocking, so that waiting takes no thread
+            let mut retry = RETRY_MIN;
+            loop {
+                let locked = if exclusive {
+                    file.try_lock()
+                } else {
+                    file.try_lock_shared()
+                };
+                match locked {
+                    Ok(()) => break,
+                    Err(TryLockError::WouldBlock) => {
+                        tokio::time::sleep(retry).await;
+                        retry = (retry * 2).min(RETRY_MAX);
+                    }
+                    Err(TryLockError::Error(e)) => return Err(e.into()),
+                }
+            }
+            if is_current(&file, &path)? {
+                break file;
+            }
+            debug!("Lock file of key: {} was removed, retrying", key);
+        };
+
+        let mut held = self.held.lock().unwrap();
+        held.entry(key.to_string()).or_default().push(file);
+        Ok(())
+    }
+}
+
+/**
+ * Whether path still refers to the opened file.
+ */
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    let opened = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == opened.dev() && current.ino() == opened.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/**
+ * Remove the lock file of file and release its lock, unless someone else
+ * holds the lock as well.
+ */
+fn remove_unused(file: File, path: &Path) {
+    // Turns a shared lock into an exclusive one if no one else holds it.
+    // If that fails, the shared lock may be gone, which is fine on release.
+    if file.try_lock().is_ok()
+        && let Err(e) = std::fs::remove_file(path)
+    {
+        warn!("Failed to remove lock file: {}: {}", path.display(), e);
+    }
+    // Closing the file releases its lock
+    drop(file);
+}
+
+impl Locks for FileLocks {
+    fn new(config: &BucketConfig, _kvstorage: &KVStorage) -> Result<Box<Self>, AppError> {
+        let file_config = config.file_locks.as_ref().ok_or_else(|| {
+            AppError::Validation(format!(
+                "Missing file_locks config for bucket: {}",
+                config.name
+            ))
+        })?;
+        let dir = PathBuf::from(&file_config.path);
+        debug!("Using lock directory: {}", dir.display());
+        std::fs::create_dir_all(&dir)?;
+        Ok(Box::new(FileLocks {
+            dir,
+            held: Arc::new(Mutex::new(HashMap::new())),
+            tracker: LockTracker::new(config),
+        }))
+    }
+
+    async fn acquire_shared(&self, key: &str) -> Result<(), AppError> {
+        self.acquire(key, false).await
+    }
+
+    async fn acquire_exclusive(&self, key: &str) -> Result<(), AppError> {
+        self.acquire(key, true).await
+    }
+
+    fn release(&self, key: &str) -> bool {
+        let mut held = self.held.lock().unwrap();
+        let Some(files) = held.get_mut(key) else {
+            return false;
+        };
+        let Some(file) = files.pop() else {
+            return false;
+        };
+        if files.is_empty() {
+            held.remove(key);
+        }
+        drop(held);
+        remove_unused(file, &self.lock_path(key));
+        true
+    }
+
+    fn tracker(&self) -> &LockTracker {
+        &self.tracker
+    }
+
+    async fn close(&self) {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        for (key, files) in held {
+            for file in files {
+                remove_unused(file, &self.lock_path(&key));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::locks::{self, LocksStorage};
+    use crate::testing;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read};
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    /// Lock directory of the process started by holds_lock_in_other_process
+    const CHILD_DIR: &str = "S3DEDUP_TEST_FILE_LOCKS_DIR";
+
+    async fn locks(dir: &Path, lock_dir: &Path) -> LocksStorage {
+        let config = testing::bucket_config(
+            dir,
+            json!({
+                "locks_type": "file",
+                "file_locks": {"path": lock_dir.to_str().unwrap()},
+                "locks_timeout_secs": 1
+            }),
+        );
+        *testing::app_state(&config).await.locks.clone()
+    }
+
+    fn lock_files(lock_dir: &Path) -> usize {
+        std::fs::read_dir(lock_dir).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn exclusive_lock_excludes_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_dir = dir.path().join("locks");
+        let locks = locks(dir.path(), &lock_dir).await;
+        let key = locks::file_lock("test", "a");
+
+        locks.acquire_exclusive(&key).await.unwrap();
+        let other = tokio::spawn({
+            let (locks, key) = (locks.clone(), key.clone());
+            async move { locks.acquire_shared(&key).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!other.is_finished());
+        assert!(locks.release(&key));
+        other.await.unwrap().unwrap();
+        assert!(locks.release(&key));
+    }
+
+    #[tokio::test]
+    async fn removes_lock_files_once_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_dir = dir.path().join("locks");
+        let locks = locks(dir.path(), &lock_dir).await;
+        let key = locks::hash_lock("test", "x");
+
+        locks.acquire_shared(&key).await.unwrap();
+        locks.acquire_shared(&key).await.unwrap();
+        assert!(locks.release(&key));
+        assert_eq!(lock_files(&lock_dir), 1, "removed while still held");
+        assert!(locks.release(&key));
+        assert_eq!(lock_files(&lock_dir), 0);
+        assert!(!locks.release(&key));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn removing_lock_files_keeps_exclusion() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_dir = dir.path().join("locks");
+        let locks = locks(dir.path(), &lock_dir).await;
+        let holders = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (locks, holders) = (locks.clone(), holders.clone());
+                tokio::spawn(async move {
+                    let key = locks::file_lock("test", "a");
+                    for _ in 0..20 {
+                        locks.acquire_exclusive(&key).await.unwrap();
+                        let others = holders.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        assert_eq!(others, 0, "lock held twice");
+                        tokio::task::yield_now().await;
+                        holders.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                        locks.release(&key);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(lock_files(&lock_dir), 0);
+    }
+
+    /**
+     * Run by holds_lock_in_other_process in a separate process: hold the
+     * lock until stdin is closed.
+     */
+    #[tokio::test]
+    async fn child_holds_lock() {
+        let Ok(lock_dir) = std::env::var(CHILD_DIR) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let locks = locks(dir.path(), Path::new(&lock_dir)).await;
+        let key = locks::file_lock("test", "a");
+        locks.acquire_exclusive(&key).await.unwrap();
+        println!("locked");
+        std::io::stdin().read_to_end(&mut Vec::new()).unwrap();
+        locks.release(&key);
+    }
+
+    #[tokio::test]
+    async fn holds_lock_in_other_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_dir = dir.path().join("locks");
+        let locks = locks(dir.path(), &lock_dir).await;
+        let key = locks::file_lock("test", "a");
+
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "locks::file::tests::child_holds_lock",
+                "--nocapture",
+            ])
+            .env(CHILD_DIR, &lock_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let locked = lines
+            .by_ref()
+            .map(Result::unwrap)
+            // The test harness may print the test name on the same line
+            .any(|line| line.ends_with("locked"));
+        assert!(locked, "other process did not take the lock");
+
+        let result = locks.acquire_exclusive(&key).await;
+        assert!(matches!(
+            result,
+            Err(crate::error::AppError::LockTimeout(_))
+        ));
+
+        drop(child.stdin.take());
+        // Read to the end, so that the harness can report the result
+        lines.for_each(drop);
+        assert!(child.wait().unwrap().success());
+        locks.acquire_exclusive(&key).await.unwrap();
+        locks.release(&key);
+        assert_eq!(lock_files(&lock_dir), 0);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use crate::kvstorage::KVStorage;
+use crate::locks::tracker::LockTracker;
+use crate::locks::{Fence, Locks};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseLocksConfig {
+    /// How long a lease stays valid without being renewed, at least 3
+    /// seconds as leases are renewed every third of it
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// How long to wait before retrying to take a lease held by someone else
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    30
+}
+
+fn default_retry_interval_ms() -> u64 {
+    50
+}
+
+impl Default for LeaseLocksConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_ttl_secs(),
+            retry_interval_ms: default_retry_interval_ms(),
+        }
+    }
+}
+
+/**
+ * Locks stored as lease rows in the bucket's KV storage, so that they are
+ * shared by all replicas using the same database.
+ *
+ * Held leases are renewed in the background. A lease that is not renewed,
+ * e.g. because its replica crashed, expires and can be taken over. A holder
+ * whose lease was taken over finds out from the `LockLost` error of its next
+ * write fenced with the lease.
+ * Leases are exclusive, so shared locks are taken as exclusive ones.
+ */
+#[derive(Clone)]
+pub(crate) struct LeaseLocks {
+    kvstorage: KVStorage,
+    bucket: String,
+    owner: String,
+    ttl: Duration,
+    retry_interval: Duration,
+    held: Arc<Mutex<HashMap<String, HeldLease>>>,
+    tracker: LockTracker,
+}
+
+struct HeldLease {
+    token: String,
+    /// Whether renewing found the lease taken over
+    lost: bool,
+}
+
+impl LeaseLocks {
+    fn expires_at(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis() + self.ttl.as_millis() as i64
+    }
+
+    async fn acquire(&self, key: &str) -> Result<(), AppError> {
+        loop {
+            // A lease lost by a request of this process is only taken again
+            // once that request released it
+            if !self.held.lock().unwrap().contains_key(key) {
+                let token = Uuid::new_v4().to_string();
+                let now = chrono::Utc::now().timestamp_millis();
+                if self
+                    .kvstorage
+                    .try_acquire_lease(
+                        &self.bucket,
+                        key,
+                        &self.owner,
+                        &token,
+                        now,
+                        self.expires_at(),
+                    )
+                    .await?
+                {
+                    let held = HeldLease { token, lost: false };
+                    self.held.lock().unwrap().insert(key.to_string(), held);
+                    return Ok(());
+                }
+            }
+            tokio::time::sleep(self.retry_interval).await;
+        }
+    }
+
+    /**
+     * Fence for writes made under the held lease on key.
+     */
+    pub fn fence(&self, key: &str) -> Result<Fence, AppError> {
+        match self.held.lock().unwrap().get(key) {
+            Some(held) if !held.lost => Ok(Fence {
+                key: key.to_string(),
+                token: held.token.clone(),
+            }),
+            _ => Err(AppError::LockLost(key.to_string())),
+        }
+    }
+
+    /**
+     * Renew all held leases, marking those taken over as lost.
+     */
+    async fn renew(&self) {
+        let leases: Vec<(String, String)> = self
+            .held
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, held)| !held.lost)
+            .map(|(key, held)| (key.clone(), held.token.clone()))
+            .collect();
+        for (key, token) in leases {
+            match self
+                .kvstorage
+                .renew_lease(&self.bucket, &key, &token, self.expires_at())
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Lease for key: {} was lost before renewal", key);
+                    if let Some(held) = self.held.lock().unwrap().get_mut(&key)
+                        && held.token == token
+                    {
+                        held.lost = true;
+                    }
+                }
+                Err(e) => error!("Failed to renew lease for key: {}: {}", key, e),
+            }
+        }
+    }
+
+    /**
+     * Renew all held leases well before they expire, until the locks
+     * are dropped, e.g. because their bucket was removed.
+     */
+    async fn renew_leases(self) {
+        let mut interval = tokio::time::interval(self.ttl / 3);
+        loop {
+            interval.tick().await;
+            if Arc::strong_count(&self.held) == 1 {
+                return;
+            }
+            self.renew().await;
+        }
+    }
+}
+
+impl Locks for LeaseLocks {
+    fn new(config: &BucketConfig, kvstorage: &KVStorage) -> Result<Box<Self>, AppError> {
+        let lease_config = config.lease_locks.clone().unwrap_or_default();
+        let owner = Uuid::new_v4().to_string();
+        debug!("Using lease owner: {}", owner);
+        let locks = LeaseLocks {
+            kvstorage: kvstorage.clone(),
+            bucket: config.name.clone(),
+            owner,
+            ttl: Duration::from_secs(lease_config.ttl_secs),
+            retry_interval: Duration::from_millis(lease_config.retry_interval_ms),
+            held: Arc::new(Mutex::new(HashMap::new())),
+            tracker: LockTracker::new(config),
+        };
+        tokio::spawn(locks.clone().renew_leases());
+        Ok(Box::new(locks))
+    }
+
+    async fn acquire_shared(&self, key: &str) -> Result<(), AppError> {
+        self.acquire(key).await
+    }
+
+    async fn acquire_exclusive(&self, key: &str) -> Result<(), AppError> {
+        self.acquire(key).await
+    }
+
+    fn release(&self, key: &str) -> bool {
+        let Some(held) = self.held.lock().unwrap().remove(key) else {
+            return false;
+        };
+        if held.lost {
+            return true;
+        }
+        let (kvstorage, bucket) = (self.kvstorage.clone(), self.bucket.clone());
+        let key = key.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = kvstorage.release_lease(&bucket, &key, &held.token).await {
+                // The lease will expire on its own
+                error!("Failed to release lease for key: {}: {}", key, e);
+            }
+        });
+        true
+    }
+
+    fn tracker(&self) -> &LockTracker {
+        &self.tracker
+    }
+
+    async fn close(&self) {
+        self.held.lock().unwrap().clear();
+        // Also covers releases still running in the background
+        if let Err(e) = self
+            .kvstorage
+            .release_leases(&self.bucket, &self.owner)
+            .await
+        {
+            // The leases will expire on their own
+            error!("Failed to release leases of owner: {}: {}", self.owner, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locks::{self, LocksStorage};
+    use crate::testing;
+    use serde_json::json;
+
+    async fn state(dir: &std::path::Path) -> Arc<crate::AppState> {
+        let config = testing::bucket_config(dir, json!({"locks_type": "lease"}));
+        testing::app_state(&config).await
+    }
+
+    fn leases(locks: &LocksStorage) -> &LeaseLocks {
+        match locks {
+            LocksStorage::Lease(leases) => leases,
+            _ => unreachable!(),
+        }
+    }
+
+    /**
+     * Let another replica take over the lease on key, as if it expired.
+     */
+    async fn take_over(kvstorage: &KVStorage, key: &str) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let taken = kvstorage
+            .try_acquire_lease(
+                "test",
+                key,
+                "other",
+                "other-token",
+                now + 60_000,
+                now + 90_000,
+            )
+            .await
+            .unwrap();
+        assert!(taken);
+    }
+
+    #[tokio::test]
+    async fn fenced_writes_succeed_while_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path()).await;
+        let key = locks::hash_lock("test", "x");
+
+        state.locks.acquire_exclusive(&key).await.unwrap();
+        let fence = state.locks.fence(&key).unwrap();
+        assert!(fence.is_some());
+        state
+            .kvstorage
+            .increment_ref_count("test", "x", fence.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(state.kvstorage.get_ref_count("test", "x").await.unwrap(), 1);
+        state.locks.release(&key);
+    }
+
+    #[tokio::test]
+    async fn fenced_writes_fail_once_lease_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path()).await;
+        let key = locks::hash_lock("test", "x");
+
+        state.locks.acquire_exclusive(&key).await.unwrap();
+        let fence = state.locks.fence(&key).unwrap();
+        take_over(&state.kvstorage, &key).await;
+
+        let result = state
+            .kvstorage
+            .increment_ref_count("test", "x", fence.as_ref())
+            .await;
+        assert!(matches!(result, Err(AppError::LockLost(_))));
+        assert_eq!(state.kvstorage.get_ref_count("test", "x").await.unwrap(), 0);
+        state.locks.release(&key);
+    }
+
+    #[tokio::test]
+    async fn renewal_marks_lease_taken_over_as_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path()).await;
+        let key = locks::hash_lock("test", "x");
+
+        state.locks.acquire_exclusive(&key).await.unwrap();
+        take_over(&state.kvstorage, &key).await;
+        leases(&state.locks).renew().await;
+        assert!(matches!(
+            state.locks.fence(&key),
+            Err(AppError::LockLost(_))
+        ));
+
+        // Releasing a lost lease leaves the new holder's lease alone
+        assert!(state.locks.release(&key));
+        tokio::task::yield_now().await;
+        let renewed = state
+            .kvstorage
+            .renew_lease("test", &key, "other-token", i64::MAX)
+            .await
+            .unwrap();
+        assert!(renewed);
+    }
+
+    #[tokio::test]
+    async fn leases_exclude_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path()).await;
+        let key = locks::file_lock("test", "a");
+
+        state.locks.acquire_shared(&key).await.unwrap();
+        let other = tokio::spawn({
+            let state = state.clone();
+            let key = key.clone();
+            async move { state.locks.acquire_shared(&key).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!other.is_finished(), "shared leases are exclusive");
+        state.locks.release(&key);
+        other.await.unwrap().unwrap();
+    }
+}
@@ -1,42 +1,199 @@
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use crate::kvstorage::KVStorage;
 use crate::locks::Locks;
+use crate::locks::tracker::LockTracker;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
 
-type LockMap = Arc<RwLock<HashMap<String, Arc<RwLock<()>>>>>;
+/**
+ * Lock for a single key together with the guards of its current holders.
+ */
+struct LockEntry {
+    lock: Arc<RwLock<()>>,
+    shared: Vec<OwnedRwLockReadGuard<()>>,
+    exclusive: Option<OwnedRwLockWriteGuard<()>>,
+}
+
+type LockMap = Arc<Mutex<HashMap<String, LockEntry>>>;
 #[derive(Clone)]
 pub(crate) struct MemoryLocks {
     locks: LockMap,
+    tracker: LockTracker,
+}
+
+/**
+ * Reference to the lock of a key held while waiting for it. Dropping the
+ * last reference removes the entry, also when the wait is given up.
+ */
+struct LockRef {
+    locks: LockMap,
+    key: String,
+    lock: Option<Arc<RwLock<()>>>,
+}
+
+impl LockRef {
+    fn lock(&self) -> Arc<RwLock<()>> {
+        self.lock.clone().unwrap()
+    }
+}
+
+impl Drop for LockRef {
+    fn drop(&mut self) {
+        let lock = self.lock.take().unwrap();
+        let mut locks = self.locks.lock().unwrap();
+        drop(lock);
+        remove_if_unused(&mut locks, &self.key);
+    }
+}
+
+/**
+ * Remove the entry of key once nobody holds or waits for its lock anymore.
+ * Guards hold a reference to the lock too.
+ */
+fn remove_if_unused(locks: &mut HashMap<String, LockEntry>, key: &str) {
+    if locks
+        .get(key)
+        .is_some_and(|entry| Arc::strong_count(&entry.lock) == 1)
+    {
+        locks.remove(key);
+    }
 }
 
 impl MemoryLocks {
-    fn get_or_create_lock(&self, key: &str) -> Arc<RwLock<()>> {
-        let mut locks = self.locks.write().unwrap();
-        locks
+    fn get_or_create_lock(&self, key: &str) -> LockRef {
+        let mut locks = self.locks.lock().unwrap();
+        let lock = locks
             .entry(key.to_string())
-            .or_insert_with(|| Arc::new(RwLock::new(())))
-            .clone()
+            .or_insert_with(|| LockEntry {
+                lock: Arc::new(RwLock::new(())),
+                shared: Vec::new(),
+                exclusive: None,
+            })
+            .lock
+            .clone();
+        LockRef {
+            locks: self.locks.clone(),
+            key: key.to_string(),
+            lock: Some(lock),
+        }
     }
 }
 
 impl Locks for MemoryLocks {
-    fn new() -> Box<Self> {
-        Box::new(Self {
-            locks: Arc::new(RwLock::new(HashMap::new())),
-        })
+    fn new(config: &BucketConfig, _kvstorage: &KVStorage) -> Result<Box<Self>, AppError> {
+        Ok(Box::new(Self {
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            tracker: LockTracker::new(config),
+        }))
     }
 
-    fn acquire_shared(&mut self, key: &str) {
+    async fn acquire_shared(&self, key: &str) -> Result<(), AppError> {
         let lock = self.get_or_create_lock(key);
-        let _guard = lock.read().unwrap();
+        let guard = lock.lock().read_owned().await;
+        let mut locks = self.locks.lock().unwrap();
+        // The entry cannot be removed while we hold a reference to its lock
+        locks.get_mut(key).unwrap().shared.push(guard);
+        Ok(())
     }
 
-    fn acquire_exclusive(&mut self, key: &str) {
+    async fn acquire_exclusive(&self, key: &str) -> Result<(), AppError> {
         let lock = self.get_or_create_lock(key);
-        let _guard = lock.write().unwrap();
+        let guard = lock.lock().write_owned().await;
+        let mut locks = self.locks.lock().unwrap();
+        locks.get_mut(key).unwrap().exclusive = Some(guard);
+        Ok(())
+    }
+
+    fn release(&self, key: &str) -> bool {
+        let mut locks = self.locks.lock().unwrap();
+        let Some(entry) = locks.get_mut(key) else {
+            return false;
+        };
+        let released = if let Some(guard) = entry.exclusive.take() {
+            drop(guard);
+            true
+        } else if let Some(guard) = entry.shared.pop() {
+            drop(guard);
+            true
+        } else {
+            false
+        };
+        remove_if_unused(&mut locks, key);
+        released
+    }
+
+    fn tracker(&self) -> &LockTracker {
+        &self.tracker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn memory_locks() -> MemoryLocks {
+        let dir = tempfile::tempdir().unwrap();
+        MemoryLocks {
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            tracker: LockTracker::new(&testing::bucket_config(dir.path(), json!({}))),
+        }
+    }
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn exclusive_lock_excludes_others() {
+        let locks = memory_locks();
+        locks.acquire_exclusive("key").await.unwrap();
+        assert!(timeout(WAIT, locks.acquire_exclusive("key")).await.is_err());
+        assert!(timeout(WAIT, locks.acquire_shared("key")).await.is_err());
+        assert!(locks.release("key"));
+        timeout(WAIT, locks.acquire_shared("key"))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_locks_exclude_only_exclusive_ones() {
+        let locks = memory_locks();
+        locks.acquire_shared("key").await.unwrap();
+        timeout(WAIT, locks.acquire_shared("key"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(timeout(WAIT, locks.acquire_exclusive("key")).await.is_err());
+        assert!(locks.release("key"));
+        assert!(timeout(WAIT, locks.acquire_exclusive("key")).await.is_err());
+        assert!(locks.release("key"));
+        timeout(WAIT, locks.acquire_exclusive("key"))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn releasing_unheld_lock_returns_false() {
+        let locks = memory_locks();
+        assert!(!locks.release("key"));
     }
 
-    fn release(&mut self, key: &str) -> bool {
-        let mut locks = self.locks.write().unwrap();
-        locks.remove(key).is_some()
+    #[tokio::test]
+    async fn removes_entries_once_unused() {
+        let locks = memory_locks();
+        locks.acquire_exclusive("key").await.unwrap();
+        // Gives up waiting, like a request running into the lock timeout
+        assert!(timeout(WAIT, locks.acquire_exclusive("key")).await.is_err());
+        assert!(timeout(WAIT, locks.acquire_shared("other")).await.is_ok());
+        locks.release("other");
+        assert_eq!(locks.locks.lock().unwrap().len(), 1);
+        locks.release("key");
+        assert!(locks.locks.lock().unwrap().is_empty());
     }
 }
@@ -1,7 +1,28 @@
-use serde::Deserialize;
-use tracing::{debug, info};
+use crate::config::BucketConfig;
+use crate::error::AppError;
+use crate::kvstorage::KVStorage;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{Instrument, debug, info, info_span};
+use tracker::{LockInfo, LockMode, LockTracker, Waiting};
 
+pub mod file;
+pub mod lease;
 pub mod memory;
+#[cfg(debug_assertions)]
+mod ordering;
+pub mod tracker;
+
+/*
+ * Lock ordering
+ *
+ * A request may acquire a hash lock while holding a file lock, but never
+ * a file lock while holding a hash lock. Every refcount transition and every
+ * blob put or delete happens under a hash lock, which is usually taken
+ * while the file lock of the path being relinked is held. Acquiring them in
+ * the opposite order could deadlock two requests relinking the same path
+ * and hash. The order is enforced in debug builds.
+ */
 
 /**
  * Get key for lock on file
@@ -13,72 +34,199 @@ pub(crate) fn file_lock(bucket: &str, path: &str) -> String {
 /**
  * Get key for lock on hash
  */
-fn hash_lock(bucket: &str, hash: &str) -> String {
+pub(crate) fn hash_lock(bucket: &str, hash: &str) -> String {
     format!("hash:{}:{}", bucket, hash)
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/**
+ * Proof that a lock is still held, for writes to the KV storage made under
+ * it. Only leases can be lost while held, so only they have one.
+ */
+#[derive(Debug, Clone)]
+pub struct Fence {
+    pub key: String,
+    /// Unique to one acquisition of the lease
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub(crate) enum LocksType {
     #[serde(rename = "memory")]
     Memory,
+    #[serde(rename = "file")]
+    File,
+    #[serde(rename = "lease")]
+    Lease,
 }
 
 pub(crate) trait Locks {
-    fn new() -> Box<Self>
+    fn new(config: &BucketConfig, kvstorage: &KVStorage) -> Result<Box<Self>, AppError>
     where
         Self: Sized;
 
-    fn acquire_shared(&mut self, key: &str);
-    fn acquire_exclusive(&mut self, key: &str);
-    fn release(&mut self, key: &str) -> bool;
+    async fn acquire_shared(&self, key: &str) -> Result<(), AppError>;
+    async fn acquire_exclusive(&self, key: &str) -> Result<(), AppError>;
+    fn release(&self, key: &str) -> bool;
+    fn tracker(&self) -> &LockTracker;
+
+    /**
+     * Release everything still held by this process, before shutting down.
+     */
+    async fn close(&self) {}
 }
 
 #[derive(Clone)]
 pub enum LocksStorage {
     Memory(memory::MemoryLocks),
+    File(file::FileLocks),
+    Lease(lease::LeaseLocks),
 }
 
 impl LocksStorage {
-    pub fn new(lock_type: &LocksType) -> Box<Self> {
-        match lock_type {
+    pub fn new(config: &BucketConfig, kvstorage: &KVStorage) -> Result<Box<Self>, AppError> {
+        match config.locks_type {
             LocksType::Memory => {
                 info!("Using memory as locks storage");
-                Box::new(LocksStorage::Memory(*memory::MemoryLocks::new()))
-            },
+                let locks = memory::MemoryLocks::new(config, kvstorage)?;
+                Ok(Box::new(LocksStorage::Memory(*locks)))
+            }
+            LocksType::File => {
+                info!("Using files as locks storage");
+                let locks = file::FileLocks::new(config, kvstorage)?;
+                Ok(Box::new(LocksStorage::File(*locks)))
+            }
+            LocksType::Lease => {
+                info!("Using KV storage leases as locks storage");
+                let locks = lease::LeaseLocks::new(config, kvstorage)?;
+                Ok(Box::new(LocksStorage::Lease(*locks)))
+            }
         }
     }
 
     /**
      * Acquire shared lock for key
      */
-    pub fn acquire_shared(&mut self, key: &str) {
+    pub async fn acquire_shared(&self, key: &str) -> Result<(), AppError> {
         debug!("Acquiring shared lock for key: {}", key);
-        match self {
-            LocksStorage::Memory(lock) => {
-                lock.acquire_shared(key);
+        #[cfg(debug_assertions)]
+        ordering::on_acquire(key);
+        let waiting = self.tracker().waiting(key, LockMode::Shared);
+        let acquire = async {
+            match self {
+                LocksStorage::Memory(lock) => lock.acquire_shared(key).await,
+                LocksStorage::File(lock) => lock.acquire_shared(key).await,
+                LocksStorage::Lease(lock) => lock.acquire_shared(key).await,
             }
-        }
+        };
+        let result = tokio::time::timeout(self.tracker().timeout(), acquire)
+            .instrument(info_span!("lock", key, mode = "shared"))
+            .await
+            .unwrap_or_else(|_| Err(AppError::LockTimeout(key.to_string())));
+        self.finish_acquire(key, waiting, result)
     }
 
     /**
      * Acquire exclusive lock for key
      */
-    pub fn acquire_exclusive(&mut self, key: &str) {
+    pub async fn acquire_exclusive(&self, key: &str) -> Result<(), AppError> {
         debug!("Acquiring exclusive lock for key: {}", key);
-        match self {
-            LocksStorage::Memory(lock) => {
-                lock.acquire_exclusive(key);
+        #[cfg(debug_assertions)]
+        ordering::on_acquire(key);
+        let waiting = self.tracker().waiting(key, LockMode::Exclusive);
+        let acquire = async {
+            match self {
+                LocksStorage::Memory(lock) => lock.acquire_exclusive(key).await,
+                LocksStorage::File(lock) => lock.acquire_exclusive(key).await,
+                LocksStorage::Lease(lock) => lock.acquire_exclusive(key).await,
+            }
+        };
+        let result = tokio::time::timeout(self.tracker().timeout(), acquire)
+            .instrument(info_span!("lock", key, mode = "exclusive"))
+            .await
+            .unwrap_or_else(|_| Err(AppError::LockTimeout(key.to_string())));
+        self.finish_acquire(key, waiting, result)
+    }
+
+    /**
+     * Record the outcome of an acquisition in the tracker
+     */
+    fn finish_acquire(
+        &self,
+        key: &str,
+        waiting: Waiting,
+        result: Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        match result {
+            Ok(()) => waiting.acquired(),
+            Err(ref e) => {
+                debug!("Failed to acquire lock for key: {}: {}", key, e);
+                #[cfg(debug_assertions)]
+                ordering::on_release(key);
             }
         }
+        result
     }
 
     /**
      * Release lock for key
      */
-    pub fn release(&mut self, key: &str) -> bool {
+    pub fn release(&self, key: &str) -> bool {
         debug!("Releasing lock for key: {}", key);
+        #[cfg(debug_assertions)]
+        ordering::on_release(key);
+        self.tracker().released(key);
         match self {
             LocksStorage::Memory(lock) => lock.release(key),
+            LocksStorage::File(lock) => lock.release(key),
+            LocksStorage::Lease(lock) => lock.release(key),
+        }
+    }
+
+    /**
+     * Fence for writes made under the held lock on key, if the lock can be
+     * lost while held. Fails if it is known to be lost already.
+     */
+    pub fn fence(&self, key: &str) -> Result<Option<Fence>, AppError> {
+        match self {
+            LocksStorage::Memory(_) | LocksStorage::File(_) => Ok(None),
+            LocksStorage::Lease(lock) => lock.fence(key).map(Some),
+        }
+    }
+
+    /**
+     * Release all remaining locks, once no request uses them anymore
+     */
+    pub async fn close(&self) {
+        match self {
+            LocksStorage::Memory(lock) => lock.close().await,
+            LocksStorage::File(lock) => lock.close().await,
+            LocksStorage::Lease(lock) => lock.close().await,
+        }
+    }
+
+    fn tracker(&self) -> &LockTracker {
+        match self {
+            LocksStorage::Memory(lock) => lock.tracker(),
+            LocksStorage::File(lock) => lock.tracker(),
+            LocksStorage::Lease(lock) => lock.tracker(),
+        }
+    }
+
+    /**
+     * List held and awaited locks
+     */
+    pub fn locks(&self) -> Vec<LockInfo> {
+        self.tracker().snapshot()
+    }
+
+    /**
+     * Periodically log locks held longer than threshold, never returns
+     */
+    pub async fn warn_long_holds(&self, threshold: Duration) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.tracker().warn_long_holds(threshold);
         }
     }
 }
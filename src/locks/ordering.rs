@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use tokio::task;
+
+/**
+ * Keys held or awaited by each task, used to check the lock ordering
+ * in debug builds.
+ */
+static HELD: LazyLock<Mutex<HashMap<task::Id, Vec<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Position of the key in the lock order. Locks must be acquired
+ * in non-decreasing rank.
+ */
+fn rank(key: &str) -> Option<u8> {
+    if key.starts_with("file:") {
+        Some(0)
+    } else if key.starts_with("hash:") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/**
+ * Panic if acquiring key in the current task would violate the lock ordering,
+ * otherwise record it as held.
+ */
+pub(super) fn on_acquire(key: &str) {
+    let Some(id) = task::try_id() else {
+        return;
+    };
+    let mut held = HELD.lock().unwrap();
+    let keys = held.entry(id).or_default();
+    let violated = rank(key).and_then(|key_rank| {
+        keys.iter()
+            .find(|held_key| rank(held_key).is_some_and(|held_rank| held_rank > key_rank))
+            .cloned()
+    });
+    if let Some(held_key) = violated {
+        // Released first, so that the panic does not poison the mutex
+        // for the other tasks
+        drop(held);
+        panic!(
+            "Lock ordering violated: acquiring {} while holding {}",
+            key, held_key
+        );
+    }
+    keys.push(key.to_string());
+}
+
+/**
+ * Forget one acquisition of key in the current task.
+ */
+pub(super) fn on_release(key: &str) {
+    let Some(id) = task::try_id() else {
+        return;
+    };
+    let mut held = HELD.lock().unwrap();
+    if let Some(keys) = held.get_mut(&id) {
+        if let Some(pos) = keys.iter().rposition(|held_key| held_key == key) {
+            keys.remove(pos);
+        }
+        if keys.is_empty() {
+            held.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn allows_hash_lock_while_holding_file_lock() {
+        let task = tokio::spawn(async {
+            on_acquire("file:b:a.txt");
+            on_acquire("hash:b:abc");
+            on_release("hash:b:abc");
+            on_release("file:b:a.txt");
+        });
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn panics_on_file_lock_while_holding_hash_lock() {
+        let task = tokio::spawn(async {
+            on_acquire("hash:b:abc");
+            on_acquire("file:b:a.txt");
+        });
+        assert!(task.await.unwrap_err().is_panic());
+    }
+
+    #[tokio::test]
+    async fn forgets_released_locks() {
+        let task = tokio::spawn(async {
+            on_acquire("hash:b:abc");
+            on_release("hash:b:abc");
+            on_acquire("file:b:a.txt");
+            on_release("file:b:a.txt");
+        });
+        task.await.unwrap();
+    }
+}
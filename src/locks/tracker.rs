@@ -0,0 +1,274 @@
+use crate::config::BucketConfig;
+use crate::metrics;
+use crate::request_id;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LockMode::Shared => "shared",
+            LockMode::Exclusive => "exclusive",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockState {
+    Waiting,
+    Held,
+}
+
+/**
+ * Snapshot of a single held or awaited lock.
+ */
+#[derive(Debug, Serialize)]
+pub struct LockInfo {
+    pub key: String,
+    pub mode: LockMode,
+    pub state: LockState,
+    /// Time since the lock was acquired, or since the wait started
+    pub duration_ms: u128,
+    pub request_id: Option<String>,
+}
+
+struct TrackedLock {
+    key: String,
+    mode: LockMode,
+    state: LockState,
+    since: Instant,
+    request_id: Option<String>,
+    warned: bool,
+}
+
+#[derive(Default)]
+struct TrackedLocks {
+    next_id: u64,
+    locks: HashMap<u64, TrackedLock>,
+}
+
+/**
+ * Bookkeeping of who holds and who waits for which lock, independent
+ * of the locks backend.
+ */
+#[derive(Clone)]
+pub(crate) struct LockTracker {
+    tracked: Arc<Mutex<TrackedLocks>>,
+    bucket: String,
+    timeout: Duration,
+}
+
+impl LockTracker {
+    pub fn new(config: &BucketConfig) -> Self {
+        Self {
+            tracked: Arc::new(Mutex::new(TrackedLocks::default())),
+            bucket: config.name.clone(),
+            timeout: Duration::from_secs(config.locks_timeout_secs),
+        }
+    }
+
+    /**
+     * How long to wait for a lock before giving up.
+     */
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /**
+     * Record that the current request started waiting for key.
+     */
+    pub fn waiting(&self, key: &str, mode: LockMode) -> Waiting<'_> {
+        let mut tracked = self.tracked.lock().unwrap();
+        let id = tracked.next_id;
+        tracked.next_id += 1;
+        tracked.locks.insert(
+            id,
+            TrackedLock {
+                key: key.to_string(),
+                mode,
+                state: LockState::Waiting,
+                since: Instant::now(),
+                request_id: request_id::current(),
+                warned: false,
+            },
+        );
+        Waiting {
+            tracker: self,
+            id,
+            mode,
+            since: Instant::now(),
+            acquired: false,
+        }
+    }
+
+    /**
+     * Forget one held lock on key, preferring the one held by the current request.
+     */
+    pub fn released(&self, key: &str) {
+        let request_id = request_id::current();
+        let mut tracked = self.tracked.lock().unwrap();
+        let held = |lock: &TrackedLock| lock.key == key && lock.state == LockState::Held;
+        let id = tracked
+            .locks
+            .iter()
+            .find(|(_, lock)| held(lock) && lock.request_id == request_id)
+            .or_else(|| tracked.locks.iter().find(|(_, lock)| held(lock)))
+            .map(|(id, _)| *id);
+        if let Some(id) = id {
+            tracked.locks.remove(&id);
+        }
+    }
+
+    /**
+     * List all held and awaited locks, longest first.
+     */
+    pub fn snapshot(&self) -> Vec<LockInfo> {
+        let tracked = self.tracked.lock().unwrap();
+        let mut locks: Vec<LockInfo> = tracked
+            .locks
+            .values()
+            .map(|lock| LockInfo {
+                key: lock.key.clone(),
+                mode: lock.mode,
+                state: lock.state,
+                duration_ms: lock.since.elapsed().as_millis(),
+                request_id: lock.request_id.clone(),
+            })
+            .collect();
+        locks.sort_by_key(|lock| std::cmp::Reverse(lock.duration_ms));
+        locks
+    }
+
+    /**
+     * Log a warning for every lock held longer than threshold.
+     * Each hold is reported only once.
+     */
+    pub fn warn_long_holds(&self, threshold: Duration) {
+        let mut tracked = self.tracked.lock().unwrap();
+        for lock in tracked.locks.values_mut() {
+            if lock.state == LockState::Held && !lock.warned && lock.since.elapsed() > threshold {
+                warn!(
+                    "Lock on key: {} held for {:?} by request: {}",
+                    lock.key,
+                    lock.since.elapsed(),
+                    lock.request_id.as_deref().unwrap_or("-")
+                );
+                lock.warned = true;
+            }
+        }
+    }
+}
+
+/**
+ * Pending wait for a lock. Dropping it before the lock is acquired
+ * removes the wait from the tracker.
+ */
+pub(crate) struct Waiting<'a> {
+    tracker: &'a LockTracker,
+    id: u64,
+    mode: LockMode,
+    since: Instant,
+    acquired: bool,
+}
+
+impl Waiting<'_> {
+    /**
+     * Record that the awaited lock is now held.
+     */
+    pub fn acquired(mut self) {
+        let mut tracked = self.tracker.tracked.lock().unwrap();
+        if let Some(lock) = tracked.locks.get_mut(&self.id) {
+            lock.state = LockState::Held;
+            lock.since = Instant::now();
+        }
+        self.acquired = true;
+        self.observe();
+    }
+
+    fn observe(&self) {
+        metrics::observe_lock_wait(
+            &self.tracker.bucket,
+            self.mode.as_str(),
+            self.since.elapsed(),
+            self.acquired,
+        );
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            let mut tracked = self.tracker.tracked.lock().unwrap();
+            tracked.locks.remove(&self.id);
+            drop(tracked);
+            self.observe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn tracker() -> LockTracker {
+        LockTracker::new(&testing::bucket_config(
+            std::path::Path::new("unused"),
+            json!({}),
+        ))
+    }
+
+    #[test]
+    fn tracks_waits_until_released() {
+        let tracker = tracker();
+        let waiting = tracker.waiting("file:a", LockMode::Exclusive);
+        let locks = tracker.snapshot();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].key, "file:a");
+        assert_eq!(locks[0].mode, LockMode::Exclusive);
+        assert_eq!(locks[0].state, LockState::Waiting);
+
+        waiting.acquired();
+        assert_eq!(tracker.snapshot()[0].state, LockState::Held);
+        tracker.released("file:a");
+        assert!(tracker.snapshot().is_empty());
+    }
+
+    #[test]
+    fn forgets_abandoned_waits() {
+        let tracker = tracker();
+        drop(tracker.waiting("hash:x", LockMode::Shared));
+        assert!(tracker.snapshot().is_empty());
+    }
+
+    #[test]
+    fn releases_one_of_several_shared_holds() {
+        let tracker = tracker();
+        tracker.waiting("hash:x", LockMode::Shared).acquired();
+        tracker.waiting("hash:x", LockMode::Shared).acquired();
+        tracker.released("hash:x");
+        assert_eq!(tracker.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn lists_longest_held_first() {
+        let tracker = tracker();
+        tracker.waiting("file:old", LockMode::Exclusive).acquired();
+        std::thread::sleep(Duration::from_millis(5));
+        tracker.waiting("file:new", LockMode::Exclusive).acquired();
+        let keys: Vec<String> = tracker.snapshot().into_iter().map(|l| l.key).collect();
+        assert_eq!(keys, ["file:old", "file:new"]);
+    }
+}
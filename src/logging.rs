@@ -1,24 +1,246 @@
-use tracing::dispatcher::SetGlobalDefaultError;
-use tracing_subscriber::{fmt, EnvFilter};
+use crate::access_log::{self, AccessLogConfig};
+use axum::http::Uri;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use tracing::Level;
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{EnvFilter, Layer, fmt};
 
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct LoggingConfig {
     level: String,
     json: bool,
+    /// Write logs to a file instead of stdout
+    #[serde(default)]
+    file: Option<LogFileConfig>,
+    /// Log every request to a stream of its own
+    #[serde(default)]
+    access_log: Option<AccessLogConfig>,
+    /// Export traces to an OpenTelemetry collector, with spans of level
+    /// info and above whatever the log level is
+    #[serde(default)]
+    otlp: Option<OtlpConfig>,
 }
 
-pub fn setup(logging_config: &LoggingConfig) -> Result<(), SetGlobalDefaultError> {
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OtlpConfig {
+    /// URL spans are posted to over HTTP, e.g. `http://localhost:4318/v1/traces`
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    /// Fraction of traces exported, unless the client decided already in
+    /// the W3C `traceparent` header of the request
+    #[serde(default = "default_sampling_ratio")]
+    sampling_ratio: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RotationPeriod {
+    #[serde(rename = "hourly")]
+    Hourly,
+    #[serde(rename = "daily")]
+    Daily,
+}
+
+/**
+ * Log file, rotated by time, by size or both. Rotated files are kept next
+ * to it as `<path>.1`, `<path>.2`, ... with the highest number the oldest.
+ */
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LogFileConfig {
+    pub path: String,
+    /// Start a new file every hour or day
+    #[serde(default)]
+    pub rotate_every: Option<RotationPeriod>,
+    /// Start a new file once the current one reaches this size
+    #[serde(default)]
+    pub rotate_size_bytes: Option<u64>,
+    /// Rotated files kept, older ones are deleted
+    #[serde(default = "default_keep_files")]
+    pub keep_files: usize,
+}
+
+fn default_keep_files() -> usize {
+    7
+}
+
+impl LogFileConfig {
+    pub fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        if self.path.is_empty() {
+            problems.push(format!("{}.path: must not be empty", at));
+        }
+        if self.rotate_size_bytes == Some(0) {
+            problems.push(format!("{}.rotate_size_bytes: must be positive", at));
+        }
+    }
+
+    /**
+     * Open the file for writing from a background thread, so that logging
+     * rarely waits for the disk.
+     */
+    pub fn open(&self) -> io::Result<(NonBlocking, WorkerGuard)> {
+        let path = Path::new(&self.path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut condition = RollingConditionBasic::new();
+        condition = match self.rotate_every {
+            Some(RotationPeriod::Hourly) => condition.hourly(),
+            Some(RotationPeriod::Daily) => condition.daily(),
+            None => condition,
+        };
+        if let Some(size) = self.rotate_size_bytes {
+            condition = condition.max_size(size);
+        }
+        let appender = BasicRollingFileAppender::new(path, condition, self.keep_files)?;
+        Ok(non_blocking(appender))
+    }
+}
+
+/**
+ * Write from a background thread. Logging waits while the thread is behind
+ * instead of dropping lines, as access logs and audit events must be complete.
+ */
+pub fn non_blocking<W: io::Write + Send + 'static>(writer: W) -> (NonBlocking, WorkerGuard) {
+    NonBlockingBuilder::default().lossy(false).finish(writer)
+}
+
+fn default_service_name() -> String {
+    "s3dedup".to_string()
+}
+
+fn default_sampling_ratio() -> f64 {
+    1.0
+}
+
+impl LoggingConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Err(e) = EnvFilter::try_new(&self.level) {
+            problems.push(format!("logging.level: {}", e));
+        }
+        if let Some(file) = &self.file {
+            file.validate("logging.file", problems);
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.validate(problems);
+            if let (Some(file), Some(access_file)) = (&self.file, &access_log.file)
+                && file.path == access_file.path
+            {
+                problems.push(
+                    "logging.access_log.file.path: must differ from logging.file.path".to_string(),
+                );
+            }
+        }
+        if let Some(otlp) = &self.otlp {
+            otlp.validate(problems);
+        }
+    }
+}
+
+impl OtlpConfig {
+    fn validate(&self, problems: &mut Vec<String>) {
+        let valid = self.endpoint.parse::<Uri>().is_ok_and(|uri| {
+            matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+        });
+        if !valid {
+            problems.push("logging.otlp.endpoint: must be an http or https URL".to_string());
+        }
+        if self.service_name.is_empty() {
+            problems.push("logging.otlp.service_name: must not be empty".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.sampling_ratio) {
+            problems.push("logging.otlp.sampling_ratio: must be between 0 and 1".to_string());
+        }
+    }
+
+    fn tracer_provider(&self) -> Result<SdkTracerProvider, Box<dyn Error>> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&self.endpoint)
+            .build()?;
+        let sampler = Sampler::TraceIdRatioBased(self.sampling_ratio);
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(self.service_name.clone())
+                    .build(),
+            )
+            .build())
+    }
+}
+
+/**
+ * Keeps writing logs and exporting traces until dropped, then writes and
+ * sends the remaining ones.
+ */
+pub struct LoggingGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+    _writers: Vec<WorkerGuard>,
+}
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take()
+            && let Err(e) = tracer_provider.shutdown()
+        {
+            eprintln!("Failed to export remaining traces: {}", e);
+        }
+    }
+}
+
+/**
+ * Log to stdout or a file, start the access log and export traces, as
+ * configured. The returned guard must be kept until the process exits.
+ */
+pub fn setup(logging_config: &LoggingConfig) -> Result<LoggingGuard, Box<dyn Error>> {
+    let mut writers = vec![];
+    let (writer, ansi) = match &logging_config.file {
+        Some(file) => {
+            let (writer, guard) = file.open()?;
+            writers.push(guard);
+            (BoxMakeWriter::new(writer), false)
+        }
+        None => (BoxMakeWriter::new(io::stdout), true),
+    };
+    if let Some(access_log) = &logging_config.access_log {
+        writers.push(access_log::init(access_log)?);
+    }
+
     let filter = EnvFilter::new(&logging_config.level);
-    if logging_config.json {
-        let subscriber = fmt::Subscriber::builder()
-            .with_env_filter(filter)
-            .json()
-            .finish();
-        tracing::subscriber::set_global_default(subscriber)
+    let fmt_layer = if logging_config.json {
+        fmt::layer().json().with_writer(writer).boxed()
     } else {
-        let subscriber = fmt::Subscriber::builder()
-            .with_env_filter(filter)
-            .finish();
-        tracing::subscriber::set_global_default(subscriber)
-    }
-}
\ No newline at end of file
+        fmt::layer().with_ansi(ansi).with_writer(writer).boxed()
+    };
+
+    let tracer_provider = match &logging_config.otlp {
+        Some(otlp) => Some(otlp.tracer_provider()?),
+        None => None,
+    };
+    // Traced independently of the log level, which is often warn or lower
+    let otel_layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("s3dedup"))
+            .with_filter(Targets::new().with_target("s3dedup", Level::INFO))
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(otel_layer);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(LoggingGuard {
+        tracer_provider,
+        _writers: writers,
+    })
+}
@@ -1,72 +1,194 @@
+use crate::auth::Auth;
+use crate::auth::acl::Acl;
+use crate::blobstorage::BlobStorage;
+use crate::in_flight::InFlight;
 use crate::kvstorage::KVStorage;
 use crate::locks::LocksStorage;
-use axum::Router;
-use axum::routing::{get, put};
-use routes::ft::put_file::ft_put_file;
+use crate::quota::QuotaConfig;
+use crate::ratelimit::RateLimiter;
+use clap::{Args, Parser, Subcommand};
 use std::error::Error;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
-use tracing::{info, Level};
-use crate::routes::ft::version::ft_version;
+use std::process::ExitCode;
+use std::time::Duration;
 
+mod access_log;
+mod auth;
+mod blobstorage;
+mod commands;
 mod config;
+mod error;
+mod in_flight;
 mod kvstorage;
+mod listen;
 mod locks;
-mod routes;
 mod logging;
+mod metrics;
+mod quota;
+mod ratelimit;
+mod request_id;
+mod routes;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 #[derive(Clone)]
 struct AppState {
     bucket_name: String,
     kvstorage: Box<KVStorage>,
+    blobstorage: Box<BlobStorage>,
     locks: Box<LocksStorage>,
+    auth: Option<Box<Auth>>,
+    acl: Option<Box<Acl>>,
+    quota: Option<QuotaConfig>,
+    rate_limit: Option<Box<RateLimiter>>,
+    max_upload_bytes: u64,
+    in_flight: InFlight,
 }
 
 impl AppState {
     async fn new(config: &config::BucketConfig) -> Result<Self, Box<dyn Error>> {
-        let kvstorage = KVStorage::new(&config).await?;
-        let locks = LocksStorage::new(&config.locks_type);
+        let kvstorage = KVStorage::new(config).await?;
+        let blobstorage = BlobStorage::new(config).await?;
+        let locks = LocksStorage::new(config, &kvstorage)?;
         Ok(Self {
             bucket_name: config.name.clone(),
             kvstorage,
+            blobstorage,
             locks,
+            auth: config.auth.as_ref().map(|auth| Box::new(Auth::new(auth))),
+            acl: config
+                .acl
+                .as_ref()
+                .map(|acl| Box::new(Acl::new(&config.name, acl))),
+            quota: config.quota.clone(),
+            rate_limit: config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| Box::new(RateLimiter::new(&config.name, rate_limit))),
+            max_upload_bytes: config.max_upload_bytes,
+            in_flight: InFlight::default(),
         })
     }
 }
 
-async fn run_server(addr: SocketAddr, app: Router) {
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+#[derive(Parser)]
+#[command(version, about = "Deduplicating file storage server")]
+struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, default_value = "config.json")]
+    config: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    let config = config::Config::new("config.json").unwrap();
-    logging::setup(&config.logging).unwrap();
-    let mut handles = vec![];
+#[derive(Args)]
+struct BucketArgs {
+    /// Only operate on the bucket with this name
+    #[arg(long)]
+    bucket: Option<String>,
+}
 
-    for bucket in config.buckets.iter() {
-        info!("Starting server for bucket: {}", bucket.name);
+#[derive(Subcommand)]
+enum Command {
+    /// Start servers for all buckets (default)
+    Serve,
+    /// Validate the configuration, then exit
+    CheckConfig,
+    /// Print the effective configuration with secrets redacted, then exit
+    DumpConfig {
+        /// Output format, defaults to the format of the configuration file
+        #[arg(long, value_enum)]
+        format: Option<config::ConfigFormat>,
+    },
+    /// Create or update the KV storage schema, then exit
+    Migrate(BucketArgs),
+    /// Check reference counts and blobs for consistency
+    Fsck {
+        #[command(flatten)]
+        bucket: BucketArgs,
+        /// Fix reference counts, only while no server is running
+        #[arg(long)]
+        repair: bool,
+        /// Seconds after which inconsistencies are checked again, only those
+        /// found twice are reported
+        #[arg(long, default_value_t = 10)]
+        grace_secs: u64,
+    },
+    /// Delete blobs that are no longer referenced
+    Gc {
+        #[command(flatten)]
+        bucket: BucketArgs,
+        /// Only print what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print deduplication statistics of the buckets
+    Stats {
+        #[command(flatten)]
+        bucket: BucketArgs,
+        /// Number of most shared hashes to list
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(i64).range(0..))]
+        top: i64,
+        /// Print one JSON object per bucket
+        #[arg(long)]
+        json: bool,
+    },
+}
 
-        let mut app_state = AppState::new(bucket).await.unwrap();
-        app_state.kvstorage.setup().await.unwrap();
+async fn run(cli: Cli) -> Result<ExitCode, Box<dyn Error>> {
+    let config = config::Config::new(&cli.config)?;
+    // Dumping works on invalid configurations too, to help finding the problem
+    if let Some(Command::DumpConfig { format }) = cli.command {
+        let format = format.map_or_else(|| config::ConfigFormat::from_path(&cli.config), Ok)?;
+        print!("{}", config.dump(format)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    config.validate()?;
+    let _logging = logging::setup(&config.logging)?;
 
-        let app = Router::new()
-            .route("/ft/version", get(ft_version))
-            .route("/ft/files/{path}", put(ft_put_file))
-            .layer( // Logging middleware
-                TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                    .on_response(DefaultOnResponse::new().level(Level::INFO)),
-            )
-            .with_state(Arc::new(app_state));
-        let address: SocketAddr = format!("{}:{}", bucket.address, bucket.port).parse().unwrap();
-        let handle = tokio::spawn(run_server(address, app));
-        handles.push(handle);
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => commands::serve::serve(&cli.config, config).await?,
+        Command::CheckConfig => println!("Configuration is valid"),
+        Command::DumpConfig { .. } => unreachable!("handled before validation"),
+        Command::Migrate(args) => {
+            commands::migrate::migrate(&config, args.bucket.as_deref()).await?
+        }
+        Command::Fsck {
+            bucket,
+            repair,
+            grace_secs,
+        } => {
+            let grace = Duration::from_secs(grace_secs);
+            let problems =
+                commands::fsck::fsck(&config, bucket.bucket.as_deref(), repair, grace).await?;
+            if problems > 0 {
+                println!("Found {} problems", problems);
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Gc { bucket, dry_run } => {
+            let removed = commands::gc::gc(&config, bucket.bucket.as_deref(), dry_run).await?;
+            if dry_run {
+                println!("Would remove {} blobs", removed);
+            } else {
+                println!("Removed {} blobs", removed);
+            }
+        }
+        Command::Stats { bucket, top, json } => {
+            commands::stats::stats(&config, bucket.bucket.as_deref(), top, json).await?
+        }
     }
+    Ok(ExitCode::SUCCESS)
+}
 
-    for handle in handles {
-        handle.await.unwrap();
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,263 @@
+use crate::kvstorage::stats::Usage;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    GaugeVec, HistogramVec, IntCounterVec, exponential_buckets, register_gauge_vec,
+    register_histogram_vec, register_int_counter_vec,
+};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "s3dedup_http_requests_total",
+        "HTTP requests handled",
+        &["bucket", "method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "s3dedup_http_request_duration_seconds",
+        "Time taken to answer HTTP requests",
+        &["bucket", "method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static KV_OPERATION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "s3dedup_kv_operation_duration_seconds",
+        "Time taken by KV storage operations",
+        &["bucket", "backend", "operation", "result"],
+        // 100µs up to about 3s
+        exponential_buckets(0.0001, 2.0, 16).unwrap()
+    )
+    .unwrap()
+});
+
+static LOCK_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "s3dedup_lock_wait_seconds",
+        "Time spent waiting for locks",
+        &["bucket", "mode", "result"]
+    )
+    .unwrap()
+});
+
+static RECEIVED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "s3dedup_received_bytes_total",
+        "Bytes of uploaded files",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+static STORED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "s3dedup_stored_bytes_total",
+        "Bytes of blobs written to the blob storage",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "s3dedup_rate_limited_total",
+        "Requests rejected for exceeding a rate or concurrency limit",
+        &["bucket", "scope", "limit"]
+    )
+    .unwrap()
+});
+
+static DEDUP_RATIO: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "s3dedup_dedup_ratio",
+        "Bytes of stored files per byte of stored blobs",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+/**
+ * Render all metrics in the Prometheus text format.
+ */
+pub fn render() -> Result<String, prometheus::Error> {
+    prometheus::TextEncoder::new().encode_to_string(&prometheus::gather())
+}
+
+/**
+ * Middleware counting requests to a bucket and timing their responses.
+ * Must be added as route layer, so that the matched route is known.
+ */
+pub async fn track_requests(
+    State(bucket): State<String>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    // Label by route pattern, not by path, to keep the number of series bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [bucket.as_str(), &method, &route, &status];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+pub fn observe_kv_operation(
+    bucket: &str,
+    backend: &str,
+    operation: &str,
+    elapsed: Duration,
+    ok: bool,
+) {
+    let result = if ok { "ok" } else { "error" };
+    KV_OPERATION_DURATION
+        .with_label_values(&[bucket, backend, operation, result])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn observe_lock_wait(bucket: &str, mode: &str, elapsed: Duration, acquired: bool) {
+    let result = if acquired { "acquired" } else { "failed" };
+    LOCK_WAIT
+        .with_label_values(&[bucket, mode, result])
+        .observe(elapsed.as_secs_f64());
+}
+
+/**
+ * Count bytes of a stored file, whether or not its blob had to be stored.
+ */
+pub fn record_received(bucket: &str, bytes: usize) {
+    RECEIVED_BYTES
+        .with_label_values(&[bucket])
+        .inc_by(bytes as u64);
+}
+
+/**
+ * Count bytes of a blob written to the blob storage.
+ */
+pub fn record_stored(bucket: &str, bytes: usize) {
+    STORED_BYTES
+        .with_label_values(&[bucket])
+        .inc_by(bytes as u64);
+}
+
+pub fn record_rate_limited(bucket: &str, scope: &str, limit: &str) {
+    RATE_LIMITED
+        .with_label_values(&[bucket, scope, limit])
+        .inc();
+}
+
+/**
+ * Update the dedup ratio from the tracked usage of a bucket.
+ * An empty bucket counts as not deduplicated.
+ */
+pub fn record_usage(bucket: &str, usage: &Usage) {
+    let ratio = if usage.physical_bytes > 0 {
+        usage.logical_bytes as f64 / usage.physical_bytes as f64
+    } else {
+        1.0
+    };
+    DEDUP_RATIO.with_label_values(&[bucket]).set(ratio);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::routing::get;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn counts_requests_by_route() {
+        let router = Router::new()
+            .route("/ft/files/{*path}", get(|| async {}))
+            .route_layer(axum::middleware::from_fn_with_state(
+                "metrics-requests".to_string(),
+                track_requests,
+            ));
+        for path in ["/ft/files/a", "/ft/files/b"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            router.clone().oneshot(request).await.unwrap();
+        }
+
+        let labels = [
+            ("bucket", "metrics-requests"),
+            ("method", "GET"),
+            ("route", "/ft/files/{*path}"),
+            ("status", "200"),
+        ];
+        assert_eq!(testing::metric("s3dedup_http_requests_total", &labels), 2.0);
+        let duration = testing::metric("s3dedup_http_request_duration_seconds", &labels);
+        assert_eq!(duration, 2.0);
+    }
+
+    #[tokio::test]
+    async fn labels_kv_operations_by_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({"name": "metrics-kv"}));
+        let state = testing::app_state(&config).await;
+        state
+            .kvstorage
+            .get_ref_count("metrics-kv", "x")
+            .await
+            .unwrap();
+
+        let labels = [
+            ("bucket", "metrics-kv"),
+            ("backend", "sqlite"),
+            ("operation", "get_ref_count"),
+            ("result", "ok"),
+        ];
+        let observed = testing::metric("s3dedup_kv_operation_duration_seconds", &labels);
+        assert_eq!(observed, 1.0);
+    }
+
+    #[tokio::test]
+    async fn dedup_ratio_follows_tracked_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({"name": "metrics-dedup"}));
+        let ratio = || testing::metric("s3dedup_dedup_ratio", &[("bucket", "metrics-dedup")]);
+        let state = testing::app_state(&config).await;
+        assert_eq!(ratio(), 1.0);
+
+        let usage = |logical_bytes, physical_bytes| Usage {
+            logical_bytes,
+            physical_bytes,
+            paths: 0,
+        };
+        let kvstorage = &state.kvstorage;
+        kvstorage
+            .add_usage("metrics-dedup", &usage(300, 100))
+            .await
+            .unwrap();
+        assert_eq!(ratio(), 3.0);
+        kvstorage
+            .add_usage("metrics-dedup", &usage(100, 0))
+            .await
+            .unwrap();
+        assert_eq!(ratio(), 4.0);
+
+        // Taken from the stored usage when the bucket starts again
+        record_usage("metrics-dedup", &Usage::default());
+        testing::app_state(&config).await;
+        assert_eq!(ratio(), 4.0);
+    }
+}
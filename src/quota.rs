@@ -0,0 +1,174 @@
+use crate::AppState;
+use crate::error::AppError;
+use crate::kvstorage::stats::Usage;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Limits of what a bucket may store. They are checked as usage is added,
+ * so that uploads running at the same time cannot overshoot them together.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Bytes counted once per path
+    #[serde(default)]
+    pub max_logical_bytes: Option<i64>,
+    /// Bytes counted once per blob
+    #[serde(default)]
+    pub max_physical_bytes: Option<i64>,
+    #[serde(default)]
+    pub max_paths: Option<i64>,
+}
+
+impl QuotaConfig {
+    pub fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        let limits = [
+            ("max_logical_bytes", self.max_logical_bytes),
+            ("max_physical_bytes", self.max_physical_bytes),
+            ("max_paths", self.max_paths),
+        ];
+        for (field, limit) in limits {
+            if limit.is_some_and(|limit| limit < 0) {
+                problems.push(format!("{}.quota.{}: must not be negative", at, field));
+            }
+        }
+    }
+
+    /**
+     * Fail if adding delta to usage exceeds a limit. Only growing counts
+     * are checked, so that buckets over quota can always shrink.
+     */
+    fn check(&self, bucket: &str, usage: &Usage, delta: &Usage) -> Result<(), AppError> {
+        let counts = [
+            (
+                "logical bytes",
+                usage.logical_bytes,
+                delta.logical_bytes,
+                self.max_logical_bytes,
+            ),
+            (
+                "physical bytes",
+                usage.physical_bytes,
+                delta.physical_bytes,
+                self.max_physical_bytes,
+            ),
+            ("paths", usage.paths, delta.paths, self.max_paths),
+        ];
+        for (name, used, added, limit) in counts {
+            if let Some(limit) = limit
+                && added > 0
+                && used + added > limit
+            {
+                return Err(AppError::QuotaExceeded(format!(
+                    "Quota of bucket: {} exceeded: {} would be {}, the limit is {}",
+                    bucket,
+                    name,
+                    used + added,
+                    limit
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Add delta to the usage of the bucket, failing if that exceeds a limit
+ * of its quota. The limits are checked and the usage added in one step.
+ */
+pub async fn add_usage(state: &AppState, delta: &Usage) -> Result<(), AppError> {
+    let bucket = &state.bucket_name;
+    let Some(quota) = &state.quota else {
+        return state.kvstorage.add_usage(bucket, delta).await;
+    };
+    loop {
+        if state
+            .kvstorage
+            .add_usage_within(bucket, delta, quota)
+            .await?
+        {
+            return Ok(());
+        }
+        // Untracked usage cannot be added to, only checked
+        let usage = state.kvstorage.get_usage(bucket).await?;
+        quota.check(bucket, &usage.clone().unwrap_or_default(), delta)?;
+        if usage.is_none() {
+            return Ok(());
+        }
+        // The usage shrank since it was rejected
+    }
+}
+
+/**
+ * Add delta to the usage of the bucket and run op, taking the usage back
+ * if op fails. Fails without running op if a limit would be exceeded.
+ */
+pub async fn charge<T>(
+    state: &AppState,
+    delta: &Usage,
+    op: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    add_usage(state, delta).await?;
+    let result = op.await;
+    if result.is_err() {
+        state
+            .kvstorage
+            .add_usage(&state.bucket_name, &delta.negated())
+            .await?;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(logical_bytes: i64, physical_bytes: i64, paths: i64) -> Usage {
+        Usage {
+            logical_bytes,
+            physical_bytes,
+            paths,
+        }
+    }
+
+    fn quota() -> QuotaConfig {
+        QuotaConfig {
+            max_logical_bytes: Some(100),
+            max_physical_bytes: None,
+            max_paths: Some(2),
+        }
+    }
+
+    #[test]
+    fn rejects_growth_over_limits() {
+        let used = usage(90, 50, 1);
+        assert!(quota().check("b", &used, &usage(10, 1000, 1)).is_ok());
+        assert!(matches!(
+            quota().check("b", &used, &usage(11, 0, 0)),
+            Err(AppError::QuotaExceeded(_))
+        ));
+        assert!(quota().check("b", &used, &usage(0, 0, 2)).is_err());
+    }
+
+    #[test]
+    fn buckets_over_quota_may_shrink() {
+        let used = usage(150, 150, 3);
+        assert!(quota().check("b", &used, &usage(-10, -10, -1)).is_ok());
+        assert!(quota().check("b", &used, &usage(-10, 0, 0)).is_ok());
+        assert!(quota().check("b", &used, &usage(1, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn rejects_negative_limits() {
+        let mut problems = vec![];
+        let quota = QuotaConfig {
+            max_logical_bytes: Some(-1),
+            max_physical_bytes: Some(0),
+            max_paths: None,
+        };
+        quota.validate("buckets[0]", &mut problems);
+        assert_eq!(
+            problems,
+            ["buckets[0].quota.max_logical_bytes: must not be negative"]
+        );
+    }
+}
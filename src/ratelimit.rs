@@ -0,0 +1,524 @@
+use crate::AppState;
+use crate::auth::Identity;
+use crate::error::AppError;
+use crate::listen::ClientAddr;
+use crate::metrics;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Routes never limited, so that load balancers and scrapers keep seeing the bucket
+const EXEMPT_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+/// Number of clients tracked before idle ones are forgotten
+const MIN_PRUNE_AT: usize = 10_000;
+
+/**
+ * Limits of requests, each optional. Rates allow bursts of one second.
+ *
+ * Bytes are counted while request bodies are read, so a single request
+ * may exceed the byte rate. Further requests are then rejected until the
+ * excess is paid off.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub requests_per_sec: Option<f64>,
+    /// Bytes of request bodies
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Uploads being received or stored at the same time
+    #[serde(default)]
+    pub max_concurrent_uploads: Option<u32>,
+}
+
+impl LimitsConfig {
+    fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        if self
+            .requests_per_sec
+            .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+        {
+            problems.push(format!("{}.requests_per_sec: must be positive", at));
+        }
+        if self.bytes_per_sec == Some(0) {
+            problems.push(format!("{}.bytes_per_sec: must be positive", at));
+        }
+        if self.max_concurrent_uploads == Some(0) {
+            problems.push(format!("{}.max_concurrent_uploads: must be positive", at));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Limits of each client, identified by credential name if the bucket
+    /// requires credentials, by IP address otherwise
+    #[serde(default)]
+    pub per_client: Option<LimitsConfig>,
+    /// Limits of all clients together
+    #[serde(default)]
+    pub per_bucket: Option<LimitsConfig>,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        if let Some(limits) = &self.per_client {
+            limits.validate(&format!("{}.rate_limit.per_client", at), problems);
+        }
+        if let Some(limits) = &self.per_bucket {
+            limits.validate(&format!("{}.rate_limit.per_bucket", at), problems);
+        }
+    }
+}
+
+/**
+ * Tokens refilled at a steady rate, up to one second worth of them.
+ */
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        // A burst must allow at least one request
+        let capacity = rate.max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /**
+     * Check that amount tokens are available, or return how long until they are.
+     */
+    fn check(&mut self, amount: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens < amount {
+            return Err(Duration::from_secs_f64((amount - self.tokens) / self.rate));
+        }
+        Ok(())
+    }
+
+    /**
+     * Take amount tokens, or return how long until they are available.
+     */
+    fn take(&mut self, amount: f64, now: Instant) -> Result<(), Duration> {
+        self.check(amount, now)?;
+        self.tokens -= amount;
+        Ok(())
+    }
+
+    /**
+     * Take amount tokens even if they are not available, going into debt.
+     */
+    fn charge(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+
+    /**
+     * How long until the debt is paid off, if there is any.
+     */
+    fn debt(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/**
+ * State of the limits of one client, or of the bucket.
+ */
+struct Limiter {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    uploads: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+    fn new(config: &LimitsConfig, now: Instant) -> Self {
+        Limiter {
+            requests: config
+                .requests_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+            bytes: config
+                .bytes_per_sec
+                .map(|rate| TokenBucket::new(rate as f64, now)),
+            uploads: config
+                .max_concurrent_uploads
+                .map(|max| Arc::new(Semaphore::new(max as usize))),
+        }
+    }
+
+    /**
+     * Check that a request would be admitted without taking anything,
+     * or return the exceeded limit and when to retry.
+     */
+    fn check(&mut self, upload: bool, now: Instant) -> Result<(), (&'static str, Duration)> {
+        if let Some(wait) = self.bytes.as_mut().and_then(|bytes| bytes.debt(now)) {
+            return Err(("bytes", wait));
+        }
+        if let Some(uploads) = &self.uploads
+            && upload
+            && uploads.available_permits() == 0
+        {
+            return Err(("concurrent_uploads", Duration::from_secs(1)));
+        }
+        if let Some(requests) = &mut self.requests {
+            requests
+                .check(1.0, now)
+                .map_err(|wait| ("requests", wait))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Admit a request, or return the exceeded limit and when to retry.
+     * Uploads hold the returned permit until they are done.
+     */
+    fn admit(
+        &mut self,
+        upload: bool,
+        now: Instant,
+    ) -> Result<Option<OwnedSemaphorePermit>, (&'static str, Duration)> {
+        self.check(upload, now)?;
+        let permit = match &self.uploads {
+            Some(uploads) if upload => Some(
+                uploads
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| ("concurrent_uploads", Duration::from_secs(1)))?,
+            ),
+            _ => None,
+        };
+        if let Some(requests) = &mut self.requests {
+            requests.take(1.0, now).map_err(|wait| ("requests", wait))?;
+        }
+        Ok(permit)
+    }
+
+    fn charge(&mut self, bytes: usize, now: Instant) {
+        if let Some(bucket) = &mut self.bytes {
+            bucket.charge(bytes as f64, now);
+        }
+    }
+
+    /**
+     * Whether forgetting the limiter would change nothing.
+     */
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.requests.as_mut().is_none_or(|b| b.is_full(now))
+            && self.bytes.as_mut().is_none_or(|b| b.is_full(now))
+            && self
+                .uploads
+                .as_ref()
+                .is_none_or(|s| Arc::strong_count(s) == 1)
+    }
+}
+
+struct Limiters {
+    bucket: Option<Limiter>,
+    clients: HashMap<String, Limiter>,
+    /// Number of clients at which idle ones are forgotten next
+    prune_at: usize,
+}
+
+/**
+ * Rate and concurrency limits of a bucket.
+ */
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: String,
+    config: RateLimitConfig,
+    limiters: Arc<Mutex<Limiters>>,
+}
+
+impl RateLimiter {
+    pub fn new(bucket: &str, config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            bucket: bucket.to_string(),
+            config: config.clone(),
+            limiters: Arc::new(Mutex::new(Limiters {
+                bucket: config
+                    .per_bucket
+                    .as_ref()
+                    .map(|limits| Limiter::new(limits, now)),
+                clients: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            })),
+        }
+    }
+
+    /**
+     * Admit a request of client, checking the limits of the client first.
+     * Nothing is taken from either limiter unless both admit the request.
+     * Returns the permits to hold while an upload is running.
+     */
+    fn admit(&self, client: &str, upload: bool) -> Result<Vec<OwnedSemaphorePermit>, AppError> {
+        let now = Instant::now();
+        let mut guard = self.limiters.lock().unwrap();
+        let limiters = &mut *guard;
+
+        let client_limiter = match &self.config.per_client {
+            Some(limits) => {
+                if limiters.clients.len() >= limiters.prune_at {
+                    limiters.clients.retain(|_, limiter| !limiter.is_idle(now));
+                    limiters.prune_at = MIN_PRUNE_AT.max(limiters.clients.len() * 2);
+                }
+                let limiter = limiters
+                    .clients
+                    .entry(client.to_string())
+                    .or_insert_with(|| Limiter::new(limits, now));
+                limiter
+                    .check(upload, now)
+                    .map_err(|(limit, wait)| self.reject("client", client, limit, wait))?;
+                Some(limiter)
+            }
+            None => None,
+        };
+        if let Some(limiter) = &mut limiters.bucket {
+            limiter
+                .check(upload, now)
+                .map_err(|(limit, wait)| self.reject("bucket", client, limit, wait))?;
+        }
+
+        // Both checked under the lock, so taking cannot fail anymore
+        let mut permits = vec![];
+        for limiter in client_limiter.into_iter().chain(limiters.bucket.as_mut()) {
+            permits.extend(limiter.admit(upload, now).map_err(|(limit, _)| {
+                AppError::Internal(format!("Limit on {} changed while admitting", limit))
+            })?);
+        }
+        Ok(permits)
+    }
+
+    fn reject(&self, scope: &str, client: &str, limit: &str, wait: Duration) -> AppError {
+        metrics::record_rate_limited(&self.bucket, scope, limit);
+        AppError::RateLimited(
+            format!(
+                "Limit of {} on {} exceeded by: {} in bucket: {}",
+                scope, limit, client, self.bucket
+            ),
+            wait,
+        )
+    }
+
+    fn charge(&self, client: &str, bytes: usize) {
+        let now = Instant::now();
+        let mut limiters = self.limiters.lock().unwrap();
+        if let Some(limiter) = limiters.clients.get_mut(client) {
+            limiter.charge(bytes, now);
+        }
+        if let Some(limiter) = &mut limiters.bucket {
+            limiter.charge(bytes, now);
+        }
+    }
+
+    fn limits_bytes(&self) -> bool {
+        [&self.config.per_client, &self.config.per_bucket]
+            .iter()
+            .any(|limits| limits.as_ref().is_some_and(|l| l.bytes_per_sec.is_some()))
+    }
+}
+
+/**
+ * Name the limits of the request are tracked under: the credential it was
+ * made with, or the address it came from. Clients on Unix sockets share
+ * their limits.
+ */
+fn client_key(request: &Request) -> String {
+    if let Some(identity) = request.extensions().get::<Identity>() {
+        return format!("credential:{}", identity.name);
+    }
+    match request.extensions().get::<ConnectInfo<ClientAddr>>() {
+        Some(ConnectInfo(ClientAddr(Some(ip)))) => format!("ip:{}", ip),
+        _ => "local".to_string(),
+    }
+}
+
+/**
+ * Middleware rejecting requests over the limits of their client or bucket
+ * with 429. Must be added as route layer inside `auth::require_auth`, so
+ * that the matched route and the identity are known.
+ */
+pub async fn limit_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(limiter) = &state.rate_limit else {
+        return Ok(next.run(request).await);
+    };
+    let route = request.extensions().get::<MatchedPath>();
+    if route.is_some_and(|route| EXEMPT_ROUTES.contains(&route.as_str())) {
+        return Ok(next.run(request).await);
+    }
+
+    let client = client_key(&request);
+    let _permits = limiter.admit(&client, request.method() == Method::PUT)?;
+    if !limiter.limits_bytes() {
+        return Ok(next.run(request).await);
+    }
+
+    let limiter = limiter.clone();
+    let request = request.map(|body| {
+        Body::from_stream(body.into_data_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                limiter.charge(&client, chunk.len());
+            }
+        }))
+    });
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::http::header::RETRY_AFTER;
+    use axum::response::IntoResponse;
+    use serde_json::json;
+
+    fn rate_limiter(bucket: &str, config: serde_json::Value) -> RateLimiter {
+        RateLimiter::new(bucket, &serde_json::from_value(config).unwrap())
+    }
+
+    fn retry_after(error: AppError) -> String {
+        let response = error.into_response();
+        assert_eq!(response.status(), 429);
+        response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_one_second() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert!(bucket.take(1.0, start).is_ok());
+        assert!(bucket.take(1.0, start).is_ok());
+        assert_eq!(bucket.take(1.0, start), Err(Duration::from_millis(500)));
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(1.0, later), Err(Duration::from_millis(250)));
+        let much_later = start + Duration::from_secs(10);
+        assert!(bucket.is_full(much_later));
+        assert!(bucket.take(2.0, much_later).is_ok());
+        assert!(bucket.check(1.0, much_later).is_err());
+
+        // A burst allows one request even below one per second
+        let mut slow = TokenBucket::new(0.5, start);
+        assert!(slow.take(1.0, start).is_ok());
+        assert_eq!(slow.take(1.0, start), Err(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn charged_bytes_are_paid_off_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, start);
+        assert_eq!(bucket.debt(start), None);
+        bucket.charge(300.0, start);
+        assert_eq!(bucket.debt(start), Some(Duration::from_secs(2)));
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(bucket.debt(later), Some(Duration::from_millis(500)));
+        assert_eq!(bucket.debt(start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn uploads_hold_permits_until_done() {
+        let now = Instant::now();
+        let config = LimitsConfig {
+            requests_per_sec: None,
+            bytes_per_sec: None,
+            max_concurrent_uploads: Some(1),
+        };
+        let mut limiter = Limiter::new(&config, now);
+        let permit = limiter.admit(true, now).unwrap();
+        assert!(permit.is_some());
+        assert!(matches!(
+            limiter.admit(true, now),
+            Err(("concurrent_uploads", _))
+        ));
+        assert!(limiter.admit(false, now).unwrap().is_none());
+        assert!(!limiter.is_idle(now));
+
+        drop(permit);
+        assert!(limiter.is_idle(now));
+        assert!(limiter.admit(true, now).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejected_requests_take_nothing() {
+        let limiter = rate_limiter(
+            "ratelimit-rejected",
+            json!({
+                "per_client": {"requests_per_sec": 1.0, "max_concurrent_uploads": 1},
+                "per_bucket": {"max_concurrent_uploads": 1}
+            }),
+        );
+        let permits = limiter.admit("a", true).unwrap();
+        assert_eq!(permits.len(), 2);
+
+        let error = limiter.admit("b", true).unwrap_err();
+        assert_eq!(retry_after(error), "1");
+        let labels = [
+            ("bucket", "ratelimit-rejected"),
+            ("scope", "bucket"),
+            ("limit", "concurrent_uploads"),
+        ];
+        assert_eq!(testing::metric("s3dedup_rate_limited_total", &labels), 1.0);
+
+        // The rejection left b its request token and its upload permit
+        drop(permits);
+        assert_eq!(limiter.admit("b", true).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retry_after_covers_the_wait() {
+        let limiter = rate_limiter(
+            "ratelimit-retry",
+            json!({
+                "per_client": {"requests_per_sec": 0.5},
+                "per_bucket": {"bytes_per_sec": 1000}
+            }),
+        );
+        limiter.admit("a", false).unwrap();
+        // Just under 2 seconds are left, rounded up
+        let error = limiter.admit("a", false).unwrap_err();
+        assert_eq!(retry_after(error), "2");
+
+        limiter.charge("b", 3500);
+        let error = limiter.admit("b", false).unwrap_err();
+        assert_eq!(retry_after(error), "3");
+        let labels = [
+            ("bucket", "ratelimit-retry"),
+            ("scope", "bucket"),
+            ("limit", "bytes"),
+        ];
+        assert_eq!(testing::metric("s3dedup_rate_limited_total", &labels), 1.0);
+    }
+}
@@ -0,0 +1,250 @@
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::field::Empty;
+use tracing::{Instrument, Span, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+/// Header a request id is accepted from and returned in
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer request ids sent by clients are replaced
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/**
+ * Get id of the request handled by the current task, if any.
+ */
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/**
+ * Middleware assigning an id to every request: the one sent in the
+ * X-Request-Id header, or a fresh one if there is none or it is not
+ * usable. The id is returned in the X-Request-Id header of the response.
+ */
+pub async fn assign(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    // Valid ids are visible ASCII, so they are valid header values
+    let value = HeaderValue::from_str(&id).unwrap();
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, value.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+/**
+ * Accept ids that are safe to log as they are.
+ */
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/**
+ * Headers of a request, to read the trace context of the client from.
+ */
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/**
+ * Span of a request to bucket, for the `TraceLayer` of its router.
+ * Handlers record the path and hash they work on, so that all logs of
+ * the request carry them.
+ *
+ * A request with a W3C `traceparent` header continues the trace of the
+ * client, which then also decides whether the trace is sampled.
+ */
+pub fn make_span(bucket: &str) -> impl Fn(&Request) -> Span + Clone + use<> {
+    let bucket = bucket.to_string();
+    move |request: &Request| {
+        let span = info_span!(
+            "request",
+            bucket = %bucket,
+            request_id = %current().as_deref().unwrap_or("-"),
+            method = %request.method(),
+            uri = %request.uri(),
+            path = Empty,
+            hash = Empty,
+        );
+        let parent = TraceContextPropagator::new().extract(&Headers(request.headers()));
+        if parent.span().span_context().is_valid() {
+            // Fails only if traces are not exported
+            let _ = span.set_parent(parent);
+        }
+        span
+    }
+}
+
+/**
+ * Spawn a task that keeps the request id and span of the current task.
+ */
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = future.instrument(Span::current());
+    match current() {
+        Some(id) => tokio::spawn(REQUEST_ID.scope(id, future)),
+        None => tokio::spawn(future),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::Path;
+    use axum::http::HeaderMap;
+    use axum::routing::get;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tower_http::trace::TraceLayer;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+
+    #[derive(Default)]
+    struct Fields(HashMap<&'static str, String>);
+
+    /**
+     * Layer keeping the fields of every span, including those recorded
+     * after it was created.
+     */
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<Mutex<HashMap<Id, Fields>>>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            self.0.lock().unwrap().insert(id.clone(), fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            if let Some(fields) = self.0.lock().unwrap().get_mut(id) {
+                values.record(fields);
+            }
+        }
+    }
+
+    /**
+     * Handler recording the path in the request span, returning the id of
+     * the request as seen by it and by spawned tasks.
+     */
+    async fn handler(Path(path): Path<String>, headers: HeaderMap) -> String {
+        Span::current().record("path", path.as_str());
+        let spawned = spawn(async { current() }).await.unwrap();
+        assert_eq!(spawned, current());
+        assert_eq!(
+            headers[&REQUEST_ID_HEADER].to_str().ok(),
+            current().as_deref()
+        );
+        current().unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route("/files/{*path}", get(handler))
+            .layer(TraceLayer::new_for_http().make_span_with(make_span("bucket")))
+            .layer(axum::middleware::from_fn(assign))
+    }
+
+    /**
+     * Send a request with the given X-Request-Id, returning the id in the
+     * response header and the one the handler saw.
+     */
+    async fn send(id: Option<&str>) -> (String, String) {
+        let mut request = Request::get("/files/a/b");
+        if let Some(id) = id {
+            request = request.header(&REQUEST_ID_HEADER, id);
+        }
+        let response = router()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let returned = response.headers()[&REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (returned, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn accepts_and_returns_client_ids() {
+        let (returned, seen) = send(Some("client-id.1")).await;
+        assert_eq!(returned, "client-id.1");
+        assert_eq!(seen, "client-id.1");
+    }
+
+    #[tokio::test]
+    async fn generates_missing_or_unusable_ids() {
+        let too_long = "x".repeat(MAX_LENGTH + 1);
+        for id in [None, Some(""), Some("with space"), Some(too_long.as_str())] {
+            let (returned, seen) = send(id).await;
+            assert!(Uuid::parse_str(&returned).is_ok(), "{:?}", id);
+            assert_eq!(seen, returned);
+        }
+        assert_ne!(send(None).await.0, send(None).await.0);
+        assert!(current().is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn request_span_carries_id_and_path() {
+        let spans = SpanFields::default();
+        let subscriber = Registry::default().with(spans.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        send(Some("span-id")).await;
+        let spans = spans.0.lock().unwrap();
+        let fields = spans
+            .values()
+            .find(|fields| fields.0.contains_key("request_id"))
+            .unwrap();
+        assert_eq!(fields.0["bucket"], "bucket");
+        assert_eq!(fields.0["request_id"], "span-id");
+        assert_eq!(fields.0["method"], "GET");
+        assert_eq!(fields.0["uri"], "/files/a/b");
+        assert_eq!(fields.0["path"], "\"a/b\"");
+    }
+}
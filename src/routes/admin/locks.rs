@@ -0,0 +1,52 @@
+use crate::AppState;
+use crate::locks::tracker::LockInfo;
+use axum::Json;
+use axum::extract::State;
+use std::sync::Arc;
+
+#[derive(Debug, serde::Serialize)]
+pub struct LocksResponse {
+    /// Process the locks are tracked by
+    process_id: u32,
+    locks: Vec<LockInfo>,
+}
+
+/**
+ * List locks held or awaited by requests of this process. With file or
+ * lease locks, holders in other processes sharing them are not listed.
+ */
+pub async fn admin_locks(State(state): State<Arc<AppState>>) -> Json<LocksResponse> {
+    Json(LocksResponse {
+        process_id: std::process::id(),
+        locks: state.locks.locks(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locks;
+    use crate::testing;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn lists_held_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+        let key = locks::file_lock("test", "a");
+        state.locks.acquire_exclusive(&key).await.unwrap();
+
+        let Json(response) = admin_locks(State(state.clone())).await;
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["process_id"], std::process::id());
+        let locks = response["locks"].as_array().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0]["key"], key);
+        assert_eq!(locks[0]["mode"], "exclusive");
+        assert_eq!(locks[0]["state"], "held");
+
+        state.locks.release(&key);
+        let Json(response) = admin_locks(State(state)).await;
+        assert!(response.locks.is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use crate::AppState;
+use crate::error::AppError;
+use crate::kvstorage::stats::BucketStats;
+use axum::Json;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use std::sync::Arc;
+
+/// Upper bound of the number of most shared hashes listed
+const MAX_TOP: i64 = 1000;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct StatsQuery {
+    /// Number of most shared hashes to list
+    #[serde(default = "default_top")]
+    top: i64,
+}
+
+fn default_top() -> i64 {
+    10
+}
+
+pub async fn admin_stats(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<StatsQuery>, QueryRejection>,
+) -> Result<Json<BucketStats>, AppError> {
+    let Query(query) = query.map_err(|e| AppError::Validation(e.body_text()))?;
+    if !(0..=MAX_TOP).contains(&query.top) {
+        return Err(AppError::Validation(format!(
+            "top must be between 0 and {}",
+            MAX_TOP
+        )));
+    }
+    let mut stats = state.kvstorage.stats(&state.bucket_name, query.top).await?;
+    stats.quota = state.quota.clone();
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    async fn stats(state: &Arc<AppState>, top: i64) -> Result<BucketStats, AppError> {
+        let query = Ok(Query(StatsQuery { top }));
+        let Json(stats) = admin_stats(State(state.clone()), query).await?;
+        Ok(stats)
+    }
+
+    #[tokio::test]
+    async fn reports_stats_with_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({"quota": {"max_paths": 10}}));
+        let state = testing::app_state(&config).await;
+
+        let stats = stats(&state, MAX_TOP).await.unwrap();
+        assert_eq!(stats.bucket, "test");
+        assert_eq!(stats.paths, 0);
+        assert_eq!(stats.quota.unwrap().max_paths, Some(10));
+    }
+
+    #[tokio::test]
+    async fn rejects_top_out_of_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+        for top in [-1, MAX_TOP + 1] {
+            let result = stats(&state, top).await;
+            assert!(matches!(result, Err(AppError::Validation(_))), "{}", top);
+        }
+    }
+}
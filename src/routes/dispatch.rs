@@ -0,0 +1,227 @@
+use crate::error::AppError;
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::http::Uri;
+use axum::http::header::HOST;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tower::ServiceExt;
+
+/// Prefix of paths addressing a bucket by name, e.g. `/b/{bucket}/ft/version`
+const BUCKET_PREFIX: &str = "/b/";
+
+/**
+ * Bucket served by a listener.
+ */
+#[derive(Clone)]
+pub struct BucketRoute {
+    /// Host header values routed to this bucket
+    pub hosts: Vec<String>,
+    pub router: Router,
+}
+
+/**
+ * Buckets served by one listener, by name. Buckets are added and removed
+ * while the listener keeps serving the others.
+ */
+pub type BucketRoutes = Arc<RwLock<HashMap<String, BucketRoute>>>;
+
+/**
+ * Route a request to a bucket of the listener: by the `/b/{bucket}` path
+ * prefix, by the Host header, or to the default bucket of the listener.
+ * Requests for a host no bucket is configured for are misdirected.
+ */
+pub async fn dispatch(State(routes): State<BucketRoutes>, mut request: Request) -> Response {
+    let router = {
+        let routes = routes.read().unwrap();
+        match select(&routes, &mut request) {
+            Some(router) => router,
+            None => {
+                let message = format!(
+                    "No bucket for: {}{}",
+                    host(&request).unwrap_or_default(),
+                    request.uri().path()
+                );
+                let error = match host(&request) {
+                    Some(_) => AppError::Misdirected(message),
+                    None => AppError::NotFound(message),
+                };
+                return error.into_response();
+            }
+        }
+    };
+    match router.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+/**
+ * Find the bucket for request, stripping the bucket prefix from its path.
+ */
+fn select(routes: &HashMap<String, BucketRoute>, request: &mut Request) -> Option<Router> {
+    if let Some(rest) = request.uri().path().strip_prefix(BUCKET_PREFIX) {
+        let (name, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if let Some(route) = routes.get(name) {
+            let router = route.router.clone();
+            let uri = strip_path(request.uri(), path)?;
+            *request.uri_mut() = uri;
+            return Some(router);
+        }
+    }
+
+    if let Some(host) = host(request) {
+        let route = routes.values().find(|route| {
+            route
+                .hosts
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(host))
+        });
+        if let Some(route) = route {
+            return Some(route.router.clone());
+        }
+    }
+
+    default_route(routes).map(|route| route.router.clone())
+}
+
+/**
+ * Bucket answering requests for hosts no bucket is configured for: the one
+ * bucket of the listener without hosts. Buckets with hosts only answer
+ * requests for them, even when they are left alone on the listener.
+ */
+fn default_route(routes: &HashMap<String, BucketRoute>) -> Option<&BucketRoute> {
+    let mut without_hosts = routes.values().filter(|route| route.hosts.is_empty());
+    match (without_hosts.next(), without_hosts.next()) {
+        (Some(route), None) => Some(route),
+        _ => None,
+    }
+}
+
+/**
+ * Host the request was sent to, without the port.
+ */
+fn host(request: &Request) -> Option<&str> {
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => request.headers().get(HOST)?.to_str().ok()?,
+    };
+    match host.rsplit_once(':') {
+        // IPv6 addresses contain colons themselves, but are in brackets
+        Some((name, port))
+            if port.chars().all(|c| c.is_ascii_digit())
+                && (name.ends_with(']') || !name.contains(':')) =>
+        {
+            Some(name)
+        }
+        _ => Some(host),
+    }
+}
+
+/**
+ * Replace the path of uri, keeping its query.
+ */
+fn strip_path(uri: &Uri, path: &str) -> Option<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+
+    fn route(name: &'static str, hosts: &[&str]) -> (String, BucketRoute) {
+        let route = BucketRoute {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            router: Router::new().fallback(get(move |uri: Uri| async move {
+                format!("{} {}", name, uri)
+            })),
+        };
+        (name.to_string(), route)
+    }
+
+    fn routes(buckets: Vec<(String, BucketRoute)>) -> BucketRoutes {
+        Arc::new(RwLock::new(buckets.into_iter().collect()))
+    }
+
+    /**
+     * Status and body of a request to the listener.
+     */
+    async fn send(routes: &BucketRoutes, host: Option<&str>, path: &str) -> (StatusCode, String) {
+        let mut request = Request::builder().uri(path);
+        if let Some(host) = host {
+            request = request.header(HOST, host);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = dispatch(State(routes.clone()), request).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn routes_by_prefix_and_host() {
+        let routes = routes(vec![route("a", &["a.example"]), route("b", &["b.example"])]);
+
+        let (status, body) = send(&routes, Some("b.example"), "/b/a/ft/version?x=1").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, "a /ft/version?x=1")
+        );
+        let (_, body) = send(&routes, Some("A.example:8080"), "/ft/version").await;
+        assert_eq!(body, "a /ft/version");
+        let (_, body) = send(&routes, Some("b.example"), "/ft/version").await;
+        assert_eq!(body, "b /ft/version");
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_go_to_the_only_bucket_without_hosts() {
+        let routes = routes(vec![route("a", &["a.example"]), route("b", &[])]);
+
+        let (_, body) = send(&routes, Some("a.example"), "/ft/version").await;
+        assert_eq!(body, "a /ft/version");
+        let (_, body) = send(&routes, Some("other.example"), "/ft/version").await;
+        assert_eq!(body, "b /ft/version");
+        let (_, body) = send(&routes, None, "/ft/version").await;
+        assert_eq!(body, "b /ft/version");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_hosts_without_a_default_bucket() {
+        let routes = routes(vec![route("a", &[]), route("b", &[])]);
+
+        let (status, _) = send(&routes, Some("other.example"), "/ft/version").await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        let (status, _) = send(&routes, None, "/ft/version").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = send(&routes, Some("other.example"), "/b/b/ft/version").await;
+        assert_eq!(body, "b /ft/version");
+    }
+
+    #[tokio::test]
+    async fn removed_bucket_does_not_fall_back_to_the_other() {
+        let routes = routes(vec![route("a", &["a.example"]), route("b", &["b.example"])]);
+        routes.write().unwrap().remove("b");
+
+        let (status, _) = send(&routes, Some("b.example"), "/ft/version").await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        let (status, _) = send(&routes, Some("b.example"), "/b/b/ft/version").await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        let (_, body) = send(&routes, Some("a.example"), "/ft/version").await;
+        assert_eq!(body, "a /ft/version");
+    }
+}
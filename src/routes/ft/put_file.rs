@@ -1,56 +1,499 @@
-use crate::{locks, AppState};
-use axum::Json;
+use crate::access_log::Upload;
+use crate::auth::Identity;
+use crate::auth::acl::{self, Permission};
+use crate::blobstorage::StagedBlob;
+use crate::error::AppError;
+use crate::kvstorage::stats::Usage;
+use crate::routes::ft::{LastModifiedQuery, utils};
+use crate::{AppState, locks, metrics, quota};
+use axum::Extension;
+use axum::body::Body;
+use axum::extract::rejection::{ExtensionRejection, QueryRejection};
 use axum::extract::{Path, Query, State};
-use std::sync::Arc;
-use axum::http::{HeaderMap, Response, StatusCode};
+use axum::http::HeaderMap;
+use axum::http::header::CONTENT_LENGTH;
 use axum::response::IntoResponse;
-use tracing::{debug, error};
-use crate::routes::ft::{utils, LastModifiedQuery};
-
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{Instrument, Span, debug, debug_span, field};
 
 pub async fn ft_put_file(
     State(state): State<Arc<AppState>>,
     Path(path): Path<String>,
-    Query(query): Query<LastModifiedQuery>,
-) -> impl IntoResponse {
+    // Only set when the bucket requires credentials
+    identity: Result<Extension<Identity>, ExtensionRejection>,
+    query: Result<Query<LastModifiedQuery>, QueryRejection>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, AppError> {
+    let path = acl::normalize_path(&path)?;
+    Span::current().record("path", field::display(&path));
+    if let Some(acl) = &state.acl {
+        acl.check(identity.as_deref().ok(), Permission::Write, &path)?;
+    }
+    let Query(query) = query.map_err(|e| AppError::Validation(e.body_text()))?;
     debug!("timestamp: {}", query.last_modified);
-    let timestamp = utils::conv_rfc2822_to_unix_timestamp(&query.last_modified);
+    let timestamp = utils::conv_rfc2822_to_unix_timestamp(&query.last_modified)?;
 
-    if let Err(e) = timestamp {
-        error!("Failed to parse last_modified: {}", e);
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Failed to parse last_modified".to_string())
-            .unwrap();
+    let max_size = state.max_upload_bytes;
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let Some(length) = content_length
+        && length > max_size
+    {
+        return Err(too_large(max_size));
     }
-    let timestamp = timestamp.unwrap();
 
-    state.locks.acquire_exclusive(&*locks::file_lock(&state.bucket_name, &path));
-    let current_modified = state.kvstorage.get_modified(&state.bucket_name, &path).await;
-    if let Err(e) = current_modified {
-        error!("Failed to get current modified: {}", e);
-        state.locks.release(&*locks::file_lock(&state.bucket_name, &path));
+    // Written to the blob storage as it arrives, so memory use does not
+    // depend on the upload size. Awaiting each write slows down reading
+    // from the client when the storage falls behind.
+    let mut staged = state.blobstorage.stage().await?;
+    let mut hasher = Sha256::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk =
+            chunk.map_err(|e| AppError::Validation(format!("Failed to read body: {}", e)))?;
+        if staged.size() + chunk.len() as u64 > max_size {
+            return Err(too_large(max_size));
+        }
+        hasher.update(&chunk);
+        staged.write(&chunk).await?;
+    }
 
-        return Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("Failed to get current modified".to_string())
-            .unwrap();
+    let hash = hex::encode(hasher.finalize());
+    Span::current().record("hash", field::display(&hash));
+    if let Some(checksum) = headers.get("SHA256-Checksum")
+        && !checksum.as_bytes().eq_ignore_ascii_case(hash.as_bytes())
+    {
+        return Err(AppError::Integrity(format!(
+            "Uploaded data does not match SHA256-Checksum, its hash is: {}",
+            hash
+        )));
     }
-    let current_modified = current_modified.unwrap();
+    let bytes = staged.size();
+
+    // The locked part runs in its own task, so that a client disconnecting
+    // mid-request cannot drop it halfway and leave the locks held.
+    let in_flight = state.in_flight.clone();
+    let dedup_hit = in_flight
+        .spawn(put_file_locked(state, path, timestamp, hash, staged))
+        .await?;
+
+    Ok((
+        Extension(Upload { bytes, dedup_hit }),
+        [("Last-Modified", query.last_modified)],
+    ))
+}
+
+fn too_large(max_size: u64) -> AppError {
+    AppError::PayloadTooLarge(format!("Upload exceeds the limit of {} bytes", max_size))
+}
+
+async fn put_file_locked(
+    state: Arc<AppState>,
+    path: String,
+    timestamp: i64,
+    hash: String,
+    staged: StagedBlob,
+) -> Result<Option<bool>, AppError> {
+    let file_lock = locks::file_lock(&state.bucket_name, &path);
+    state.locks.acquire_exclusive(&file_lock).await?;
+    let result = store_file(&state, &path, timestamp, &hash, staged).await;
+    state.locks.release(&file_lock);
+    result
+}
 
-    // If the uploaded file is younger than the current one, return 200 OK
+/**
+ * Store the uploaded file unless the current version is at least as new.
+ * Returns whether its blob was stored already, if the file was stored.
+ * Caller must hold an exclusive lock on the file.
+ */
+async fn store_file(
+    state: &AppState,
+    path: &str,
+    timestamp: i64,
+    hash: &str,
+    staged: StagedBlob,
+) -> Result<Option<bool>, AppError> {
+    let current_modified = state
+        .kvstorage
+        .get_modified(&state.bucket_name, path)
+        .await?;
+
+    // If the uploaded file is not younger than the current one, keep the current one
     if current_modified >= timestamp {
-        state.locks.release(&*locks::file_lock(&state.bucket_name, &path));
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header("Last-Modified", query.last_modified)
-            .body("".to_string())
+        return Ok(None);
+    }
+    let bytes = staged.size();
+    let dedup_hit = link_file(state, path, hash, staged, timestamp).await?;
+    metrics::record_received(&state.bucket_name, bytes as usize);
+    Ok(Some(dedup_hit))
+}
+
+/**
+ * Point path at the blob with given hash and drop the reference to the blob
+ * it pointed at before, keeping track of the bucket usage.
+ * Returns whether the blob was stored already.
+ * Caller must hold an exclusive lock on the file.
+ */
+async fn link_file(
+    state: &AppState,
+    path: &str,
+    hash: &str,
+    staged: StagedBlob,
+    modified: i64,
+) -> Result<bool, AppError> {
+    let bucket = &state.bucket_name;
+    let old_hash = state.kvstorage.get_ref_file(bucket, path).await?;
+
+    // Uploading the current contents again stores nothing
+    let mut dedup_hit = true;
+    if old_hash != hash {
+        let old_size = if old_hash.is_empty() {
+            0
+        } else {
+            // Sizes never change, no lock needed
+            state
+                .kvstorage
+                .get_blob_size(bucket, &old_hash)
+                .await?
+                .unwrap_or(0)
+        };
+        let delta = Usage {
+            logical_bytes: staged.size() as i64 - old_size,
+            physical_bytes: 0,
+            paths: old_hash.is_empty() as i64,
+        };
+        let hash_lock = locks::hash_lock(bucket, hash);
+        dedup_hit = quota::charge(state, &delta, async {
+            state.locks.acquire_exclusive(&hash_lock).await?;
+            let linked = link_hash(state, hash, staged).await;
+            state.locks.release(&hash_lock);
+            let dedup_hit = linked?;
+            state.kvstorage.set_ref_file(bucket, path, hash).await?;
+            Ok(dedup_hit)
+        })
+        .await?;
+    }
+    state.kvstorage.set_modified(bucket, path, modified).await?;
+
+    if !old_hash.is_empty() && old_hash != hash {
+        let hash_lock = locks::hash_lock(bucket, &old_hash);
+        state.locks.acquire_exclusive(&hash_lock).await?;
+        // Logged with the hash it works on, not the uploaded one
+        let unlinked = unlink_hash(state, &old_hash)
+            .instrument(debug_span!("unlink", hash = %old_hash))
+            .await;
+        state.locks.release(&hash_lock);
+        unlinked?;
+    }
+    Ok(dedup_hit)
+}
+
+/**
+ * Add a reference to hash, storing the staged blob if it is not referenced
+ * yet. Otherwise the staged blob is discarded.
+ * Returns whether the blob was stored already.
+ * Caller must hold an exclusive lock on the hash.
+ */
+async fn link_hash(state: &AppState, hash: &str, staged: StagedBlob) -> Result<bool, AppError> {
+    let bucket = &state.bucket_name;
+    let fence = state.locks.fence(&locks::hash_lock(bucket, hash))?;
+    let size = staged.size() as i64;
+    let ref_count = state.kvstorage.get_ref_count(bucket, hash).await?;
+    if ref_count == 0 {
+        let stored = Usage {
+            physical_bytes: size,
+            ..Usage::default()
+        };
+        quota::charge(state, &stored, async {
+            state.blobstorage.commit(staged, hash).await?;
+            state
+                .kvstorage
+                .increment_ref_count(bucket, hash, fence.as_ref())
+                .await
+        })
+        .await?;
+        metrics::record_stored(bucket, size as usize);
+    } else {
+        state
+            .kvstorage
+            .increment_ref_count(bucket, hash, fence.as_ref())
+            .await?;
+    }
+
+    // Blobs stored before sizes were recorded count once their size is known
+    if state.kvstorage.set_blob_size(bucket, hash, size).await? && ref_count > 0 {
+        let delta = Usage {
+            logical_bytes: size * ref_count as i64,
+            physical_bytes: size,
+            paths: 0,
+        };
+        state.kvstorage.add_usage(bucket, &delta).await?;
+    }
+    Ok(ref_count > 0)
+}
+
+/**
+ * Remove a reference to hash, deleting the blob once nothing references it.
+ * Caller must hold an exclusive lock on the hash.
+ */
+async fn unlink_hash(state: &AppState, hash: &str) -> Result<(), AppError> {
+    let bucket = &state.bucket_name;
+    let fence = state.locks.fence(&locks::hash_lock(bucket, hash))?;
+    state
+        .kvstorage
+        .decrement_ref_count(bucket, hash, fence.as_ref())
+        .await?;
+    if state.kvstorage.get_ref_count(bucket, hash).await? == 0 {
+        state.blobstorage.delete(hash).await?;
+        if let Some(size) = state.kvstorage.get_blob_size(bucket, hash).await? {
+            let delta = Usage {
+                physical_bytes: -size,
+                ..Usage::default()
+            };
+            state.kvstorage.add_usage(bucket, &delta).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn hash_of(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    /**
+     * Store data at path like an upload does, after the body was read.
+     */
+    async fn try_upload(
+        state: &Arc<AppState>,
+        path: &str,
+        data: &[u8],
+        modified: i64,
+    ) -> Result<Option<bool>, AppError> {
+        let mut staged = state.blobstorage.stage().await.unwrap();
+        staged.write(data).await.unwrap();
+        put_file_locked(
+            state.clone(),
+            path.to_string(),
+            modified,
+            hash_of(data),
+            staged,
+        )
+        .await
+    }
+
+    async fn upload(state: &Arc<AppState>, path: &str, data: &[u8], modified: i64) -> Option<bool> {
+        try_upload(state, path, data, modified).await.unwrap()
+    }
+
+    /**
+     * Tracked usage as (logical bytes, physical bytes, paths).
+     */
+    async fn usage(state: &AppState) -> (i64, i64, i64) {
+        let usage = state
+            .kvstorage
+            .get_usage(&state.bucket_name)
+            .await
+            .unwrap()
             .unwrap();
+        (usage.logical_bytes, usage.physical_bytes, usage.paths)
+    }
+
+    async fn ref_count(state: &AppState, data: &[u8]) -> i32 {
+        state
+            .kvstorage
+            .get_ref_count(&state.bucket_name, &hash_of(data))
+            .await
+            .unwrap()
+    }
+
+    async fn stored(state: &AppState, data: &[u8]) -> bool {
+        state.blobstorage.exists(&hash_of(data)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_blob_once_per_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+
+        assert_eq!(upload(&state, "a", b"same", 1).await, Some(false));
+        assert_eq!(upload(&state, "b", b"same", 1).await, Some(true));
+        assert_eq!(ref_count(&state, b"same").await, 2);
+        assert!(stored(&state, b"same").await);
     }
 
-    // tmp
-    Response::builder()
-        .status(StatusCode::OK)
-        .body("".to_string())
-        .unwrap()
+    #[tokio::test]
+    async fn overwriting_deletes_unreferenced_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+
+        upload(&state, "a", b"old", 1).await;
+        upload(&state, "a", b"new", 2).await;
+        assert_eq!(ref_count(&state, b"old").await, 0);
+        assert!(!stored(&state, b"old").await);
+        assert_eq!(ref_count(&state, b"new").await, 1);
+        assert!(stored(&state, b"new").await);
+    }
+
+    #[tokio::test]
+    async fn ignores_older_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+
+        upload(&state, "a", b"new", 2).await;
+        assert_eq!(upload(&state, "a", b"old", 1).await, None);
+        assert_eq!(ref_count(&state, b"old").await, 0);
+        assert!(!stored(&state, b"old").await);
+        assert_eq!(ref_count(&state, b"new").await, 1);
+    }
+
+    #[tokio::test]
+    async fn counts_received_bytes_of_stored_files_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({"name": "received"}));
+        let state = testing::app_state(&config).await;
+        let received =
+            || testing::metric("s3dedup_received_bytes_total", &[("bucket", "received")]);
+        let stored = || testing::metric("s3dedup_stored_bytes_total", &[("bucket", "received")]);
+
+        upload(&state, "a", b"new", 2).await;
+        upload(&state, "b", b"new", 2).await;
+        upload(&state, "a", b"older", 1).await;
+        assert_eq!(received(), 6.0);
+        assert_eq!(stored(), 3.0);
+    }
+
+    #[tokio::test]
+    async fn tracks_usage_on_link_and_unlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+
+        upload(&state, "a", b"aaaa", 1).await;
+        assert_eq!(usage(&state).await, (4, 4, 1), "new path");
+        upload(&state, "b", b"aaaa", 1).await;
+        assert_eq!(usage(&state).await, (8, 4, 2), "shared blob");
+        upload(&state, "a", b"xy", 2).await;
+        assert_eq!(usage(&state).await, (6, 6, 2), "overwrite");
+        upload(&state, "b", b"xy", 2).await;
+        assert_eq!(usage(&state).await, (4, 2, 2), "unlink to zero");
+        upload(&state, "b", b"xy", 3).await;
+        assert_eq!(usage(&state).await, (4, 2, 2), "same contents");
+
+        let counted = state.kvstorage.count_usage("test").await.unwrap();
+        assert_eq!((counted.logical_bytes, counted.physical_bytes), (4, 2));
+        assert_eq!(counted.paths, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_uploads_over_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(
+            dir.path(),
+            json!({"quota": {"max_physical_bytes": 5, "max_paths": 2}}),
+        );
+        let state = testing::app_state(&config).await;
+        upload(&state, "a", b"aaaa", 1).await;
+
+        let result = try_upload(&state, "b", b"bb", 1).await;
+        assert!(matches!(result, Err(AppError::QuotaExceeded(_))));
+        let response = result.unwrap_err().into_response();
+        assert_eq!(response.status(), 507);
+        assert!(!stored(&state, b"bb").await);
+        assert_eq!(usage(&state).await, (4, 4, 1));
+
+        // Deduplicated content and shrinking stay possible
+        upload(&state, "b", b"aaaa", 1).await;
+        let result = try_upload(&state, "c", b"aaaa", 1).await;
+        assert!(matches!(result, Err(AppError::QuotaExceeded(_))));
+        upload(&state, "a", b"a", 2).await;
+        assert_eq!(usage(&state).await, (5, 5, 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_stay_within_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(
+            dir.path(),
+            json!({"quota": {"max_logical_bytes": 10, "max_paths": 8}}),
+        );
+        let state = testing::app_state(&config).await;
+
+        let uploads: Vec<_> = (0..16)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let data = format!("{:02}", i);
+                    try_upload(&state, &i.to_string(), data.as_bytes(), 1).await
+                })
+            })
+            .collect();
+        let mut stored = 0;
+        for upload in uploads {
+            match upload.await.unwrap() {
+                Ok(_) => stored += 1,
+                Err(AppError::QuotaExceeded(_)) => {}
+                Err(e) => panic!("upload failed: {}", e),
+            }
+        }
+        assert_eq!(stored, 5);
+        assert_eq!(usage(&state).await, (10, 10, 5));
+    }
+
+    #[tokio::test]
+    async fn streams_large_uploads_in_flat_memory() {
+        static CHUNK: [u8; 1 << 20] = [7; 1 << 20];
+        const CHUNKS: u64 = 2048;
+        const MAX_GROWTH: usize = 64 << 20;
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::bucket_config(dir.path(), json!({"max_upload_bytes": 4u64 << 30}));
+        let state = testing::app_state(&config).await;
+        let router = axum::Router::new()
+            .route("/ft/files/{*path}", axum::routing::put(ft_put_file))
+            .with_state(state.clone());
+        let chunks =
+            (0..CHUNKS).map(|_| Ok::<_, std::io::Error>(axum::body::Bytes::from_static(&CHUNK)));
+        let request = axum::extract::Request::put(
+            "/ft/files/large?last_modified=Mon,%2001%20Jan%202024%2000:00:00%20GMT",
+        )
+        .body(Body::from_stream(futures_util::stream::iter(chunks)))
+        .unwrap();
+
+        let before = testing::reset_peak_allocated();
+        let response = tower::ServiceExt::oneshot(router, request).await.unwrap();
+        let growth = testing::peak_allocated() - before;
+        assert_eq!(response.status(), 200);
+        assert!(growth < MAX_GROWTH, "allocated {} bytes more", growth);
+        let (logical_bytes, _, _) = usage(&state).await;
+        assert_eq!(logical_bytes as u64, CHUNKS * CHUNK.len() as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn relinking_while_unlinking_keeps_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = testing::app_state(&testing::bucket_config(dir.path(), json!({}))).await;
+
+        for i in 0..20 {
+            let data = format!("content {}", i);
+            let data = data.as_bytes();
+            let (old, new) = (format!("old{}", i), format!("new{}", i));
+            upload(&state, &old, data, 1).await;
+            // One drops the last reference while the other adds one
+            tokio::join!(
+                upload(&state, &old, b"other", 2),
+                upload(&state, &new, data, 1),
+            );
+            assert_eq!(ref_count(&state, data).await, 1);
+            assert!(stored(&state, data).await, "blob of {} was deleted", new);
+        }
+    }
 }
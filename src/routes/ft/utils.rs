@@ -1,7 +1,8 @@
-use std::error::Error;
+use crate::error::AppError;
 use chrono::DateTime;
 
-pub fn conv_rfc2822_to_unix_timestamp(rfc2822: &str) -> Result<i64, Box<dyn Error>> {
-    let dt = DateTime::parse_from_rfc2822(rfc2822)?;
+pub fn conv_rfc2822_to_unix_timestamp(rfc2822: &str) -> Result<i64, AppError> {
+    let dt = DateTime::parse_from_rfc2822(rfc2822)
+        .map_err(|e| AppError::Validation(format!("Failed to parse last_modified: {}", e)))?;
     Ok(dt.timestamp())
 }
\ No newline at end of file
@@ -0,0 +1,188 @@
+use crate::AppState;
+use crate::error::AppError;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Probes taking longer than this count as failed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Ready,
+    NotReady,
+    Draining,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    status: Status,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    status: Status,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadyResponse {
+    status: Status,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/**
+ * Liveness: answers as long as the process serves requests.
+ */
+pub async fn healthz() -> Json<HealthResponse> {
+    Json(HealthResponse { status: Status::Ok })
+}
+
+/**
+ * Readiness: probes the storages of the bucket.
+ */
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let (kvstorage, blobstorage) = tokio::join!(
+        check(state.kvstorage.ping(&state.bucket_name)),
+        check(state.blobstorage.probe())
+    );
+    let ready = kvstorage.error.is_none() && blobstorage.error.is_none();
+    let response = ReadyResponse {
+        status: if ready {
+            Status::Ready
+        } else {
+            Status::NotReady
+        },
+        checks: BTreeMap::from([("kvstorage", kvstorage), ("blobstorage", blobstorage)]),
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(response))
+}
+
+/**
+ * Readiness of a bucket that is being stopped, so that no new requests
+ * are sent its way.
+ */
+pub async fn readyz_draining() -> impl IntoResponse {
+    let response = ReadyResponse {
+        status: Status::Draining,
+        checks: BTreeMap::new(),
+    };
+    (StatusCode::SERVICE_UNAVAILABLE, Json(response))
+}
+
+async fn check(probe: impl Future<Output = Result<(), AppError>>) -> CheckResult {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(AppError::StorageUnavailable(format!(
+            "no answer within {:?}",
+            PROBE_TIMEOUT
+        ))),
+    };
+    let latency_ms = start.elapsed().as_micros() as f64 / 1000.0;
+    match result {
+        Ok(()) => CheckResult {
+            status: Status::Ok,
+            latency_ms,
+            error: None,
+        },
+        Err(e) => CheckResult {
+            status: Status::Error,
+            latency_ms,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::routing::get;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    async fn get_json(router: Router, uri: &str) -> (StatusCode, Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn router(dir: &std::path::Path) -> (Router, Arc<AppState>) {
+        let state = testing::app_state(&testing::bucket_config(dir, json!({}))).await;
+        let router = Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(state.clone());
+        (router, state)
+    }
+
+    #[tokio::test]
+    async fn healthz_answers_ok() {
+        let dir = tempfile::tempdir().unwrap();
+        let (router, _) = router(dir.path()).await;
+        let (status, body) = get_json(router, "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"status": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_every_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let (router, _) = router(dir.path()).await;
+        let (status, body) = get_json(router, "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        for check in ["kvstorage", "blobstorage"] {
+            assert_eq!(body["checks"][check]["status"], "ok", "{}", check);
+            assert!(body["checks"][check]["latency_ms"].is_f64(), "{}", check);
+            assert!(body["checks"][check].get("error").is_none(), "{}", check);
+        }
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_failed_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (router, state) = router(dir.path()).await;
+        std::fs::remove_dir_all(dir.path().join("blobs")).unwrap();
+        state.kvstorage.close().await;
+
+        let (status, body) = get_json(router, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        for check in ["kvstorage", "blobstorage"] {
+            assert_eq!(body["checks"][check]["status"], "error", "{}", check);
+            assert!(body["checks"][check]["error"].is_string(), "{}", check);
+        }
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_draining() {
+        let router = Router::new().route("/readyz", get(readyz_draining));
+        let (status, body) = get_json(router, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, json!({"status": "draining", "checks": {}}));
+    }
+}
@@ -0,0 +1,35 @@
+use crate::error::AppError;
+use crate::metrics;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+/**
+ * Metrics of all buckets in the Prometheus text format.
+ */
+pub async fn metrics() -> Result<impl IntoResponse, AppError> {
+    let body = metrics::render().map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn renders_the_text_format() {
+        metrics::record_rate_limited("metrics-route", "client", "requests");
+        let response = metrics().await.unwrap().into_response();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            prometheus::TEXT_FORMAT
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            "s3dedup_rate_limited_total{bucket=\"metrics-route\",limit=\"requests\",scope=\"client\"} 1"
+        ));
+    }
+}
@@ -1 +1,15 @@
+use crate::error::AppError;
+use axum::http::Uri;
+
+pub mod admin;
+pub mod dispatch;
 pub mod ft;
+pub mod health;
+pub mod metrics;
+
+/**
+ * Handler for requests not matching any route.
+ */
+pub async fn fallback(uri: Uri) -> AppError {
+    AppError::NotFound(format!("No route for: {}", uri.path()))
+}
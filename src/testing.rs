@@ -0,0 +1,204 @@
+use crate::AppState;
+use crate::config::{BucketConfig, Config};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider, SpanData, SpanExporter};
+use serde_json::{Value, json};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::Level;
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+
+/**
+ * Bucket configuration as read from a file, with storages below dir.
+ * Fields of extra are added to or replace the defaults.
+ */
+pub fn bucket_config(dir: &Path, extra: Value) -> BucketConfig {
+    let mut config = json!({
+        "name": "test",
+        "address": "127.0.0.1",
+        "port": 3000,
+        "kvstorage_type": "sqlite",
+        "sqlite": {
+            "path": dir.join("kv.db").to_str().unwrap(),
+            "pool_size": 5
+        },
+        "filesystem": {
+            "path": dir.join("blobs").to_str().unwrap()
+        },
+        "locks_type": "memory",
+        "locks_timeout_secs": 5
+    });
+    let fields = config.as_object_mut().unwrap();
+    for (key, value) in extra.as_object().unwrap() {
+        fields.insert(key.clone(), value.clone());
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+/**
+ * App state of a bucket with storages below dir, set up like a server does.
+ */
+pub async fn app_state(config: &BucketConfig) -> Arc<AppState> {
+    let state = AppState::new(config).await.unwrap();
+    state.kvstorage.setup().await.unwrap();
+    state.kvstorage.init_usage(&config.name).await.unwrap();
+    Arc::new(state)
+}
+
+/**
+ * Configuration with only the given bucket, as the commands take it.
+ */
+pub fn config(bucket: BucketConfig) -> Config {
+    Config {
+        logging: serde_json::from_value(json!({"level": "info", "json": false})).unwrap(),
+        buckets: vec![bucket],
+    }
+}
+
+/**
+ * Current value of a registered metric with the given labels: the value of
+ * a counter or gauge, or the number of observations of a histogram.
+ * Panics for other metric types. Metrics are global, so tests use bucket
+ * names of their own.
+ */
+pub fn metric(name: &str, labels: &[(&str, &str)]) -> f64 {
+    let families = prometheus::gather();
+    let Some(family) = families.iter().find(|family| family.name() == name) else {
+        return 0.0;
+    };
+    let matches = |metric: &&prometheus::proto::Metric| {
+        labels.iter().all(|(name, value)| {
+            metric
+                .get_label()
+                .iter()
+                .any(|label| label.name() == *name && label.value() == *value)
+        })
+    };
+    let Some(metric) = family.get_metric().iter().find(matches) else {
+        return 0.0;
+    };
+    match family.get_field_type() {
+        prometheus::proto::MetricType::COUNTER => metric.get_counter().get_value(),
+        prometheus::proto::MetricType::GAUGE => metric.get_gauge().get_value(),
+        prometheus::proto::MetricType::HISTOGRAM => {
+            metric.get_histogram().get_sample_count() as f64
+        }
+        other => panic!("{} is a {:?}, which tests do not read", name, other),
+    }
+}
+
+/**
+ * Exporter keeping the spans in memory.
+ */
+#[derive(Clone, Debug, Default)]
+struct Exported(Arc<Mutex<Vec<SpanData>>>);
+
+impl SpanExporter for Exported {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.0.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+static TRACING: OnceLock<(SdkTracerProvider, Exported)> = OnceLock::new();
+
+/**
+ * Install the global subscriber exporting spans to OpenTelemetry, once for
+ * all tests of the binary. It is global like in production, as spans of
+ * SQLite queries are closed on threads of their own. Nothing is sampled
+ * unless the client sampled its trace.
+ */
+pub fn init_tracing() {
+    TRACING.get_or_init(|| {
+        let exported = Exported::default();
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(0.0)));
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exported.clone())
+            .with_sampler(sampler)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("s3dedup"))
+                .with_filter(Targets::new().with_target("s3dedup", Level::INFO)),
+        );
+        tracing::subscriber::set_global_default(subscriber)
+            .expect("the global subscriber of tests is installed by init_tracing only");
+        (provider, exported)
+    });
+}
+
+/**
+ * Spans exported since init_tracing. Tests share them, so each test
+ * looks at the spans of its own traces only.
+ */
+pub fn exported_spans() -> Vec<SpanData> {
+    let (provider, exported) = TRACING.get().expect("init_tracing was not called");
+    provider.force_flush().unwrap();
+    exported.0.lock().unwrap().clone()
+}
+
+/**
+ * Allocator of the tests, keeping track of the bytes allocated by the
+ * whole process, so that tests can bound the memory use of a request.
+ */
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+impl CountingAllocator {
+    fn allocated(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            Self::allocated(new_size);
+        }
+        new
+    }
+}
+
+/**
+ * Start measuring the peak of allocated bytes from now on.
+ * Returns the bytes allocated now.
+ */
+pub fn reset_peak_allocated() -> usize {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(allocated, Ordering::Relaxed);
+    allocated
+}
+
+/**
+ * Most bytes allocated at once since reset_peak_allocated.
+ * Tests running at the same time count as well.
+ */
+pub fn peak_allocated() -> usize {
+    PEAK_ALLOCATED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,399 @@
+use axum::serve::Listener;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error, info};
+
+/// How often certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// Connections not completing the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf certificate first
+    pub cert_path: String,
+    /// PEM private key of the certificate
+    pub key_path: String,
+    /// PEM CA certificates. When set, clients must present a certificate
+    /// signed by one of them.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
+impl TlsConfig {
+    fn paths(&self) -> impl Iterator<Item = &str> {
+        [
+            Some(&self.cert_path),
+            Some(&self.key_path),
+            self.client_ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+    }
+
+    /**
+     * Modification times of all files, to notice when they change.
+     */
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn load(&self) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("{}: {}", self.cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| format!("{}: {}", self.key_path, e))?;
+
+        let builder = match &self.client_ca_path {
+            None => ServerConfig::builder().with_no_client_auth(),
+            Some(client_ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca_path)
+                    .map_err(|e| format!("{}: {}", client_ca_path, e))?
+                {
+                    roots.add(cert.map_err(|e| format!("{}: {}", client_ca_path, e))?)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Connection that completed the handshake, with its peer address
+type Handshaken<L> = (TlsStream<<L as Listener>::Io>, <L as Listener>::Addr);
+
+/**
+ * Listener terminating TLS, for use with `axum::serve`.
+ *
+ * Handshakes run in their own tasks, so that slow clients cannot hold up
+ * accepting others. Certificates are reloaded when their files change.
+ */
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    handshakes: JoinSet<Option<Handshaken<L>>>,
+    reload: JoinHandle<()>,
+}
+
+impl<L> TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Debug,
+{
+    pub fn new(listener: L, config: &TlsConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_reload_interval(listener, config, RELOAD_INTERVAL)
+    }
+
+    fn with_reload_interval(
+        listener: L,
+        config: &TlsConfig,
+        reload_interval: Duration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let acceptor = Arc::new(RwLock::new(config.load().map_err(|e| e.to_string())?));
+        let reload = tokio::spawn(reload_certificates(
+            config.clone(),
+            acceptor.clone(),
+            reload_interval,
+        ));
+        Ok(TlsListener {
+            inner: listener,
+            acceptor,
+            handshakes: JoinSet::new(),
+            reload,
+        })
+    }
+}
+
+impl<L: Listener> Drop for TlsListener<L> {
+    fn drop(&mut self) {
+        self.reload.abort();
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Debug,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                // Retries on errors by itself
+                (stream, addr) = self.inner.accept() => {
+                    let acceptor = self.acceptor.read().unwrap().clone();
+                    self.handshakes.spawn(handshake(acceptor, stream, addr));
+                }
+                Some(Ok(Some(accepted))) = self.handshakes.join_next() => return accepted,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+async fn handshake<Io, Addr>(
+    acceptor: TlsAcceptor,
+    stream: Io,
+    addr: Addr,
+) -> Option<(TlsStream<Io>, Addr)>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+    Addr: Debug,
+{
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some((stream, addr)),
+        Ok(Err(e)) => {
+            debug!("TLS handshake with: {:?} failed: {}", addr, e);
+            None
+        }
+        Err(_) => {
+            debug!("TLS handshake with: {:?} timed out", addr);
+            None
+        }
+    }
+}
+
+/**
+ * Reload the certificates whenever their files change, keeping the
+ * current ones if the new ones cannot be loaded.
+ */
+async fn reload_certificates(
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    every: Duration,
+) {
+    let mut modified = config.modified();
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let current = config.modified();
+        if current == modified {
+            continue;
+        }
+        match config.load() {
+            Ok(reloaded) => {
+                *acceptor.write().unwrap() = reloaded;
+                info!("Reloaded TLS certificate: {}", config.cert_path);
+            }
+            // Possibly caught mid-update, retried once the files change again
+            Err(e) => error!(
+                "Failed to reload TLS certificate: {}: {}",
+                config.cert_path, e
+            ),
+        }
+        modified = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    /**
+     * Certificate authority issuing the server and client certificates.
+     */
+    struct Ca {
+        issuer: Issuer<'static, KeyPair>,
+        cert: CertificateDer<'static>,
+        pem: String,
+    }
+
+    /**
+     * Certificate issued by the test CA, with its key.
+     */
+    struct Issued {
+        cert: CertificateDer<'static>,
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key).unwrap();
+            Ca {
+                issuer: Issuer::new(params, key),
+                cert: cert.der().clone(),
+                pem: cert.pem(),
+            }
+        }
+
+        fn issue(&self, name: &str) -> Issued {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &self.issuer).unwrap();
+            Issued {
+                cert: cert.der().clone(),
+                cert_pem: cert.pem(),
+                key_pem: key.serialize_pem(),
+            }
+        }
+
+        fn client_config(&self, client: Option<&Issued>) -> ClientConfig {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.cert.clone()).unwrap();
+            let builder = ClientConfig::builder().with_root_certificates(roots);
+            match client {
+                None => builder.with_no_client_auth(),
+                Some(client) => builder
+                    .with_client_auth_cert(
+                        vec![client.cert.clone()],
+                        PrivateKeyDer::from_pem_slice(client.key_pem.as_bytes()).unwrap(),
+                    )
+                    .unwrap(),
+            }
+        }
+    }
+
+    /**
+     * Write the server certificate and key, and the client CA if given.
+     */
+    fn write_config(dir: &Path, server: &Issued, client_ca: Option<&Ca>) -> TlsConfig {
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("cert.pem"), &server.cert_pem).unwrap();
+        std::fs::write(path("key.pem"), &server.key_pem).unwrap();
+        if let Some(ca) = client_ca {
+            std::fs::write(path("ca.pem"), &ca.pem).unwrap();
+        }
+        TlsConfig {
+            cert_path: path("cert.pem"),
+            key_path: path("key.pem"),
+            client_ca_path: client_ca.map(|_| path("ca.pem")),
+        }
+    }
+
+    /**
+     * Serve config on a local port, echoing one line per connection.
+     */
+    async fn serve(config: &TlsConfig, reload_interval: Duration) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener =
+            TlsListener::with_reload_interval(listener, config, reload_interval).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await;
+                tokio::spawn(async move {
+                    let mut buf = [0; 64];
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    let _ = stream.write_all(&buf[..n]).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        addr
+    }
+
+    /**
+     * Send a line over TLS and return the certificate the server presented,
+     * or None if the server did not answer.
+     */
+    async fn exchange(
+        addr: std::net::SocketAddr,
+        config: ClientConfig,
+    ) -> Option<CertificateDer<'static>> {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, tcp).await.ok()?;
+        stream.write_all(b"ping").await.ok()?;
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.ok()?;
+        if buf != b"ping" {
+            return None;
+        }
+        let (_, connection) = stream.get_ref();
+        Some(connection.peer_certificates()?[0].clone().into_owned())
+    }
+
+    #[tokio::test]
+    async fn completes_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Ca::new();
+        let server = ca.issue("localhost");
+        let addr = serve(&write_config(dir.path(), &server, None), RELOAD_INTERVAL).await;
+
+        let presented = exchange(addr, ca.client_config(None)).await;
+        assert_eq!(presented, Some(server.cert));
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Ca::new();
+        let old = ca.issue("localhost");
+        let config = write_config(dir.path(), &old, None);
+        let addr = serve(&config, Duration::from_millis(10)).await;
+        assert_eq!(exchange(addr, ca.client_config(None)).await, Some(old.cert));
+
+        let new = ca.issue("localhost");
+        write_config(dir.path(), &new, None);
+        // Modification times may be too coarse to tell the writes apart
+        let later = SystemTime::now() + Duration::from_secs(10);
+        for path in config.paths() {
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(later).unwrap();
+        }
+        for _ in 0..200 {
+            if exchange(addr, ca.client_config(None)).await == Some(new.cert.clone()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("certificate was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificate_with_client_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Ca::new();
+        let server = ca.issue("localhost");
+        let addr = serve(
+            &write_config(dir.path(), &server, Some(&ca)),
+            RELOAD_INTERVAL,
+        )
+        .await;
+
+        assert_eq!(exchange(addr, ca.client_config(None)).await, None);
+        let other_ca = Ca::new();
+        let untrusted = other_ca.issue("client");
+        let config = ca.client_config(Some(&untrusted));
+        assert_eq!(exchange(addr, config).await, None);
+        let client = ca.issue("client");
+        let config = ca.client_config(Some(&client));
+        assert_eq!(exchange(addr, config).await, Some(server.cert));
+    }
+}